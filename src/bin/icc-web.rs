@@ -18,14 +18,19 @@ use log::{error, info, debug};
 use std::env;
 use std::sync::{Arc, RwLock};
 use icc::util::config::{config, Config};
+use icc::util::db::Db;
+use icc::ping::model::{timestamp_text, Timestamp};
+use icc::report::{self, Period, Zone, PeriodReport};
+use icc::export::{self, Format};
 
 struct GlobalData {
-    pub is_down : bool
+    pub is_down : bool,
+    pub db : String
 }
 
 impl GlobalData {
-    pub fn new() -> Arc<RwLock<GlobalData>> {
-        Arc::new(RwLock::new(GlobalData { is_down: false }))
+    pub fn new(db : String) -> Arc<RwLock<GlobalData>> {
+        Arc::new(RwLock::new(GlobalData { is_down: false, db: db }))
     }
 }
 
@@ -96,16 +101,90 @@ impl Ws {
 #[derive(Template)]
 #[template(path = "index.html")]
 struct IndexTemplate<'a> {
-    is_down: &'a str
+    is_down: &'a str,
+    downtimes: Vec<DowntimeRow>,
+    degraded: Vec<DegradedRow>,
+    unmonitored: Vec<UnmonitoredRow>
 }
 
+struct DowntimeRow {
+    start: String,
+    end: String,
+    group: String,
+    location: String
+}
+
+// Report template
+
+#[derive(Template)]
+#[template(path = "report.html")]
+struct ReportTemplate<'a> {
+    period: &'a str,
+    rows: Vec<ReportRow>
+}
+
+struct ReportRow {
+    label: String,
+    availability: String,
+    outages: usize,
+    downtime: String,
+    longest: String,
+    mttr: String,
+    mtbf: String,
+    unmonitored: String
+}
+
+impl ReportRow {
+    fn new(report : &PeriodReport) -> Self {
+        let optional = |millis : Option<i64>| millis.map_or("-".to_owned(), report::duration_text);
+        ReportRow {
+            label: report.label.clone(),
+            availability: report.availability.map_or("-".to_owned(), |availability| format!("{:.3}%", availability)),
+            outages: report.outages,
+            downtime: report::duration_text(report.downtime),
+            longest: optional(report.longest_outage),
+            mttr: optional(report.mttr),
+            mtbf: optional(report.mtbf),
+            unmonitored: report::duration_text(report.unmonitored)
+        }
+    }
+}
+
+struct UnmonitoredRow {
+    start: String,
+    end: String,
+    reason: String
+}
+
+struct DegradedRow {
+    target: String,
+    start: String,
+    end: String,
+    loss_percent: String,
+    rtt: String
+}
+
+
 fn main() {
-    let config : Config = config();
+    let config : Config = match config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
     let sys = actix::System::new("icc-web");
 
     setup();
 
-    let data = GlobalData::new();
+    let (db, bind_address) = match (config.db.clone(), config.bind_address.clone()) {
+        (Some(db), Some(bind_address)) => (db, bind_address),
+        _ => {
+            eprintln!("db and bind_address have to be set");
+            std::process::exit(1);
+        }
+    };
+    let data = GlobalData::new(db);
 
     let app = move || {
         let d = data.clone();
@@ -132,6 +211,76 @@ fn main() {
                 })
             })
 
+            // /export?format=ics&from=2019-03-01&to=2019-04-01&tz=utc, everything up to now by default
+            .resource("/export", |r| {
+                r.method(Method::GET).f(move |req| {
+                    let state : &Arc<RwLock<GlobalData>> = req.state();
+                    let query = req.query();
+
+                    let format : Result<Format, String> = query.get("format").map_or(Ok(Format::Csv), |format| format.parse());
+                    let zone : Result<Zone, String> = query.get("tz").map_or(Ok(Zone::Local), |zone| zone.parse());
+                    let parsed = format.and_then(|format| zone.and_then(|zone| {
+                        let from = query.get("from").map_or(Ok(0), |from| zone.parse_time(from))?;
+                        let to = query.get("to").map_or(Ok(Timestamp::now().epoch_millis), |to| zone.parse_time(to))?;
+                        Ok((format, from, to))
+                    }));
+                    let (format, from, to) = match parsed {
+                        Ok(parsed) => parsed,
+                        Err(e) => {
+                            return HttpResponse::BadRequest()
+                                .content_type("text/plain; charset=utf-8")
+                                .body(e);
+                        }
+                    };
+
+                    let mut payload : Vec<u8> = Vec::new();
+                    if let Err(e) = Db::new(state.read().unwrap().db.as_str()).and_then(|db| export::export(&db, format, from, to, &mut payload)) {
+                        error!("Unable to export downtimes: {}", e);
+                        return HttpResponse::InternalServerError()
+                            .content_type("text/plain; charset=utf-8")
+                            .body(format!("{}", e));
+                    }
+
+                    HttpResponse::Ok()
+                        .content_type(format.content_type())
+                        .header("content-disposition", format!("attachment; filename=\"icc-downtime.{}\"", format.extension()))
+                        .header("server", "icc")
+                        .body(payload)
+                })
+            })
+
+            // /report?period=week&count=8&tz=utc
+            .resource("/report", |r| {
+                r.method(Method::GET).f(move |req| {
+                    let state : &Arc<RwLock<GlobalData>> = req.state();
+                    let query = req.query();
+
+                    let period : Result<Period, String> = query.get("period").map_or(Ok(Period::Day), |period| period.parse());
+                    let count : Result<u32, String> = query.get("count").map_or(Ok(7), |count| report::parse_count(count));
+                    let zone : Result<Zone, String> = query.get("tz").map_or(Ok(Zone::Local), |zone| zone.parse());
+                    let (period, count, zone) = match (period, count, zone) {
+                        (Ok(period), Ok(count), Ok(zone)) => (period, count, zone),
+                        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                            return HttpResponse::BadRequest()
+                                .content_type("text/plain; charset=utf-8")
+                                .body(e);
+                        }
+                    };
+
+                    let reports = match Db::new(state.read().unwrap().db.as_str()).and_then(|db| report::report(&db, period, zone, count, Timestamp::now().epoch_millis)) {
+                        Ok(reports) => reports,
+                        Err(e) => {
+                            error!("Unable to create report: {}", e);
+                            return HttpResponse::InternalServerError()
+                                .content_type("text/plain; charset=utf-8")
+                                .body(format!("{}", e));
+                        }
+                    };
+
+                    html(&ReportTemplate {period: period.as_str(), rows: reports.iter().map(ReportRow::new).collect()})
+                })
+            })
+
             .resource("/", |r| {
                 r.method(Method::GET).f(move |req| {
                     let state : &Arc<RwLock<GlobalData>> = req.state();
@@ -143,21 +292,30 @@ fn main() {
                         state.write().unwrap().is_down = true;
                     }
 
-                    let payload = IndexTemplate {is_down: format!("{}", is_down).as_str()}.render().unwrap();
-
-
-                    HttpResponse::Ok()
-                        .content_type("text/html; charset=utf-8")
-                        .header("server", "icc")
-                        .body(payload)
+                    let (downtimes, degraded, unmonitored) = match latest_records(state.read().unwrap().db.as_str()) {
+                        Ok(records) => records,
+                        Err(e) => {
+                            error!("Unable to read downtimes: {}", e);
+                            return HttpResponse::InternalServerError()
+                                .content_type("text/plain; charset=utf-8")
+                                .body(format!("{}", e));
+                        }
+                    };
+
+                    html(&IndexTemplate {is_down: format!("{}", is_down).as_str(), downtimes: downtimes, degraded: degraded, unmonitored: unmonitored})
                 })
             })
     };
 
 
-    let server = server::new(app)
-        .bind(config.bind_address.expect("Bind address is not specified"))
-        .unwrap()
+    let server = match server::new(app).bind(&bind_address) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Unable to listen on {}: {}", bind_address, e);
+            std::process::exit(1);
+        }
+    };
+    let server = server
         .shutdown_timeout(5)
         .disable_signals();
     let server_addr = server.start();
@@ -168,6 +326,47 @@ fn main() {
     sys.run();
 }
 
+// The page, or a 500 if the template can't be rendered
+fn html<T : Template>(template : &T) -> HttpResponse {
+    match template.render() {
+        Ok(payload) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .header("server", "icc")
+            .body(payload),
+        Err(e) => {
+            error!("Unable to render page: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("{}", e))
+        }
+    }
+}
+
+fn latest_records(db : &str) -> Result<(Vec<DowntimeRow>, Vec<DegradedRow>, Vec<UnmonitoredRow>), icc::Error> {
+    let db_client = Db::new(db)?;
+    let downtimes = db_client.latest_downtimes(25)?.into_iter().map(|record| DowntimeRow {
+        start: timestamp_text(record.start),
+        end: if record.interrupted { format!("{} (interrupted)", timestamp_text(record.end)) } else { timestamp_text(record.end) },
+        group: record.group.unwrap_or_else(|| "-".to_owned()),
+        location: record.location.unwrap_or_else(|| "unknown".to_owned())
+    }).collect();
+    let degraded = db_client.latest_degraded(25)?.into_iter().map(|record| DegradedRow {
+        target: record.target,
+        start: timestamp_text(record.start),
+        end: timestamp_text(record.end),
+        loss_percent: format!("{:.1}%", record.loss_percent),
+        rtt: record.rtt.map_or("n/a".to_owned(), |rtt| format!("{} ms", rtt))
+    }).collect();
+
+    let unmonitored = db_client.latest_unmonitored(25)?.into_iter().map(|record| UnmonitoredRow {
+        start: timestamp_text(record.start),
+        end: timestamp_text(record.end),
+        reason: record.reason.replace('_', " ")
+    }).collect();
+
+    Ok((downtimes, degraded, unmonitored))
+}
+
 struct HandleIcc {
     http_server : Addr<actix_net::server::Server>
 }
@@ -0,0 +1,637 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use icc::ping::probe::probe;
+use icc::ping::model::{Timestamp, timestamp_text};
+use icc::report::{self, Period, PeriodReport, Zone, duration_text};
+use icc::export::{self, Downtime, Format};
+use icc::util::db::Db;
+use icc::util::heartbeat;
+use icc::util::import::{import, parse_log};
+use icc::control::{ControlClient, DaemonStatus, Outage, Request, TargetStatus};
+use icc::util::config::{add_target_to_file, config_from, remove_target_from_file, Config, Target};
+
+fn print_json<T : serde::Serialize>(value : &T) -> i32 {
+    match serde_json::to_string(value) {
+        Ok(json) => {
+            println!("{}", json);
+            0
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn open_db(config : &Config) -> Result<Db, i32> {
+    Db::new(config.db.as_ref().unwrap()).map_err(|e| {
+        eprintln!("{}", e);
+        1
+    })
+}
+
+#[derive(Serialize)]
+struct Status {
+    // Whether icc is monitoring with this database
+    running : bool,
+    // From the control socket, None if icc isn't running or can't be reached
+    daemon : Option<DaemonStatus>,
+    outages : Vec<Outage>,
+    target_stats : Vec<TargetStatus>,
+    last_heartbeat : Option<String>,
+    monitored_since : Option<String>,
+    targets : usize,
+    today : Option<PeriodReport>,
+    last_downtime : Option<Downtime>,
+}
+
+// icc status
+pub fn status_command(config : &Config, args : &[String], json : bool) -> i32 {
+    if let Some(arg) = args.first() {
+        eprintln!("unknown argument '{}'", arg);
+        return 2;
+    }
+
+    let status = match open_db(config).and_then(|db| status(config, &db).map_err(|e| {
+        eprintln!("{}", e);
+        1
+    })) {
+        Ok(status) => status,
+        Err(code) => return code
+    };
+
+    if json {
+        return print_json(&status);
+    }
+
+    match (&status.daemon, &status.last_heartbeat, status.running) {
+        (Some(daemon), _, _) => println!("icc is {} (pid {}, started {})", if daemon.paused { "paused" } else { "running" }, daemon.pid, daemon.started),
+        (None, Some(last_heartbeat), true) => println!("icc is running, last heartbeat {}", last_heartbeat),
+        (None, Some(last_heartbeat), false) => println!("icc is not running, last ran {}", last_heartbeat),
+        (None, None, _) => println!("icc has not run with {} yet", config.db.as_ref().unwrap()),
+    }
+    for outage in &status.outages {
+        println!("Down: group {} since {} ({}), targets {}, location: {}",
+            outage.group, outage.start, duration_text(outage.duration), outage.targets.join(", "), outage.location);
+    }
+    if let Some(since) = &status.monitored_since {
+        println!("Monitored since: {}", since);
+    }
+    println!("Targets: {}", status.targets);
+    if let Some(today) = &status.today {
+        let availability = today.availability.map_or("-".to_owned(), |availability| format!("{:.3}%", availability));
+        println!("Today: {} available, {} outages, {} down, {} not monitored",
+            availability, today.outages, duration_text(today.downtime), duration_text(today.unmonitored));
+    }
+    match &status.last_downtime {
+        Some(downtime) => println!("Last downtime: {} - {}, {}, group: {}, location: {}{}",
+            downtime.start,
+            downtime.end,
+            duration_text(downtime.duration),
+            downtime.group.as_ref().map_or("unknown", |group| group.as_str()),
+            downtime.location.as_ref().map_or("unknown", |location| location.as_str()),
+            if downtime.interrupted { " (interrupted)" } else { "" }),
+        None => println!("Last downtime: none"),
+    }
+    if !status.target_stats.is_empty() {
+        print!("\n{}", targets_table(&status.target_stats));
+    }
+    0
+}
+
+fn status(config : &Config, db : &Db) -> Result<Status, icc::Error> {
+    let now = Timestamp::now().epoch_millis;
+    let last_heartbeat = db.last_heartbeat()?;
+
+    let mut status = Status {
+        // Going by the heartbeat unless the control socket answers, e.g. when it is relative to another directory or owned by
+        // another user. Shortly after icc stopped, the heartbeat is still recent.
+        running: last_heartbeat.map_or(false, |last_heartbeat| heartbeat::is_recent(last_heartbeat, now)),
+        daemon: None,
+        outages: Vec::new(),
+        target_stats: Vec::new(),
+        last_heartbeat: last_heartbeat.map(timestamp_text),
+        monitored_since: db.monitored_since()?.map(timestamp_text),
+        targets: config.targets().len(),
+        today: report::report(db, Period::Day, Zone::Local, 1, now)?.pop(),
+        last_downtime: db.latest_downtimes(1)?.first().map(Downtime::new),
+    };
+
+    if let Some(mut client) = daemon(config) {
+        status.daemon = client.request(&Request::Status)?.status;
+        status.outages = client.request(&Request::Outage)?.outages.unwrap_or_default();
+        status.target_stats = client.request(&Request::Stats)?.targets.unwrap_or_default();
+        status.running = true;
+    }
+    Ok(status)
+}
+
+// icc history [--limit <downtimes>]
+pub fn history_command(config : &Config, args : &[String], json : bool) -> i32 {
+    let mut limit : u32 = 20;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        let parsed : Result<(), String> = match arg.as_str() {
+            "--limit" => value().and_then(|value| value.parse().map(|value| limit = value).map_err(|_| format!("invalid limit '{}'", value))),
+            _ => Err(format!("unknown argument '{}'", arg))
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}", e);
+            return 2;
+        }
+    }
+
+    let downtimes = match open_db(config).and_then(|db| db.latest_downtimes(limit).map_err(|e| {
+        eprintln!("{}", e);
+        1
+    })) {
+        Ok(downtimes) => downtimes,
+        Err(code) => return code
+    };
+
+    if json {
+        let downtimes : Vec<Downtime> = downtimes.iter().map(Downtime::new).collect();
+        return print_json(&downtimes);
+    }
+
+    println!("{:<29}  {:<29}  {:>10}  {:<16}  {}", "Start", "End", "Duration", "Group", "Location");
+    for downtime in &downtimes {
+        println!("{:<29}  {:<29}  {:>10}  {:<16}  {}{}",
+            timestamp_text(downtime.start),
+            timestamp_text(downtime.end),
+            duration_text(downtime.duration),
+            downtime.group.as_ref().map_or("-", |group| group.as_str()),
+            downtime.location.as_ref().map_or("unknown", |location| location.as_str()),
+            if downtime.interrupted { " (interrupted)" } else { "" });
+    }
+    0
+}
+
+// icc report [--period day|week|month] [--count <periods>] [--tz local|utc|<+hh:mm>] [--sla <percent>]
+pub fn report_command(config : &Config, args : &[String], json : bool) -> i32 {
+    let mut period = Period::Day;
+    let mut count : u32 = 7;
+    let mut zone = Zone::Local;
+    let mut sla : Option<f64> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        let parsed : Result<(), String> = match arg.as_str() {
+            "--period" => value().and_then(|value| value.parse().map(|value| period = value)),
+            "--count" => value().and_then(|value| report::parse_count(value).map(|value| count = value)),
+            "--tz" => value().and_then(|value| value.parse().map(|value| zone = value)),
+            "--sla" => value().and_then(|value| value.parse().map(|value| sla = Some(value)).map_err(|_| format!("invalid SLA percentage '{}'", value))),
+            _ => Err(format!("unknown argument '{}'", arg))
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}", e);
+            return 2;
+        }
+    }
+
+    match Db::new(config.db.as_ref().unwrap()).and_then(|db| report::report(&db, period, zone, count, Timestamp::now().epoch_millis)) {
+        Ok(reports) => {
+            if json {
+                return print_json(&reports);
+            }
+            print!("{}", report::table(&reports, sla));
+            0
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+// icc export [--format csv|jsonl|ics] [--from <date or time>] [--to <date or time>] [--tz local|utc|<+hh:mm>] [--output <path>]
+pub fn export_command(config : &Config, args : &[String]) -> i32 {
+    let mut format = Format::Csv;
+    let mut from : Option<String> = None;
+    let mut to : Option<String> = None;
+    let mut zone = Zone::Local;
+    let mut output : Option<String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        let parsed : Result<(), String> = match arg.as_str() {
+            "--format" => value().and_then(|value| value.parse().map(|value| format = value)),
+            "--from" => value().map(|value| from = Some(value.clone())),
+            "--to" => value().map(|value| to = Some(value.clone())),
+            "--tz" => value().and_then(|value| value.parse().map(|value| zone = value)),
+            "--output" => value().map(|value| output = Some(value.clone())),
+            _ => Err(format!("unknown argument '{}'", arg))
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}", e);
+            return 2;
+        }
+    }
+
+    // Everything up to now by default
+    let range = from.map_or(Ok(0), |from| zone.parse_time(&from))
+        .and_then(|from| to.map_or(Ok(Timestamp::now().epoch_millis), |to| zone.parse_time(&to)).map(|to| (from, to)));
+    let (from, to) = match range {
+        Ok(range) => range,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let result = Db::new(config.db.as_ref().unwrap()).and_then(|db| match &output {
+        Some(path) => File::create(path).map_err(icc::Error::from).and_then(|mut file| export::export(&db, format, from, to, &mut file)),
+        None => export::export(&db, format, from, to, &mut io::stdout())
+    });
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+// icc import [--dry-run] <clear text log>...
+pub fn import_command(config : &Config, args : &[String]) -> i32 {
+    let mut dry_run = false;
+    let mut paths : Vec<&String> = Vec::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            _ if arg.starts_with("--") => {
+                eprintln!("unknown argument '{}'", arg);
+                return 2;
+            },
+            _ => paths.push(arg)
+        }
+    }
+    if paths.is_empty() {
+        eprintln!("icc import needs the path of a clear text log");
+        return 2;
+    }
+
+    let db = match open_db(config) {
+        Ok(db) => db,
+        Err(code) => return code
+    };
+
+    for path in paths {
+        let parsed = match File::open(path).map_err(icc::Error::from).and_then(|file| parse_log(io::BufReader::new(file))) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("Unable to read {}: {}", path, e);
+                return 1;
+            }
+        };
+
+        if dry_run {
+            println!("{}: {} downtimes, {} unreadable", path, parsed.downtimes.len(), parsed.skipped);
+            continue;
+        }
+
+        match import(&db, &parsed) {
+            Ok(summary) => println!("{}: imported {} downtimes, {} already in the database, {} unreadable", path, summary.imported, summary.duplicates, summary.skipped),
+            Err(e) => {
+                eprintln!("Unable to import {}: {}", path, e);
+                return 1;
+            }
+        }
+    }
+    0
+}
+
+// icc targets list
+// icc targets add <name> [<address>] [--group <group>] [--interval <seconds>] [--timeout <milliseconds>] [--temporary]
+// icc targets remove <name> [--temporary]
+// Adding and removing changes the config file, or with --temporary only the running icc
+pub fn targets_command(config_path : Option<&Path>, args : &[String], json : bool) -> i32 {
+    let temporary = args.iter().any(|arg| arg == "--temporary");
+    let args : Vec<String> = args.iter().filter(|arg| *arg != "--temporary").cloned().collect();
+
+    match args.first().map(|action| action.as_str()) {
+        Some("list") | None => match (config_from(config_path), args.get(1)) {
+            (_, Some(arg)) => {
+                eprintln!("unknown argument '{}'", arg);
+                2
+            },
+            (Ok(config), None) => list_targets(&config, json),
+            (Err(e), None) => {
+                eprintln!("{}", e);
+                1
+            }
+        },
+        Some("add") => add_target(config_path, &args[1..], temporary),
+        Some("remove") => match &args[1..] {
+            [name] => remove_target(config_path, name, temporary),
+            _ => {
+                eprintln!("icc targets remove needs the name of a target");
+                2
+            }
+        },
+        Some(action) => {
+            eprintln!("unknown targets command '{}', expected list, add or remove", action);
+            2
+        }
+    }
+}
+
+// The targets as the running icc is pinging them, or as configured if it isn't running
+fn list_targets(config : &Config, json : bool) -> i32 {
+    let (targets, tracking) = match daemon(config) {
+        Some(mut client) => match client.request(&Request::Stats) {
+            Ok(response) => (response.targets.unwrap_or_default(), response.tracking),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        },
+        None => (TargetStatus::configured(config), None)
+    };
+
+    if json {
+        return print_json(&targets);
+    }
+    print!("{}", targets_table(&targets));
+    if let Some(tracking) = tracking {
+        println!("\nIPv4 echo requests: {} waiting for a reply, {} timed out in the last minute, {} late replies since start",
+            tracking.pending, tracking.expired, tracking.late_replies);
+    }
+    0
+}
+
+fn targets_table(targets : &[TargetStatus]) -> String {
+    let optional = |value : Option<u64>, unit : &str| value.map_or("-".to_owned(), |value| format!("{}{}", value, unit));
+    let mut table = format!("{:<20} {:<30} {:<16} {:<16} {:<15} {:>9} {:>8} {:>7} {:>10}\n", "Name", "Address", "Groups", "Tags", "Resolved", "Interval", "Timeout", "Loss", "RTT");
+    for target in targets {
+        table.push_str(&format!("{:<20} {:<30} {:<16} {:<16} {:<15} {:>9} {:>8} {:>7} {:>10}\n",
+            target.name,
+            target.address,
+            target.groups.join(","),
+            if target.tags.is_empty() { "-".to_owned() } else { target.tags.join(",") },
+            target.resolved.as_ref().map_or("-", |resolved| resolved.as_str()),
+            optional(target.interval, "s"),
+            optional(target.timeout, "ms"),
+            target.loss_percent.map_or("-".to_owned(), |loss| format!("{:.1}%", loss)),
+            target.average_rtt.map_or("-".to_owned(), |rtt| format!("{:.3} ms", rtt))));
+    }
+    table
+}
+
+fn add_target(config_path : Option<&Path>, args : &[String], temporary : bool) -> i32 {
+    let mut names : Vec<&String> = Vec::new();
+    let mut group : Option<String> = None;
+    let mut interval : Option<u64> = None;
+    let mut timeout : Option<u64> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        let parsed : Result<(), String> = match arg.as_str() {
+            "--group" => value().map(|value| group = Some(value.clone())),
+            "--interval" => value().and_then(|value| value.parse().map(|value| interval = Some(value)).map_err(|_| format!("invalid interval '{}'", value))),
+            "--timeout" => value().and_then(|value| value.parse().map(|value| timeout = Some(value)).map_err(|_| format!("invalid timeout '{}'", value))),
+            _ if arg.starts_with("--") => Err(format!("unknown argument '{}'", arg)),
+            _ => {
+                names.push(arg);
+                Ok(())
+            }
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}", e);
+            return 2;
+        }
+    }
+
+    // Named after its address if only the address is given
+    let (name, address) = match names[..] {
+        [name] => (name, name),
+        [name, address] => (name, address),
+        _ => {
+            eprintln!("icc targets add needs a name, and optionally an address");
+            return 2;
+        }
+    };
+
+    if temporary {
+        let request = Request::AddTarget {name: name.clone(), address: address.clone(), group: group, interval: interval, timeout: timeout};
+        return control_command(config_path, &request, &format!("Added target {} ({}) until icc restarts or reloads its config", name, address));
+    }
+
+    let path = match config_path {
+        Some(path) => path,
+        None => {
+            eprintln!("No config file to add the target to, pass --config <path> to create one");
+            return 1;
+        }
+    };
+
+    let target = Target {
+        name: name.clone(),
+        address: address.clone(),
+        probe: Default::default(),
+        interval: interval,
+        timeout: timeout,
+        group: group,
+        tags: Vec::new(),
+    };
+    match add_target_to_file(path, &target) {
+        Ok(_) => {
+            println!("Added target {} ({}) to {}", target.name, target.address, path.display());
+            0
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn remove_target(config_path : Option<&Path>, name : &str, temporary : bool) -> i32 {
+    if temporary {
+        let request = Request::RemoveTarget {name: name.to_owned()};
+        return control_command(config_path, &request, &format!("Removed target {} until icc restarts or reloads its config", name));
+    }
+
+    let path = match config_path {
+        Some(path) => path,
+        None => {
+            eprintln!("No config file to remove the target from");
+            return 1;
+        }
+    };
+    match remove_target_from_file(path, name) {
+        Ok(true) => {
+            println!("Removed target {} from {}", name, path.display());
+            0
+        },
+        Ok(false) => {
+            eprintln!("{} has no target {}", path.display(), name);
+            1
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+// The running icc, None if it isn't running or doesn't listen on a control socket
+fn daemon(config : &Config) -> Option<ControlClient> {
+    config.control_socket.as_ref().and_then(|path| ControlClient::connect(Path::new(path)).ok())
+}
+
+// icc pause, icc resume and icc reload, and changes to the targets of the running icc
+pub fn control_command(config_path : Option<&Path>, request : &Request, done : &str) -> i32 {
+    let config = match config_from(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let path = match &config.control_socket {
+        Some(path) => path,
+        None => {
+            eprintln!("No control_socket is configured");
+            return 1;
+        }
+    };
+
+    match ControlClient::connect(Path::new(path)).map_err(|e| format!("Unable to reach icc at {}: {}", path, e))
+        .and_then(|mut client| client.request(request).map_err(|e| e.to_string())) {
+        Ok(_) => {
+            println!("{}", done);
+            0
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+// icc probe <target>... [--count <probes>] [--timeout <milliseconds>]
+// Pings the targets like ping does, exits with 0 if every target answered at least once
+pub fn probe_command(config : &Config, args : &[String], json : bool) -> i32 {
+    let mut targets : Vec<String> = Vec::new();
+    let mut count : u32 = 4;
+    let mut timeout : u64 = config.max_ping_timeout.as_ref().unwrap().clone();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        let parsed : Result<(), String> = match arg.as_str() {
+            "--count" => value().and_then(|value| match value.parse() {
+                Ok(value) if value > 0 => {
+                    count = value;
+                    Ok(())
+                },
+                _ => Err(format!("invalid count '{}'", value))
+            }),
+            "--timeout" => value().and_then(|value| match value.parse() {
+                Ok(value) if value > 0 => {
+                    timeout = value;
+                    Ok(())
+                },
+                _ => Err(format!("invalid timeout '{}'", value))
+            }),
+            _ if arg.starts_with("--") => Err(format!("unknown argument '{}'", arg)),
+            _ => {
+                if !targets.contains(arg) {
+                    targets.push(arg.clone());
+                }
+                Ok(())
+            }
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}", e);
+            return 2;
+        }
+    }
+    if targets.is_empty() {
+        eprintln!("icc probe needs at least one target");
+        return 2;
+    }
+
+    let summaries = probe(&targets, count, Duration::from_millis(timeout), |target, reply| {
+        if json {
+            return;
+        }
+        let address = reply.address.map_or(String::new(), |address| format!(" ({})", address));
+        match (reply.rtt, &reply.error) {
+            (Some(rtt), _) => println!("Reply from {}{}: seq={} time={:.3} ms", target, address, reply.sequence, rtt),
+            (None, error) => println!("No reply from {}{}: seq={} {}", target, address, reply.sequence, error.as_ref().map_or("timeout", |error| error.as_str())),
+        }
+    });
+    let summaries = match summaries {
+        Ok(summaries) => summaries,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    let code = if summaries.iter().all(|summary| summary.is_reachable()) { 0 } else { 1 };
+    if json {
+        print_json(&summaries);
+        return code;
+    }
+
+    for summary in &summaries {
+        println!();
+        println!("--- {} probe statistics ---", summary.target);
+        println!("{} probes transmitted, {} received, {:.0}% packet loss", summary.transmitted, summary.received, summary.loss_percent);
+        if let (Some(min), Some(avg), Some(max), Some(mdev)) = (summary.rtt_min, summary.rtt_avg, summary.rtt_max, summary.rtt_mdev) {
+            println!("rtt min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms", min, avg, max, mdev);
+        }
+    }
+    code
+}
+
+#[derive(Serialize)]
+struct ConfigCheck {
+    // None if no config file was found, and the defaults are used
+    path : Option<String>,
+    valid : bool,
+    error : Option<String>,
+}
+
+// icc config check
+// Exits with 1 if the config file can't be used, without starting anything
+pub fn config_command(config_path : Option<&Path>, args : &[String], json : bool) -> i32 {
+    match args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>()[..] {
+        ["check"] => {},
+        _ => {
+            eprintln!("unknown config command, expected check");
+            return 2;
+        }
+    }
+
+    let result = config_from(config_path);
+    let check = ConfigCheck {
+        path: config_path.map(|path| path.display().to_string()),
+        valid: result.is_ok(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+    };
+    let code = if check.valid { 0 } else { 1 };
+    if json {
+        print_json(&check);
+        return code;
+    }
+
+    let path = check.path.as_ref().map_or("no config file, using the defaults".to_owned(), |path| path.clone());
+    match &result {
+        Ok(config) => println!("{}: valid, {} targets in {} groups", path, config.targets().len(), config.target_groups().len()),
+        Err(e) => eprintln!("{}", e),
+    }
+    code
+}
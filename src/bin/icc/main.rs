@@ -0,0 +1,526 @@
+extern crate pretty_env_logger;
+extern crate log;
+extern crate icc;
+extern crate ctrlc;
+#[macro_use]
+extern crate serde_derive;
+
+mod commands;
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::net::IpAddr;
+use std::time::Duration;
+use log::{error, info};
+
+use icc::ping::{PingUtility, PingResult as PingUtilityResult};
+use icc::ping::hops::{Finding, Locator};
+use icc::ping::model::{ConnectivityDown, DurationFormat, Timestamp, fractional_millis, timestamp_text};
+use icc::control::{ControlServer, DaemonStatus, Outage, Request, Response, TargetStatus};
+use icc::detector::{DowntimeDetector, DetectorEvent};
+use icc::util::{log_cd, log_degraded};
+use icc::util::db::UnmonitoredRecord;
+use icc::util::heartbeat::Heartbeat;
+use icc::util::webhook::{self, WebhookPayload};
+use icc::util::clear_text_log::{ClearTextLog, LogFile};
+use icc::util::config::{config_from, config_path_arg, find_config_file, Config, ConfigWatcher, Target};
+
+// How far out the first ISP hop is looked for
+const MAX_HOPS : u8 = 8;
+
+// How often the result loop checks whether icc should stop, when no results arrive
+const STOP_POLL_INTERVAL : Duration = Duration::from_millis(200);
+
+// How long to wait for downtimes to be written when stopping
+const SHUTDOWN_TIMEOUT : Duration = Duration::from_secs(10);
+
+// SIGINT and SIGTERM both stop icc gracefully. A second one exits immediately.
+fn handle_exit(stop_sig : Arc<AtomicBool>) {
+    ctrlc::set_handler(move || {
+        if stop_sig.swap(true, Ordering::Relaxed) {
+            std::process::exit(1);
+        }
+        info!("Stopping ICC");
+    }).expect("Unable to set SIGINT/SIGTERM handler");
+}
+
+const USAGE : &str = "Usage: icc [--config <path>] [--json] [<command>]
+
+Commands:
+  run                     Monitor the connection, the default without a command
+  status                  Whether icc is running, today's availability and the last downtime
+  history                 The latest downtimes [--limit <downtimes>]
+  report                  Availability per period [--period day|week|month] [--count <periods>] [--tz <timezone>] [--sla <percent>]
+  export                  Downtimes as a file [--format csv|jsonl|ics] [--from <time>] [--to <time>] [--tz <timezone>] [--output <path>]
+  import                  Downtimes from clear text logs [--dry-run] <log>...
+  targets list            The targets that are pinged
+  targets add <name> [<address>]
+                          Adds a target to the config file [--group <group>] [--interval <seconds>] [--timeout <milliseconds>]
+  targets remove <name>   Removes a target from the config file
+                          With --temporary, targets are only added to or removed from the running icc
+  pause                   Stops the running icc from pinging, without stopping it
+  resume                  Resumes pinging after icc pause
+  reload                  Makes the running icc reload its config file
+  probe <target>...       Pings targets like ping, exits with 1 if any of them didn't answer
+                          [--count <probes>] (default 4) [--timeout <milliseconds>]
+  config check            Validates the config file
+  help                    This text
+
+--json prints the output of status, history, report, targets list, probe and config check as JSON.
+";
+
+// The command and its arguments, without the options every command takes: --config <path> and --json
+struct CommandLine {
+    command : Option<String>,
+    args : Vec<String>,
+    json : bool,
+}
+
+fn command_line() -> CommandLine {
+    let mut line = CommandLine {command: None, args: Vec::new(), json: false};
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            args.next();
+        } else if arg.starts_with("--config=") {
+            continue;
+        } else if arg == "--json" {
+            line.json = true;
+        } else if line.command.is_none() {
+            line.command = Some(arg);
+        } else {
+            line.args.push(arg);
+        }
+    }
+    line
+}
+
+fn load_config(path : Option<&Path>) -> Config {
+    match config_from(path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let line = command_line();
+    let config_path = find_config_file(config_path_arg());
+    let path = config_path.as_ref().map(|path| path.as_path());
+
+    let code = match line.command.as_ref().map(|command| command.as_str()) {
+        None | Some("run") => match line.args.first() {
+            Some(arg) => {
+                eprintln!("unknown argument '{}'", arg);
+                2
+            },
+            None => {
+                run(config_path.clone(), load_config(path));
+                0
+            }
+        },
+        Some("status") => commands::status_command(&load_config(path), &line.args, line.json),
+        Some("history") => commands::history_command(&load_config(path), &line.args, line.json),
+        Some("report") => commands::report_command(&load_config(path), &line.args, line.json),
+        Some("export") => commands::export_command(&load_config(path), &line.args),
+        Some("import") => commands::import_command(&load_config(path), &line.args),
+        Some("targets") => commands::targets_command(path, &line.args, line.json),
+        Some("probe") => commands::probe_command(&load_config(path), &line.args, line.json),
+        Some("config") => commands::config_command(path, &line.args, line.json),
+        Some("pause") => commands::control_command(path, &Request::Pause, "Paused, the time until icc resume counts as not monitored"),
+        Some("resume") => commands::control_command(path, &Request::Resume, "Resumed"),
+        Some("reload") => commands::control_command(path, &Request::Reload, "Config reloaded"),
+        Some("help") | Some("--help") | Some("-h") => {
+            print!("{}", USAGE);
+            0
+        },
+        Some(command) => {
+            eprint!("Unknown command '{}'\n\n{}", command, USAGE);
+            2
+        }
+    };
+    std::process::exit(code);
+}
+
+// Monitors until SIGINT or SIGTERM
+fn run(config_path : Option<PathBuf>, mut config : Config) {
+    let mut config_watcher = ConfigWatcher::new(config_path);
+
+    setup();
+
+    let stop_bool = Arc::new(AtomicBool::new(false));
+    handle_exit(stop_bool.clone());
+
+    // Also keeps a second icc from starting with the same socket
+    let control = config.control_socket.as_ref().map(|path| exit_on_error(ControlServer::bind(Path::new(path))));
+    let started = Timestamp::now();
+    // When icc was paused through the control socket
+    let mut paused : Option<i64> = None;
+
+    let (p_utility, results) = exit_on_error(PingUtility::new(Some(config.max_ping_timeout.as_ref().unwrap().clone())));
+
+    p_utility.set_resolve_interval(Duration::from_secs(config.resolve_interval.as_ref().unwrap().clone()));
+
+    for target in config.targets() {
+        p_utility.add_target(&target.name, &target.address, target.probe_options());
+    }
+
+    exit_on_error(p_utility.start_pinging());
+
+    // Also reports a database that can't be created or migrated at startup
+    let (mut heartbeat, gap) = exit_on_error(Heartbeat::start(config.db.as_ref().unwrap()));
+    if let Some(gap) = gap {
+        log_unmonitored(&gap);
+    }
+    let log_file : LogFile = Arc::new(Mutex::new(exit_on_error(ClearTextLog::from_config(&config).map_err(icc::Error::from))));
+
+    let locator = Locator::new(p_utility.hop_prober(), trace_destination(&config), MAX_HOPS, Duration::from_millis(config.max_ping_timeout.as_ref().unwrap().clone()));
+
+    let mut cd_col : Vec<ConnectivityDown> = Vec::new();
+    let mut detector = DowntimeDetector::new(config.max_timeouts.as_ref().unwrap().clone(), &config.target_groups(), config.degraded_thresholds());
+
+    loop {
+        match results.recv_timeout(STOP_POLL_INTERVAL) {
+            Ok(res) => {
+                match &res {
+                    PingUtilityResult::Response{target, addr, rtt, sequence, identifier, ..} => {
+                        info!("Receive from Target {} ({}) in {:?}. seq = {}, identifier = {}", target, addr, rtt, sequence, identifier);
+                    },
+                    PingUtilityResult::Timeout {target, addr: Some(addr), ..} => {
+                        error!("Idle Target {} ({}).", target, addr);
+                    },
+                    PingUtilityResult::Timeout {target, addr: None, ..} => {
+                        error!("Idle Target {}, unable to resolve.", target);
+                    },
+                    PingUtilityResult::DestinationUnreachable {target, addr, from, code, ..} => {
+                        error!("Target {} ({}) unreachable, code {} from {}.", target, addr, code, from);
+                    },
+                    PingUtilityResult::TimeExceeded {target, addr, from, ..} => {
+                        error!("Target {} ({}) time exceeded at {}.", target, addr, from);
+                    },
+                    PingUtilityResult::IcmpError {target, addr, from, icmp_type, code, ..} => {
+                        error!("Target {} ({}) ICMP error type {} code {} from {}.", target, addr, icmp_type, code, from);
+                    },
+                    _ => {}
+                }
+
+                for event in detector.process(&res) {
+                    handle_event(event, &config, &log_file, &mut cd_col, &mut detector, &locator, &p_utility);
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("The pinger stopped sending results");
+                break;
+            }
+        }
+
+        for finding in locator.findings() {
+            record_finding(finding, &config, &mut detector);
+        }
+
+        if let Some(gap) = heartbeat.beat() {
+            log_unmonitored(&gap);
+        }
+
+        for pending in control.iter().flat_map(|control| control.pending()) {
+            let response = match &pending.request {
+                Request::Status => Response {status: Some(daemon_status(&config, &detector, &started, paused.is_some())), ..Response::ok()},
+                Request::Outage => Response {outages: Some(outages(&detector)), ..Response::ok()},
+                Request::Stats => Response {targets: Some(target_statuses(&config, &detector, &p_utility)), tracking: Some(p_utility.tracking_stats()), ..Response::ok()},
+                Request::AddTarget {..} | Request::RemoveTarget {..} | Request::Reload => match changed_config(&pending.request, &config, config_watcher.path()) {
+                    Ok(new_config) => {
+                        apply_config(new_config, &mut config, &log_file, &mut cd_col, &mut detector, &locator, &p_utility);
+                        Response::ok()
+                    },
+                    Err(e) => Response::error(&e)
+                },
+                Request::Pause => match paused {
+                    Some(_) => Response::error("already paused"),
+                    // Pinging can't be started again there, see PingUtility::stop_pinging
+                    None if !cfg!(unix) => Response::error("pausing isn't supported on this platform"),
+                    None => {
+                        p_utility.stop_pinging();
+                        store_interrupted(&config, &log_file, &mut detector, "pausing");
+                        // Starts over on resume, rather than judging the targets by the pings before the pause
+                        detector = DowntimeDetector::new(config.max_timeouts.as_ref().unwrap().clone(), &config.target_groups(), config.degraded_thresholds());
+                        paused = Some(Timestamp::now().epoch_millis);
+                        info!("Paused");
+                        Response::ok()
+                    }
+                },
+                Request::Resume => match paused.take() {
+                    Some(since) => {
+                        log_unmonitored(&heartbeat.paused(since));
+                        match p_utility.start_pinging() {
+                            Ok(_) => {
+                                info!("Resumed");
+                                Response::ok()
+                            },
+                            Err(e) => {
+                                paused = Some(Timestamp::now().epoch_millis);
+                                Response::error(&e.to_string())
+                            }
+                        }
+                    },
+                    None => Response::error("not paused")
+                }
+            };
+            pending.reply(response);
+        }
+
+        if config_watcher.should_reload() {
+            match config_from(config_watcher.path()) {
+                Ok(new_config) => apply_config(new_config, &mut config, &log_file, &mut cd_col, &mut detector, &locator, &p_utility),
+                Err(e) => error!("Keeping the running config, unable to reload: {}", e)
+            }
+        }
+
+        if stop_bool.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+
+    p_utility.stop_pinging();
+    if let Some(since) = paused {
+        log_unmonitored(&heartbeat.paused(since));
+    }
+    shutdown(&config, &log_file, &mut detector, &mut heartbeat);
+}
+
+fn log_unmonitored(gap : &UnmonitoredRecord) {
+    info!("Not monitored from {} to {} ({} ms), reason: {}", timestamp_text(gap.start), timestamp_text(gap.end), gap.duration, gap.reason);
+}
+
+// Stores the downtimes still in progress as interrupted, and waits for everything to be written
+fn shutdown(config : &Config, log_file : &LogFile, detector : &mut DowntimeDetector, heartbeat : &mut Heartbeat) {
+    heartbeat.stop();
+    store_interrupted(config, log_file, detector, "stopping");
+
+    let active = icc::util::wait_graceful(SHUTDOWN_TIMEOUT);
+    if active != 0 {
+        error!("Gave up waiting for {} downtimes to be written", active);
+    }
+
+    if let Some(log) = log_file.lock().unwrap().as_mut() {
+        if let Err(e) = log.sync() {
+            error!("Error flushing clear text log: {}", e);
+        }
+    }
+}
+
+// Ends the downtimes in progress as interrupted, and stores them
+fn store_interrupted(config : &Config, log_file : &LogFile, detector : &mut DowntimeDetector, reason : &str) {
+    for event in detector.shutdown() {
+        if let DetectorEvent::DowntimeEnded {group, cd} = event {
+            info!("Group {} was still down when {}, storing the downtime as interrupted after {}", group, reason, cd.duration_text());
+            post_downtime(config, webhook::EVENT_ENDED, &cd);
+            icc::util::THREADS_ACTIVE_GRACEFUL.fetch_add(1, Ordering::SeqCst);
+            log_cd(cd, log_file.clone(), config.db.as_ref().unwrap().to_owned());
+        }
+    }
+}
+
+fn daemon_status(config : &Config, detector : &DowntimeDetector, started : &Timestamp, paused : bool) -> DaemonStatus {
+    DaemonStatus {
+        pid: std::process::id(),
+        started: started.text(),
+        paused: paused,
+        down: detector.is_down(),
+        degraded: detector.is_degraded(),
+        targets: config.targets().len(),
+    }
+}
+
+fn outages(detector : &DowntimeDetector) -> Vec<Outage> {
+    let now = Timestamp::now().epoch_millis;
+    detector.current().into_iter()
+        .filter(|(_, cd)| cd.is_started())
+        .map(|(group, cd)| Outage {
+            group: group.to_owned(),
+            start: cd.start_text(),
+            start_epoch_millis: cd.start_epoch_millis(),
+            duration: now - cd.start_epoch_millis(),
+            targets: cd.targets().to_vec(),
+            location: cd.location_text().to_owned(),
+        })
+        .collect()
+}
+
+fn target_statuses(config : &Config, detector : &DowntimeDetector, p_utility : &PingUtility) -> Vec<TargetStatus> {
+    let stats = detector.stats();
+    let mut targets = TargetStatus::configured(config);
+    for target in targets.iter_mut() {
+        target.resolved = p_utility.resolved(&target.name).map(|address| address.to_string());
+        target.resolved_all = p_utility.resolved_all(&target.name).iter().map(|address| address.to_string()).collect();
+        if let Some((_, stats)) = stats.iter().find(|(name, _)| *name == target.name) {
+            target.sent = stats.sent();
+            target.received = stats.received();
+            target.loss_percent = if stats.sent() > 0 { Some(stats.loss_percent()) } else { None };
+            target.average_rtt = stats.average_rtt().map(fractional_millis);
+        }
+    }
+    targets
+}
+
+// The config a control request changes the running config to, or why it can't
+fn changed_config(request : &Request, running : &Config, path : Option<&Path>) -> Result<Config, String> {
+    match request {
+        Request::AddTarget {name, address, group, interval, timeout} => running.with_target(Target {
+            name: name.clone(),
+            address: address.clone(),
+            probe: Default::default(),
+            interval: *interval,
+            timeout: *timeout,
+            group: group.clone(),
+            tags: Vec::new(),
+        }).map_err(|e| e.to_string()),
+        Request::RemoveTarget {name} => running.without_target(name).ok_or_else(|| format!("no target {}", name)),
+        _ => config_from(path).map_err(|e| e.to_string())
+    }
+}
+
+// Switches to a reloaded or changed config, storing the downtimes of groups that no longer exist
+fn apply_config(new_config : Config, config : &mut Config, log_file : &LogFile, cd_col : &mut Vec<ConnectivityDown>, detector : &mut DowntimeDetector, locator : &Locator, p_utility : &PingUtility) {
+    for event in reload_config(config, &new_config, p_utility, detector, log_file) {
+        handle_event(event, &new_config, log_file, cd_col, detector, locator, p_utility);
+    }
+    *config = new_config;
+}
+
+fn exit_on_error<T>(result : Result<T, icc::Error>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Hop probes towards the first public IPv4 target, as that is what the outages are measured against
+fn trace_destination(config : &Config) -> IpAddr {
+    config.targets().iter()
+        .filter_map(|target| target.address.parse::<IpAddr>().ok())
+        .find(|address| match address {
+            IpAddr::V4(address) => !address.is_private() && !address.is_loopback() && !address.is_link_local(),
+            IpAddr::V6(_) => false
+        })
+        .unwrap_or(IpAddr::from([8, 8, 8, 8]))
+}
+
+fn handle_event(event : DetectorEvent, config : &Config, log_file : &LogFile, cd_col : &mut Vec<ConnectivityDown>, detector : &mut DowntimeDetector, locator : &Locator, p_utility : &PingUtility) {
+    match event {
+        DetectorEvent::DowntimeStarted {group, cd} => {
+            error!("Group {} is down", group);
+            let targets : Vec<(String, IpAddr)> = detector.down_targets(&group).into_iter()
+                .filter_map(|target| p_utility.resolved(&target).map(|address| (target, address)))
+                .collect();
+            locator.locate(&group, cd.start_epoch_millis(), targets);
+            post_downtime(config, webhook::EVENT_STARTED, &cd);
+        },
+        DetectorEvent::DowntimeEnded {group, cd} => {
+            info!("Group {} is up again, was down for {}, location: {}", group, cd.duration_text(), cd.location_text());
+            // The path couldn't be discovered at startup, try again now the network is back
+            if !locator.is_discovered() {
+                locator.discover();
+            }
+            post_downtime(config, webhook::EVENT_ENDED, &cd);
+            icc::util::THREADS_ACTIVE_GRACEFUL.fetch_add(1, Ordering::SeqCst);
+            log_cd(cd.clone(), log_file.clone(), config.db.as_ref().unwrap().to_owned());
+            cd_col.push(cd);
+        },
+        DetectorEvent::DegradedStarted {target} => {
+            error!("Target {} is degraded", target);
+        },
+        DetectorEvent::DegradedEnded {degraded} => {
+            info!("Target {} is no longer degraded, was degraded for {}", degraded.target(), degraded.duration().as_text());
+            icc::util::THREADS_ACTIVE_GRACEFUL.fetch_add(1, Ordering::SeqCst);
+            log_degraded(degraded, log_file.clone(), config.db.as_ref().unwrap().to_owned());
+        }
+    }
+}
+
+// Posts the downtime to the webhook, if there is one
+fn post_downtime(config : &Config, event : &'static str, cd : &ConnectivityDown) {
+    if let Some(url) = &config.webhook {
+        webhook::send(url, WebhookPayload::downtime(event, cd));
+    }
+}
+
+// Adds where a downtime is to it, unless the downtime is over already. Those are stored as located at unknown.
+fn record_finding(finding : Finding, config : &Config, detector : &mut DowntimeDetector) {
+    let current = detector.current().into_iter()
+        .any(|(group, cd)| group == finding.group && cd.start_epoch_millis() == finding.start_epoch_millis);
+    if !current {
+        info!("Group {} was up again before its location was found: {}", finding.group, finding.location.as_str());
+        return;
+    }
+
+    info!("Group {} is down, location: {}", finding.group, finding.location.as_str());
+    for trace in &finding.traces {
+        info!("Traceroute {}", trace.text());
+    }
+    detector.set_location(&finding.group, finding.location);
+    detector.set_traces(&finding.group, finding.traces);
+    let group = finding.group;
+    if let Some((_, cd)) = detector.current().into_iter().find(|(name, _)| *name == group) {
+        post_downtime(config, webhook::EVENT_LOCATED, cd);
+    }
+}
+
+// Applies the differences between the running and the reloaded config, without losing the downtime in progress
+fn reload_config(running : &Config, new : &Config, p_utility : &PingUtility, detector : &mut DowntimeDetector, log_file : &LogFile) -> Vec<DetectorEvent> {
+    let changes = running.changes(new);
+
+    for target in &changes.removed_targets {
+        p_utility.remove_target(&target.name);
+    }
+
+    for target in &changes.added_targets {
+        p_utility.add_target(&target.name, &target.address, target.probe_options());
+    }
+
+    if let Some(max_ping_timeout) = changes.max_ping_timeout {
+        p_utility.set_timeout(max_ping_timeout);
+    }
+
+    if let Some(resolve_interval) = changes.resolve_interval {
+        p_utility.set_resolve_interval(Duration::from_secs(resolve_interval));
+    }
+
+    if changes.clear_text_log {
+        match ClearTextLog::from_config(new) {
+            Ok(log) => *log_file.lock().unwrap() = log,
+            Err(e) => error!("Keeping the previous clear text log: {}", e)
+        }
+    }
+
+    for key in &changes.restart_required {
+        info!("{} changed, this requires a restart of icc to take effect", key);
+    }
+
+    info!("Config reloaded");
+
+    if changes.detection {
+        detector.reconfigure(new.max_timeouts.as_ref().unwrap().clone(), &new.target_groups(), new.degraded_thresholds())
+    } else {
+        Vec::new()
+    }
+}
+
+
+#[cfg(debug_assertions)]
+fn setup() {
+    env::set_var("RUST_LOG", "trace");
+    pretty_env_logger::init();
+}
+
+#[cfg(not(debug_assertions))]
+fn setup() {
+
+}
\ No newline at end of file
@@ -0,0 +1,346 @@
+// Control API of a running icc, over a Unix domain socket. Every line sent is a JSON request, answered by a single line of JSON:
+//
+// {"command": "status"}
+// {"ok":true,"status":{"pid":1234,"started":"2019-03-02T21:14:03.250+01:00","paused":false,"down":false,"degraded":false,"targets":2}}
+//
+// Commands are status, outage, stats, add_target, remove_target, pause, resume and reload.
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use log::{debug, error};
+use crate::error::Error;
+use crate::util::config::Config;
+use crate::ping::TrackingStats;
+
+// How long a request waits for the result loop to answer it, and the client for the answer
+const REQUEST_TIMEOUT : Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    // The downtimes in progress
+    Outage,
+    // Packet loss and round-trip time per target
+    Stats,
+    // Only changes the running icc, the target is gone after a restart or when the config file is reloaded
+    AddTarget { name: String, address: String, group: Option<String>, interval: Option<u64>, timeout: Option<u64> },
+    RemoveTarget { name: String },
+    // Stops pinging, the time until resume is recorded as not monitored
+    Pause,
+    Resume,
+    // Reloads the config file, like SIGHUP
+    Reload,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Response {
+    pub ok : bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status : Option<DaemonStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outages : Option<Vec<Outage>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub targets : Option<Vec<TargetStatus>>,
+    // Echo requests waiting for a reply, answered by stats
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tracking : Option<TrackingStats>,
+}
+
+impl Response {
+    pub fn ok() -> Self {
+        Self {ok: true, ..Default::default()}
+    }
+
+    pub fn error(message : &str) -> Self {
+        Self {ok: false, error: Some(message.to_owned()), ..Default::default()}
+    }
+
+    // The error as a Result, for clients
+    pub fn into_result(self) -> Result<Self, Error> {
+        match (self.ok, &self.error) {
+            (true, _) => Ok(self),
+            (false, error) => Err(Error::Io(io::Error::new(io::ErrorKind::Other, error.clone().unwrap_or_else(|| "request failed".to_owned()))))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DaemonStatus {
+    pub pid : u32,
+    pub started : String,
+    pub paused : bool,
+    // Whether any group is down, or any target degraded
+    pub down : bool,
+    pub degraded : bool,
+    pub targets : usize,
+}
+
+// A downtime in progress. Durations are milliseconds.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Outage {
+    pub group : String,
+    pub start : String,
+    pub start_epoch_millis : i64,
+    pub duration : i64,
+    // Targets that have reached max_timeouts
+    pub targets : Vec<String>,
+    // "gateway", "isp", "internet" or "unknown"
+    pub location : String,
+}
+
+// A target as it is being pinged. Loss and round-trip time are over the last degraded_window pings, None until the first ping.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TargetStatus {
+    pub name : String,
+    pub address : String,
+    pub groups : Vec<String>,
+    #[serde(default)]
+    pub tags : Vec<String>,
+    pub interval : Option<u64>,
+    pub timeout : Option<u64>,
+    // What the address resolved to, and is pinged at
+    pub resolved : Option<String>,
+    // The IPv4 and IPv6 address of a hostname
+    #[serde(default)]
+    pub resolved_all : Vec<String>,
+    pub sent : usize,
+    pub received : usize,
+    pub loss_percent : Option<f64>,
+    // Milliseconds
+    pub average_rtt : Option<f64>,
+}
+
+impl TargetStatus {
+    // The targets of a config, before any pinging
+    pub fn configured(config : &Config) -> Vec<Self> {
+        let groups = config.target_groups();
+        config.targets().into_iter().map(|target| Self {
+            groups: groups.iter().filter(|group| group.addresses.contains(&target.name)).map(|group| group.name.clone()).collect(),
+            name: target.name,
+            address: target.address,
+            tags: target.tags,
+            interval: target.interval,
+            timeout: target.timeout,
+            resolved: None,
+            resolved_all: Vec::new(),
+            sent: 0,
+            received: 0,
+            loss_percent: None,
+            average_rtt: None,
+        }).collect()
+    }
+}
+
+// A request waiting for the result loop to answer it
+pub struct Pending {
+    pub request : Request,
+    reply : Sender<Response>,
+}
+
+impl Pending {
+    pub fn reply(self, response : Response) {
+        // The client may have given up waiting
+        let _ = self.reply.send(response);
+    }
+}
+
+// Accepts connections on the control socket, and passes the requests on to whoever calls pending()
+pub struct ControlServer {
+    path : PathBuf,
+    requests : Receiver<Pending>,
+}
+
+impl ControlServer {
+    // Fails if another icc is listening on the socket, or something other than a socket is at the path.
+    // A socket file left behind by an icc that didn't exit cleanly is replaced.
+    #[cfg(unix)]
+    pub fn bind(path : &Path) -> Result<Self, Error> {
+        use std::fs;
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            // Anything else there, e.g. the database named by mistake, is left alone
+            if !metadata.file_type().is_socket() {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::AlreadyExists, format!("unable to create control socket {}: a file that isn't a socket is in the way", path.display()))));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::AddrInUse, format!("another icc is running with the control socket {}", path.display()))));
+            }
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("unable to create control socket {}: {}", path.display(), e)))?;
+        // Anyone who can connect can add targets and pause monitoring
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let sender = sender.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = serve(&stream, &mut &stream, &sender) {
+                                debug!("Control connection closed: {}", e);
+                            }
+                        });
+                    },
+                    Err(e) => error!("Error accepting control connection: {}", e)
+                }
+            }
+        });
+
+        Ok(Self {path: path.to_owned(), requests: requests})
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind(path : &Path) -> Result<Self, Error> {
+        Err(Error::Io(io::Error::new(io::ErrorKind::Other, format!("unable to create control socket {}: only supported on unix", path.display()))))
+    }
+
+    // Requests that came in since the last call, doesn't block
+    pub fn pending(&self) -> Vec<Pending> {
+        self.requests.try_iter().collect()
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Answers the requests on a connection until the client closes it
+fn serve<R : io::Read, W : Write>(reader : R, writer : &mut W, requests : &Sender<Pending>) -> io::Result<()> {
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let (reply, response) = mpsc::channel();
+                match requests.send(Pending {request: request, reply: reply}) {
+                    Ok(_) => response.recv_timeout(REQUEST_TIMEOUT).unwrap_or_else(|_| Response::error("icc didn't answer in time")),
+                    Err(_) => Response::error("icc is stopping")
+                }
+            },
+            Err(e) => Response::error(&format!("invalid request: {}", e))
+        };
+
+        let mut line = serde_json::to_string(&response).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push('\n');
+        writer.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+// A connection to a running icc
+pub struct ControlClient {
+    #[cfg(unix)]
+    stream : std::os::unix::net::UnixStream,
+}
+
+impl ControlClient {
+    // Fails with NotFound or ConnectionRefused if icc isn't running
+    #[cfg(unix)]
+    pub fn connect(path : &Path) -> Result<Self, Error> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        Ok(Self {stream: stream})
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect(_path : &Path) -> Result<Self, Error> {
+        Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "the control socket is only supported on unix")))
+    }
+
+    // The response, or its error
+    #[cfg(unix)]
+    pub fn request(&mut self, request : &Request) -> Result<Response, Error> {
+        let mut line = serde_json::to_string(request).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push('\n');
+        self.stream.write_all(line.as_bytes())?;
+
+        let mut line = String::new();
+        BufReader::new(&self.stream).read_line(&mut line)?;
+        let response : Response = serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        response.into_result()
+    }
+
+    #[cfg(not(unix))]
+    pub fn request(&mut self, _request : &Request) -> Result<Response, Error> {
+        Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "the control socket is only supported on unix")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The responses written for the input, one per line
+    fn responses(output : Vec<u8>) -> Vec<Response> {
+        String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn answers_requests_line_by_line() {
+        let (sender, requests) = mpsc::channel::<Pending>();
+        let responder = thread::spawn(move || {
+            let mut answered = 0;
+            for pending in requests {
+                answered += 1;
+                match pending.request.clone() {
+                    Request::Status => pending.reply(Response::ok()),
+                    Request::RemoveTarget {name} => pending.reply(Response::error(&format!("no target {}", name))),
+                    // Dropped without an answer
+                    _ => {}
+                }
+            }
+            answered
+        });
+
+        let input = "{\"command\": \"status\"}\n\n   \n{\"command\": \"remove_target\", \"name\": \"cf\"}\nnot json\n{\"command\": \"launch\"}\n{\"command\": \"pause\"}";
+        let mut output = Vec::new();
+        serve(input.as_bytes(), &mut output, &sender).unwrap();
+        drop(sender);
+        assert_eq!(responder.join().unwrap(), 3);
+
+        // Blank lines aren't answered
+        let responses = responses(output);
+        assert_eq!(responses.len(), 5);
+        assert!(responses[0].ok);
+        assert_eq!(responses[0].error, None);
+        assert!(!responses[1].ok);
+        assert_eq!(responses[1].error.as_ref().map(|e| e.as_str()), Some("no target cf"));
+        for response in &responses[2..4] {
+            assert!(!response.ok);
+            assert!(response.error.as_ref().unwrap().starts_with("invalid request: "), "{:?}", response.error);
+        }
+        assert_eq!(responses[4].error.as_ref().map(|e| e.as_str()), Some("icc didn't answer in time"));
+    }
+
+    #[test]
+    fn answers_while_stopping() {
+        let (sender, requests) = mpsc::channel::<Pending>();
+        drop(requests);
+
+        let mut output = Vec::new();
+        serve(&b"{\"command\": \"status\"}\n{\"command\": \"resume\"}\n"[..], &mut output, &sender).unwrap();
+        let responses = responses(output);
+        assert_eq!(responses.len(), 2);
+        for response in responses {
+            assert!(!response.ok);
+            assert_eq!(response.error.as_ref().map(|e| e.as_str()), Some("icc is stopping"));
+            assert!(response.into_result().is_err());
+        }
+    }
+}
@@ -0,0 +1,877 @@
+use log::debug;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::mem;
+use std::time::Duration;
+use super::ping::PingResult;
+use super::ping::model::{ConnectivityDown, ConnectivityDegraded, FaultLocation, Timestamp, Trace};
+use super::util::config::TargetGroup;
+
+// Source of the current time. Lets the detector be driven by a fake clock instead of the wall clock.
+pub trait Clock {
+    fn now(&self) -> Timestamp;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        Timestamp::now()
+    }
+}
+
+// How many targets of a group have to be down, before the group itself counts as down
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DetectionPolicy {
+    All,
+    Majority,
+    AtLeast(usize),
+}
+
+impl Default for DetectionPolicy {
+    fn default() -> Self {
+        DetectionPolicy::All
+    }
+}
+
+// toml can't serialize enums, so the variants are written out the same way they are read: "all", "majority" or { at_least = N }
+impl Serialize for DetectionPolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            DetectionPolicy::All => serializer.serialize_str("all"),
+            DetectionPolicy::Majority => serializer.serialize_str("majority"),
+            DetectionPolicy::AtLeast(n) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("at_least", &n)?;
+                map.end()
+            }
+        }
+    }
+}
+
+// Read by hand as well, toml only reads { at_least = N } as an enum when it is an inline table, not once rewritten as [group.policy]
+impl<'de> Deserialize<'de> for DetectionPolicy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PolicyVisitor;
+
+        impl<'de> Visitor<'de> for PolicyVisitor {
+            type Value = DetectionPolicy;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "\"all\", \"majority\" or {{ at_least = N }}")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<DetectionPolicy, E> {
+                match value {
+                    "all" => Ok(DetectionPolicy::All),
+                    "majority" => Ok(DetectionPolicy::Majority),
+                    _ => Err(E::unknown_variant(value, &["all", "majority", "at_least"]))
+                }
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<DetectionPolicy, M::Error> {
+                match map.next_key::<String>()? {
+                    Some(ref key) if key == "at_least" => {
+                        let n = map.next_value()?;
+                        if map.next_key::<String>()?.is_some() {
+                            return Err(de::Error::custom("expected only at_least"));
+                        }
+                        Ok(DetectionPolicy::AtLeast(n))
+                    },
+                    Some(key) => Err(de::Error::unknown_field(&key, &["at_least"])),
+                    None => Err(de::Error::missing_field("at_least"))
+                }
+            }
+        }
+
+        deserializer.deserialize_any(PolicyVisitor)
+    }
+}
+
+impl DetectionPolicy {
+    pub fn is_met(&self, down : usize, total : usize) -> bool {
+        if total == 0 || down == 0 {
+            return false;
+        }
+
+        match *self {
+            DetectionPolicy::All => down >= total,
+            DetectionPolicy::Majority => down * 2 > total,
+            DetectionPolicy::AtLeast(n) => down >= n,
+        }
+    }
+}
+
+// Thresholds for a target to count as degraded. A threshold that is None is not checked; the config turns one off with 0.
+#[derive(Clone, Copy, Debug)]
+pub struct DegradedThresholds {
+    pub loss_percent: Option<f64>,
+    pub rtt: Option<Duration>,
+    // Number of most recent probes the packet loss and average RTT are computed over
+    pub window: usize,
+}
+
+impl DegradedThresholds {
+    fn is_exceeded(&self, stats : &TargetStats) -> bool {
+        if !stats.is_full() {
+            return false;
+        }
+
+        let loss_exceeded = self.loss_percent.map_or(false, |limit| stats.loss_percent() >= limit);
+        let rtt_exceeded = match (self.rtt, stats.average_rtt()) {
+            (Some(limit), Some(rtt)) => rtt >= limit,
+            _ => false
+        };
+
+        loss_exceeded || rtt_exceeded
+    }
+}
+
+// Sliding window of the most recent probe results for a single target
+pub struct TargetStats {
+    // RTT of each probe, None if it timed out
+    samples: VecDeque<Option<Duration>>,
+    window: usize,
+}
+
+impl TargetStats {
+    pub fn new(window : usize) -> Self {
+        Self {samples: VecDeque::with_capacity(window), window: window}
+    }
+
+    pub fn set_window(&mut self, window : usize) {
+        while self.samples.len() > window {
+            self.samples.pop_front();
+        }
+        self.window = window;
+    }
+
+    pub fn push(&mut self, rtt : Option<Duration>) {
+        if self.samples.len() >= self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.samples.len() >= self.window
+    }
+
+    pub fn sent(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn received(&self) -> usize {
+        self.samples.iter().filter(|sample| sample.is_some()).count()
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+
+        (self.sent() - self.received()) as f64 * 100.0 / self.sent() as f64
+    }
+
+    pub fn average_rtt(&self) -> Option<Duration> {
+        let received = self.received();
+        if received == 0 {
+            return None;
+        }
+
+        let total : Duration = self.samples.iter().flatten().sum();
+        Some(total / received as u32)
+    }
+}
+
+struct TargetState {
+    stats: TargetStats,
+
+    // Send time of the most recent answered and unanswered probe
+    last_answered: Option<Timestamp>,
+    last_unanswered: Option<Timestamp>,
+
+    // Degraded period in progress, if any
+    degraded: Option<ConnectivityDegraded>,
+}
+
+#[derive(Clone)]
+pub enum DetectorEvent {
+    // Enough targets in the group have reached the timeout limit for the outage to count as downtime.
+    // The ConnectivityDown has a start, but no end yet.
+    DowntimeStarted { group: String, cd: ConnectivityDown },
+    // The group is responding again after a downtime, the ConnectivityDown is ready to be stored.
+    DowntimeEnded { group: String, cd: ConnectivityDown },
+    // Packet loss or RTT of the target has been above the thresholds for a full window of probes.
+    DegradedStarted { target: String },
+    // The target is back below the thresholds, the ConnectivityDegraded is ready to be stored.
+    DegradedEnded { degraded: ConnectivityDegraded },
+}
+
+struct GroupState {
+    name: String,
+    policy: DetectionPolicy,
+
+    // Consecutive timeouts per target, capped at the timeout limit
+    no_response_counters: HashMap<String, u32>,
+
+    // Downtime currently being tracked. Started as soon as the policy is met by targets with a single timeout,
+    // but only counts once it is met by targets that have reached the limit.
+    cd: ConnectivityDown,
+
+    is_down: bool,
+}
+
+// The probe result being processed, with the probes before it to bound when the state actually changed
+struct Probe {
+    sent: Timestamp,
+    timed_out: bool,
+    last_answered: Option<Timestamp>,
+    last_unanswered: Option<Timestamp>,
+}
+
+impl GroupState {
+    fn new(group : &TargetGroup) -> Self {
+        let mut no_response_counters = HashMap::new();
+        for address in &group.addresses {
+            no_response_counters.insert(address.clone(), 0);
+        }
+
+        Self {
+            name: group.name.clone(),
+            policy: group.policy,
+            no_response_counters: no_response_counters,
+            cd: ConnectivityDown::new(),
+            is_down: false,
+        }
+    }
+
+    // Applies a changed group definition, keeping the downtime in progress and the counters of addresses still in the group
+    fn reconfigure(&mut self, group : &TargetGroup, limit : u32) {
+        let mut updated = GroupState::new(group);
+        for (address, counter) in updated.no_response_counters.iter_mut() {
+            if let Some(previous) = self.no_response_counters.get(address) {
+                *counter = (*previous).min(limit);
+            }
+        }
+
+        self.policy = updated.policy;
+        self.no_response_counters = updated.no_response_counters;
+    }
+
+    fn count_down(&self, threshold : u32) -> usize {
+        self.no_response_counters.values().filter(|counter| **counter >= threshold).count()
+    }
+
+    fn evaluate(&mut self, probe : &Probe, limit : u32, events : &mut Vec<DetectorEvent>) {
+        let total = self.no_response_counters.len();
+        let tentative = self.policy.is_met(self.count_down(1), total);
+        let confirmed = self.policy.is_met(self.count_down(limit), total);
+
+        if self.is_down && !confirmed {
+            self.cd.end_between(probe.last_unanswered, probe.sent);
+            let cd = mem::replace(&mut self.cd, ConnectivityDown::new());
+            events.push(DetectorEvent::DowntimeEnded { group: self.name.clone(), cd: cd });
+            self.is_down = false;
+        }
+
+        if !self.is_down {
+            if tentative {
+                if !self.cd.is_started() {
+                    // Start tracking of downtime, backdated to the probe that went unanswered
+                    let earliest = if probe.timed_out { probe.last_answered } else { None };
+                    self.cd.start_between(earliest, probe.sent);
+                }
+                if confirmed {
+                    let mut targets : Vec<String> = self.no_response_counters.iter()
+                        .filter(|(_, counter)| **counter >= limit)
+                        .map(|(target, _)| target.clone())
+                        .collect();
+                    targets.sort();
+                    self.cd.set_targets(&self.name, targets);
+                    self.is_down = true;
+                    events.push(DetectorEvent::DowntimeStarted { group: self.name.clone(), cd: self.cd.clone() });
+                }
+            } else if self.cd.is_started() {
+                self.cd = ConnectivityDown::new();
+            }
+        }
+    }
+}
+
+pub struct DowntimeDetector<C: Clock = SystemClock> {
+    clock: C,
+
+    groups: Vec<GroupState>,
+
+    // By target as configured, an IP address or hostname
+    targets: HashMap<String, TargetState>,
+
+    // Maximum ping timeouts before a target counts as down
+    no_response_counter_limit: u32,
+
+    degraded_thresholds: DegradedThresholds,
+}
+
+impl DowntimeDetector<SystemClock> {
+    pub fn new(no_response_counter_limit: u32, groups: &[TargetGroup], degraded_thresholds: DegradedThresholds) -> Self {
+        Self::with_clock(no_response_counter_limit, groups, degraded_thresholds, SystemClock)
+    }
+}
+
+impl<C: Clock> DowntimeDetector<C> {
+    pub fn with_clock(no_response_counter_limit: u32, groups: &[TargetGroup], degraded_thresholds: DegradedThresholds, clock: C) -> Self {
+        Self {
+            clock: clock,
+            groups: groups.iter().map(GroupState::new).collect(),
+            targets: HashMap::new(),
+            no_response_counter_limit: no_response_counter_limit,
+            degraded_thresholds: degraded_thresholds,
+        }
+    }
+
+    // Feeds a single ping result into the detector, returning the state transitions it caused, if any.
+    pub fn process(&mut self, result: &PingResult) -> Vec<DetectorEvent> {
+        let (addr, rtt, sent) = match result {
+            PingResult::Response {target, rtt, sent, ..} => (target.as_str(), Some(*rtt), *sent),
+            PingResult::Timeout {target, sent, ..}
+            | PingResult::DestinationUnreachable {target, sent, ..}
+            | PingResult::TimeExceeded {target, sent, ..}
+            | PingResult::IcmpError {target, sent, ..} => (target.as_str(), None, *sent),
+            _ => return Vec::new()
+        };
+        let timed_out = rtt.is_none();
+
+        let now = self.clock.now();
+        let limit = self.no_response_counter_limit;
+        let mut events = Vec::new();
+
+        let thresholds = self.degraded_thresholds;
+        let target = self.targets.entry(addr.to_owned()).or_insert_with(|| TargetState {
+            stats: TargetStats::new(thresholds.window),
+            last_answered: None,
+            last_unanswered: None,
+            degraded: None,
+        });
+
+        let probe = Probe {
+            sent: sent,
+            timed_out: timed_out,
+            last_answered: target.last_answered,
+            last_unanswered: target.last_unanswered,
+        };
+
+        if timed_out {
+            target.last_unanswered = Some(sent);
+        } else {
+            target.last_answered = Some(sent);
+        }
+
+        self.process_degraded(addr, rtt, now, &mut events);
+
+        for group in self.groups.iter_mut() {
+            let counter = match group.no_response_counters.get_mut(addr) {
+                Some(counter) => counter,
+                None => continue
+            };
+
+            if !timed_out {
+                if *counter != 0 {
+                    *counter = 0;
+                    debug!("no_response_counter for {} reset to 0", addr);
+                }
+            } else if *counter < limit {
+                *counter = *counter + 1;
+                debug!("no_response_counter for {} increased with 1, currently at {}", addr, counter);
+            }
+
+            group.evaluate(&probe, limit, &mut events);
+        }
+
+        events
+    }
+
+    fn process_degraded(&mut self, addr : &str, rtt : Option<Duration>, now : Timestamp, events : &mut Vec<DetectorEvent>) {
+        let thresholds = self.degraded_thresholds;
+        let target = match self.targets.get_mut(addr) {
+            Some(target) => target,
+            None => return
+        };
+
+        target.stats.push(rtt);
+        let exceeded = thresholds.is_exceeded(&target.stats);
+
+        match target.degraded.take() {
+            Some(mut degraded) => {
+                if exceeded {
+                    degraded.update(target.stats.loss_percent(), target.stats.average_rtt());
+                    target.degraded = Some(degraded);
+                } else {
+                    degraded.end_at(now);
+                    events.push(DetectorEvent::DegradedEnded { degraded: degraded });
+                }
+            },
+            None => {
+                if exceeded {
+                    let mut degraded = ConnectivityDegraded::new(addr);
+                    degraded.start_at(now);
+                    degraded.update(target.stats.loss_percent(), target.stats.average_rtt());
+                    target.degraded = Some(degraded);
+                    events.push(DetectorEvent::DegradedStarted { target: addr.to_owned() });
+                }
+            }
+        }
+    }
+
+    // Applies a reloaded config. Groups are matched by name, so a downtime in progress survives changes to its group.
+    // Downtimes of groups that no longer exist are ended, and returned so they can be stored.
+    pub fn reconfigure(&mut self, no_response_counter_limit: u32, groups: &[TargetGroup], degraded_thresholds: DegradedThresholds) -> Vec<DetectorEvent> {
+        let now = self.clock.now();
+        let mut events = Vec::new();
+
+        let mut previous : Vec<GroupState> = self.groups.drain(..).collect();
+        for group in groups {
+            match previous.iter().position(|state| state.name == group.name) {
+                Some(index) => {
+                    let mut state = previous.remove(index);
+                    state.reconfigure(group, no_response_counter_limit);
+                    self.groups.push(state);
+                },
+                None => self.groups.push(GroupState::new(group))
+            }
+        }
+
+        for mut removed in previous {
+            if removed.is_down {
+                removed.cd.end_at(now);
+                events.push(DetectorEvent::DowntimeEnded { group: removed.name, cd: removed.cd });
+            }
+        }
+
+        for target in self.targets.values_mut() {
+            target.stats.set_window(degraded_thresholds.window);
+        }
+
+        self.no_response_counter_limit = no_response_counter_limit;
+        self.degraded_thresholds = degraded_thresholds;
+
+        events
+    }
+
+    // Ends the downtimes in progress as interrupted, so they can be stored before icc exits
+    pub fn shutdown(&mut self) -> Vec<DetectorEvent> {
+        let now = self.clock.now();
+        let mut events = Vec::new();
+
+        for group in self.groups.iter_mut() {
+            if group.is_down {
+                group.cd.interrupt_at(now);
+                let cd = mem::replace(&mut group.cd, ConnectivityDown::new());
+                events.push(DetectorEvent::DowntimeEnded { group: group.name.clone(), cd: cd });
+                group.is_down = false;
+            }
+        }
+
+        events
+    }
+
+    // Records where the downtime in progress for a group is
+    pub fn set_location(&mut self, group: &str, location: FaultLocation) {
+        if let Some(state) = self.groups.iter_mut().find(|state| state.name == group && state.is_down) {
+            state.cd.set_location(location);
+        }
+    }
+
+    // Records traceroutes towards the failing targets of a group that is down
+    pub fn set_traces(&mut self, group: &str, traces: Vec<Trace>) {
+        if let Some(state) = self.groups.iter_mut().find(|state| state.name == group && state.is_down) {
+            state.cd.set_traces(traces);
+        }
+    }
+
+    // Targets of a group that have reached the timeout limit
+    pub fn down_targets(&self, group: &str) -> Vec<String> {
+        let limit = self.no_response_counter_limit;
+        self.groups.iter()
+            .filter(|state| state.name == group)
+            .flat_map(|state| state.no_response_counters.iter())
+            .filter(|(_, counter)| **counter >= limit)
+            .map(|(target, _)| target.clone())
+            .collect()
+    }
+
+    // Whether any group is currently down
+    pub fn is_down(&self) -> bool {
+        self.groups.iter().any(|group| group.is_down)
+    }
+
+    // Whether any target is currently degraded
+    pub fn is_degraded(&self) -> bool {
+        self.targets.values().any(|target| target.degraded.is_some())
+    }
+
+    // Packet loss and RTT over the most recent probes, by target
+    pub fn stats(&self) -> Vec<(&str, &TargetStats)> {
+        self.targets.iter().map(|(addr, target)| (addr.as_str(), &target.stats)).collect()
+    }
+
+    // The downtimes in progress, by group name
+    pub fn current(&self) -> Vec<(&str, &ConnectivityDown)> {
+        self.groups.iter()
+            .filter(|group| group.is_down)
+            .map(|group| (group.name.as_str(), &group.cd))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    // 2019-03-02T20:14:03.250Z
+    const EPOCH : i64 = 1_551_557_643_250;
+
+    // Milliseconds since the test began, set by the test and read by the detector
+    #[derive(Clone)]
+    struct FakeClock {
+        base : Instant,
+        millis : Rc<Cell<i64>>,
+    }
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self {base: Instant::now(), millis: Rc::new(Cell::new(0))}
+        }
+
+        fn at(&self, millis : i64) -> Timestamp {
+            Timestamp {epoch_millis: EPOCH + millis, instant: self.base + Duration::from_millis(millis as u64)}
+        }
+
+        fn set(&self, millis : i64) {
+            self.millis.set(millis);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Timestamp {
+            self.at(self.millis.get())
+        }
+    }
+
+    fn group(name : &str, addresses : &[&str], policy : DetectionPolicy) -> TargetGroup {
+        TargetGroup {name: name.to_owned(), addresses: addresses.iter().map(|address| address.to_string()).collect(), policy: policy}
+    }
+
+    fn thresholds() -> DegradedThresholds {
+        DegradedThresholds {loss_percent: None, rtt: None, window: 10}
+    }
+
+    fn detector(limit : u32, groups : &[TargetGroup], clock : &FakeClock) -> DowntimeDetector<FakeClock> {
+        DowntimeDetector::with_clock(limit, groups, thresholds(), clock.clone())
+    }
+
+    fn timeout(clock : &FakeClock, target : &str, sent : i64) -> PingResult {
+        clock.set(sent);
+        PingResult::Timeout {target: target.to_owned(), addr: None, sent: clock.at(sent)}
+    }
+
+    fn response(clock : &FakeClock, target : &str, sent : i64) -> PingResult {
+        clock.set(sent);
+        PingResult::Response {target: target.to_owned(), addr: "192.0.2.1".parse().unwrap(), rtt: Duration::from_millis(20), sequence: 0, identifier: 0, sent: clock.at(sent)}
+    }
+
+    fn slow_response(clock : &FakeClock, target : &str, sent : i64, rtt : u64) -> PingResult {
+        match response(clock, target, sent) {
+            PingResult::Response {target, addr, sequence, identifier, sent, ..} => PingResult::Response {target, addr, rtt: Duration::from_millis(rtt), sequence, identifier, sent},
+            _ => unreachable!()
+        }
+    }
+
+    fn degraded_started(events : Vec<DetectorEvent>) -> String {
+        match events.as_slice() {
+            [DetectorEvent::DegradedStarted {target}] => target.clone(),
+            _ => panic!("expected a single DegradedStarted, got {} events", events.len())
+        }
+    }
+
+    fn degraded_ended(events : Vec<DetectorEvent>) -> ConnectivityDegraded {
+        match events.as_slice() {
+            [DetectorEvent::DegradedEnded {degraded}] => degraded.clone(),
+            _ => panic!("expected a single DegradedEnded, got {} events", events.len())
+        }
+    }
+
+    fn started(events : Vec<DetectorEvent>) -> (String, ConnectivityDown) {
+        match events.as_slice() {
+            [DetectorEvent::DowntimeStarted {group, cd}] => (group.clone(), cd.clone()),
+            _ => panic!("expected a single DowntimeStarted, got {} events", events.len())
+        }
+    }
+
+    fn ended(events : Vec<DetectorEvent>) -> (String, ConnectivityDown) {
+        match events.as_slice() {
+            [DetectorEvent::DowntimeEnded {group, cd}] => (group.clone(), cd.clone()),
+            _ => panic!("expected a single DowntimeEnded, got {} events", events.len())
+        }
+    }
+
+    #[test]
+    fn starts_and_ends_downtime_with_uncertainty() {
+        let clock = FakeClock::new();
+        let mut detector = detector(2, &[group("default", &["a", "b"], DetectionPolicy::All)], &clock);
+
+        assert!(detector.process(&response(&clock, "a", 0)).is_empty());
+        assert!(detector.process(&response(&clock, "b", 0)).is_empty());
+        assert!(detector.process(&timeout(&clock, "a", 1000)).is_empty());
+        assert!(detector.process(&timeout(&clock, "b", 1000)).is_empty());
+        assert!(detector.process(&timeout(&clock, "a", 2000)).is_empty());
+        assert!(!detector.is_down());
+
+        // Backdated to the first unanswered probe, which may have been lost any time after the last answered one
+        let (group, cd) = started(detector.process(&timeout(&clock, "b", 2000)));
+        assert_eq!(group, "default");
+        assert_eq!(cd.start_epoch_millis(), EPOCH + 1000);
+        assert_eq!(cd.start_uncertainty(), Some(Duration::from_millis(1000)));
+        assert_eq!(cd.targets(), &["a".to_owned(), "b".to_owned()][..]);
+        assert!(detector.is_down());
+        assert_eq!(detector.current().len(), 1);
+
+        let (_, cd) = ended(detector.process(&response(&clock, "a", 3000)));
+        assert_eq!(cd.end_epoch_millis(), EPOCH + 3000);
+        assert_eq!(cd.end_uncertainty(), Some(Duration::from_millis(1000)));
+        assert_eq!(cd.duration_millis(), 2000);
+        assert!(!cd.is_interrupted());
+        assert!(!detector.is_down());
+    }
+
+    #[test]
+    fn single_timeouts_are_not_downtime() {
+        let clock = FakeClock::new();
+        let mut detector = detector(2, &[group("default", &["a"], DetectionPolicy::All)], &clock);
+
+        assert!(detector.process(&timeout(&clock, "a", 1000)).is_empty());
+        assert!(detector.process(&response(&clock, "a", 2000)).is_empty());
+
+        // A later outage starts at its own first timeout, not the earlier one
+        assert!(detector.process(&timeout(&clock, "a", 3000)).is_empty());
+        let (_, cd) = started(detector.process(&timeout(&clock, "a", 4000)));
+        assert_eq!(cd.start_epoch_millis(), EPOCH + 3000);
+        assert_eq!(cd.start_uncertainty(), Some(Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn policies() {
+        assert!(!DetectionPolicy::All.is_met(2, 3));
+        assert!(DetectionPolicy::All.is_met(3, 3));
+        assert!(!DetectionPolicy::Majority.is_met(1, 2));
+        assert!(DetectionPolicy::Majority.is_met(2, 3));
+        assert!(DetectionPolicy::AtLeast(2).is_met(2, 5));
+        assert!(!DetectionPolicy::AtLeast(2).is_met(1, 5));
+        // Nothing down, or nothing to be down
+        assert!(!DetectionPolicy::AtLeast(0).is_met(0, 5));
+        assert!(!DetectionPolicy::All.is_met(0, 0));
+
+        // Timeouts needed before the group is down, with a limit of one
+        for (policy, needed) in vec![(DetectionPolicy::All, 3), (DetectionPolicy::Majority, 2), (DetectionPolicy::AtLeast(1), 1)] {
+            let clock = FakeClock::new();
+            let mut detector = detector(1, &[group("g", &["a", "b", "c"], policy)], &clock);
+            for (index, target) in ["a", "b", "c"].iter().enumerate().take(needed) {
+                let events = detector.process(&timeout(&clock, target, 1000));
+                assert_eq!(events.len(), if index + 1 == needed { 1 } else { 0 }, "{:?} after {} timeouts", policy, index + 1);
+            }
+            assert!(detector.is_down(), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn groups_are_independent() {
+        let clock = FakeClock::new();
+        let mut detector = detector(1, &[group("isp", &["a"], DetectionPolicy::All), group("internet", &["a", "b"], DetectionPolicy::All)], &clock);
+
+        let (group, _) = started(detector.process(&timeout(&clock, "a", 1000)));
+        assert_eq!(group, "isp");
+        let (group, _) = started(detector.process(&timeout(&clock, "b", 1000)));
+        assert_eq!(group, "internet");
+        assert_eq!(detector.down_targets("internet").len(), 2);
+    }
+
+    #[test]
+    fn icmp_errors_count_as_timeouts() {
+        let clock = FakeClock::new();
+        let mut detector = detector(2, &[group("default", &["a"], DetectionPolicy::All)], &clock);
+        let unreachable = |sent : i64| {
+            clock.set(sent);
+            PingResult::DestinationUnreachable {target: "a".to_owned(), addr: "192.0.2.1".parse().unwrap(), from: "192.168.1.1".parse().unwrap(), code: 1, sequence: 0, identifier: 0, sent: clock.at(sent)}
+        };
+
+        // Requests going out say nothing about the target
+        assert!(detector.process(&PingResult::Request {addr: "192.0.2.1".parse().unwrap(), sequence: 0, identifier: 0}).is_empty());
+        assert!(detector.stats().is_empty());
+
+        assert!(detector.process(&unreachable(1000)).is_empty());
+        let (_, cd) = started(detector.process(&unreachable(2000)));
+        // Never answered before, so the outage may have started any time before the first probe
+        assert_eq!(cd.start_epoch_millis(), EPOCH + 1000);
+        assert_eq!(cd.start_uncertainty(), None);
+
+        // Counters are capped at the limit, one answer ends the downtime however long it went on
+        for sent in 3..10 {
+            assert!(detector.process(&unreachable(sent * 1000)).is_empty());
+        }
+        assert_eq!(detector.down_targets("default"), vec!["a".to_owned()]);
+        let (_, cd) = ended(detector.process(&response(&clock, "a", 10_000)));
+        assert_eq!(cd.duration_millis(), 9000);
+        assert_eq!(cd.end_uncertainty(), Some(Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn targets_outside_groups_only_get_stats() {
+        let clock = FakeClock::new();
+        let mut detector = detector(1, &[group("default", &["a"], DetectionPolicy::All)], &clock);
+
+        assert!(detector.process(&timeout(&clock, "b", 1000)).is_empty());
+        assert!(detector.process(&response(&clock, "b", 2000)).is_empty());
+        assert!(!detector.is_down());
+        let stats = detector.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].0, stats[0].1.sent(), stats[0].1.received()), ("b", 2, 1));
+
+        // Location and traces only go to a group that is down
+        detector.set_location("default", FaultLocation::Isp);
+        let (_, cd) = started(detector.process(&timeout(&clock, "a", 3000)));
+        assert_eq!(cd.location_text(), "unknown");
+        detector.set_location("default", FaultLocation::Isp);
+        detector.set_location("other", FaultLocation::Gateway);
+        assert_eq!(detector.current()[0].1.location(), Some(FaultLocation::Isp));
+    }
+
+    #[test]
+    fn reconfigure_keeps_open_outage() {
+        let clock = FakeClock::new();
+        let mut detector = detector(1, &[group("default", &["a"], DetectionPolicy::All)], &clock);
+        let (_, cd) = started(detector.process(&timeout(&clock, "a", 1000)));
+
+        // The group gains a target, the downtime in progress goes on
+        let events = detector.reconfigure(1, &[group("default", &["a", "b"], DetectionPolicy::AtLeast(1))], thresholds());
+        assert!(events.is_empty());
+        assert!(detector.is_down());
+        assert_eq!(detector.current()[0].1.start_epoch_millis(), cd.start_epoch_millis());
+        assert!(detector.process(&timeout(&clock, "b", 2000)).is_empty());
+
+        // The group is gone, so its downtime ends now
+        clock.set(5000);
+        let (group, cd) = ended(detector.reconfigure(1, &[group("other", &["a"], DetectionPolicy::All)], thresholds()));
+        assert_eq!(group, "default");
+        assert_eq!(cd.end_epoch_millis(), EPOCH + 5000);
+        assert!(!detector.is_down());
+    }
+
+    #[test]
+    fn reconfigure_keeps_counters_of_remaining_targets() {
+        let clock = FakeClock::new();
+        let mut detector = detector(3, &[group("default", &["a", "b"], DetectionPolicy::All)], &clock);
+        for sent in &[1000, 2000, 3000] {
+            assert!(detector.process(&timeout(&clock, "a", *sent)).is_empty());
+            assert!(detector.process(&response(&clock, "b", *sent)).is_empty());
+        }
+
+        // b is replaced by c, a keeps its timeouts, capped at the lower limit
+        assert!(detector.reconfigure(2, &[group("default", &["a", "c"], DetectionPolicy::All)], thresholds()).is_empty());
+        assert!(detector.process(&timeout(&clock, "b", 4000)).is_empty());
+        assert!(detector.process(&timeout(&clock, "c", 4000)).is_empty());
+        let (group, cd) = started(detector.process(&timeout(&clock, "c", 5000)));
+        assert_eq!(group, "default");
+        assert_eq!(cd.start_epoch_millis(), EPOCH + 4000);
+        assert_eq!(cd.targets(), &["a".to_owned(), "c".to_owned()][..]);
+
+        // One answer is enough to drop below the lower limit again
+        let (_, cd) = ended(detector.process(&response(&clock, "a", 6000)));
+        assert_eq!(cd.end_epoch_millis(), EPOCH + 6000);
+    }
+
+    #[test]
+    fn shutdown_interrupts_outage() {
+        let clock = FakeClock::new();
+        let mut detector = detector(1, &[group("default", &["a"], DetectionPolicy::All)], &clock);
+        assert!(detector.shutdown().is_empty());
+        started(detector.process(&timeout(&clock, "a", 1000)));
+
+        clock.set(5000);
+        let (group, cd) = ended(detector.shutdown());
+        assert_eq!(group, "default");
+        assert!(cd.is_interrupted());
+        assert_eq!(cd.end_epoch_millis(), EPOCH + 5000);
+        assert_eq!(cd.duration_millis(), 4000);
+        assert!(!detector.is_down());
+        assert!(detector.shutdown().is_empty());
+    }
+
+    #[test]
+    fn degraded_by_packet_loss() {
+        let clock = FakeClock::new();
+        let thresholds = DegradedThresholds {loss_percent: Some(50.0), rtt: None, window: 4};
+        let mut detector = DowntimeDetector::with_clock(100, &[group("default", &["a"], DetectionPolicy::All)], thresholds, clock.clone());
+
+        // Not judged until the window is full
+        for sent in &[1000, 2000, 3000] {
+            assert!(detector.process(&timeout(&clock, "a", *sent)).is_empty());
+        }
+        assert_eq!(degraded_started(detector.process(&response(&clock, "a", 4000))), "a");
+        assert!(detector.is_degraded());
+
+        // 50% is still at the threshold
+        assert!(detector.process(&response(&clock, "a", 5000)).is_empty());
+        let degraded = degraded_ended(detector.process(&response(&clock, "a", 6000)));
+        assert_eq!(degraded.target(), "a");
+        assert_eq!(degraded.start_epoch_millis(), EPOCH + 4000);
+        assert_eq!(degraded.end_epoch_millis(), EPOCH + 6000);
+        // The worst loss seen while degraded
+        assert_eq!(degraded.loss_percent(), 75.0);
+        assert!(!detector.is_degraded());
+        assert!(!detector.is_down());
+    }
+
+    #[test]
+    fn degraded_by_round_trip_time() {
+        let clock = FakeClock::new();
+        let thresholds = DegradedThresholds {loss_percent: None, rtt: Some(Duration::from_millis(100)), window: 2};
+        let mut detector = DowntimeDetector::with_clock(1, &[group("default", &["a", "b"], DetectionPolicy::All)], thresholds, clock.clone());
+
+        assert!(detector.process(&slow_response(&clock, "a", 1000, 150)).is_empty());
+        assert!(detector.process(&slow_response(&clock, "b", 1000, 20)).is_empty());
+        assert_eq!(degraded_started(detector.process(&slow_response(&clock, "a", 2000, 250))), "a");
+        assert!(detector.process(&slow_response(&clock, "b", 2000, 20)).is_empty());
+
+        // Timeouts don't count towards the average
+        assert!(detector.process(&timeout(&clock, "a", 3000)).is_empty());
+        let degraded = degraded_ended(detector.process(&slow_response(&clock, "a", 4000, 10)));
+        assert_eq!(degraded.start_epoch_millis(), EPOCH + 2000);
+        assert_eq!(degraded.end_epoch_millis(), EPOCH + 4000);
+        assert_eq!(degraded.rtt(), Some(Duration::from_millis(250)));
+        assert_eq!(degraded.loss_percent(), 50.0);
+    }
+
+    #[test]
+    fn zero_thresholds_are_off() {
+        let config : crate::util::config::Config = toml::from_str("degraded_loss_percent = 0.0\ndegraded_rtt = 0\ndegraded_window = 2").unwrap();
+        let clock = FakeClock::new();
+        let mut detector = DowntimeDetector::with_clock(100, &[group("default", &["a"], DetectionPolicy::All)], config.degraded_thresholds(), clock.clone());
+
+        for sent in 1..10 {
+            assert!(detector.process(&timeout(&clock, "a", sent * 1000)).is_empty());
+            assert!(detector.process(&slow_response(&clock, "a", sent * 1000 + 500, 5000)).is_empty());
+        }
+        assert!(!detector.is_degraded());
+
+        // Turning a threshold on takes effect on the next probe
+        let thresholds = DegradedThresholds {loss_percent: Some(10.0), rtt: None, window: 2};
+        assert!(detector.reconfigure(100, &[group("default", &["a"], DetectionPolicy::All)], thresholds).is_empty());
+        assert_eq!(degraded_started(detector.process(&timeout(&clock, "a", 11000))), "a");
+    }
+}
@@ -0,0 +1,53 @@
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use crate::util::config::ConfigError;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(ConfigError),
+    Database(rusqlite::Error),
+    // Raw ICMP sockets can only be opened by root, or with CAP_NET_RAW
+    Permission(io::Error),
+    // An echo request couldn't be sent
+    Send { addr: IpAddr, error: io::Error },
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "{}", e),
+            Error::Database(e) => write!(f, "database error: {}", e),
+            Error::Permission(e) => {
+                if cfg!(windows) {
+                    write!(f, "unable to open raw ICMP socket ({}), icc needs to run as administrator", e)
+                } else {
+                    write!(f, "unable to open raw ICMP socket ({}), icc needs to run as root or with CAP_NET_RAW, e.g. `setcap cap_net_raw+ep <path to icc>`", e)
+                }
+            },
+            Error::Send { addr, error } => write!(f, "unable to send ping to {}: {}", addr, error),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::Config(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Database(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
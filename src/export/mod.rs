@@ -0,0 +1,255 @@
+use std::io::Write;
+use std::str::FromStr;
+use chrono::prelude::{TimeZone, Utc};
+use crate::error::Error;
+use crate::ping::model::timestamp_text;
+use crate::report::duration_text;
+use crate::util::db::{Db, DowntimeRecord};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Csv,
+    // JSON Lines, one downtime per line
+    Jsonl,
+    // iCalendar, one event per downtime
+    Ics,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Jsonl => "application/x-ndjson",
+            Format::Ics => "text/calendar; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Jsonl => "jsonl",
+            Format::Ics => "ics",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" | "json" => Ok(Format::Jsonl),
+            "ics" | "ical" => Ok(Format::Ics),
+            _ => Err(format!("unknown format '{}', expected csv, jsonl or ics", s))
+        }
+    }
+}
+
+// A downtime as exported. Times are local RFC 3339 text as well as milliseconds since the unix epoch, durations milliseconds.
+#[derive(Serialize)]
+pub struct Downtime {
+    pub start : String,
+    pub end : String,
+    pub start_epoch_millis : i64,
+    pub end_epoch_millis : i64,
+    pub duration : i64,
+    pub start_uncertainty : Option<i64>,
+    pub end_uncertainty : Option<i64>,
+    pub location : Option<String>,
+    pub interrupted : bool,
+    // None for downtimes recorded before groups were stored
+    pub group : Option<String>,
+    pub targets : Vec<String>,
+}
+
+impl Downtime {
+    pub fn new(record : &DowntimeRecord) -> Self {
+        Self {
+            start: timestamp_text(record.start),
+            end: timestamp_text(record.end),
+            start_epoch_millis: record.start,
+            end_epoch_millis: record.end,
+            duration: record.duration,
+            start_uncertainty: record.start_uncertainty,
+            end_uncertainty: record.end_uncertainty,
+            location: record.location.clone(),
+            interrupted: record.interrupted,
+            group: record.group.clone(),
+            targets: record.targets.clone(),
+        }
+    }
+}
+
+// Writes the downtimes overlapping from - to, oldest first
+pub fn export<W : Write>(db : &Db, format : Format, from : i64, to : i64, out : &mut W) -> Result<(), Error> {
+    let downtimes : Vec<Downtime> = db.downtimes_between(from, to)?.iter().map(Downtime::new).collect();
+
+    match format {
+        Format::Csv => write_csv(&downtimes, out)?,
+        Format::Jsonl => {
+            for downtime in &downtimes {
+                let line = serde_json::to_string(downtime).map_err(|e| Error::Io(e.into()))?;
+                writeln!(out, "{}", line)?;
+            }
+        },
+        Format::Ics => write_ics(&downtimes, out)?,
+    }
+    Ok(())
+}
+
+fn write_csv<W : Write>(downtimes : &[Downtime], out : &mut W) -> Result<(), Error> {
+    writeln!(out, "start,end,start_epoch_millis,end_epoch_millis,duration_ms,start_uncertainty_ms,end_uncertainty_ms,location,interrupted,group,targets")?;
+    let optional = |value : Option<i64>| value.map_or(String::new(), |value| value.to_string());
+    for downtime in downtimes {
+        writeln!(out, "{},{},{},{},{},{},{},{},{},{},{}",
+            downtime.start,
+            downtime.end,
+            downtime.start_epoch_millis,
+            downtime.end_epoch_millis,
+            downtime.duration,
+            optional(downtime.start_uncertainty),
+            optional(downtime.end_uncertainty),
+            csv_field(downtime.location.as_ref().map_or("", |location| location.as_str())),
+            downtime.interrupted,
+            csv_field(downtime.group.as_ref().map_or("", |group| group.as_str())),
+            csv_field(&downtime.targets.join(",")))?;
+    }
+    Ok(())
+}
+
+// Quoted if it contains a separator, quote or line break
+fn csv_field(value : &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+// RFC 5545. Lines end with CRLF, and are folded at 75 octets.
+fn write_ics<W : Write>(downtimes : &[Downtime], out : &mut W) -> Result<(), Error> {
+    let stamp = ics_time(Utc::now().timestamp_millis());
+    let mut lines : Vec<String> = vec![
+        "BEGIN:VCALENDAR".to_owned(),
+        "VERSION:2.0".to_owned(),
+        "PRODID:-//icc//Internet connectivity checker//EN".to_owned(),
+    ];
+
+    for downtime in downtimes {
+        let location = downtime.location.as_ref().map_or("unknown", |location| location.as_str());
+        let mut description = format!("Internet connectivity was down for {}, location: {}.", duration_text(downtime.duration), location);
+        if let Some(group) = &downtime.group {
+            description.push_str(&format!(" Group {} was down, failing targets: {}.", group, downtime.targets.join(", ")));
+        }
+        if downtime.interrupted {
+            description.push_str(" icc was stopped before the outage ended.");
+        }
+
+        lines.push("BEGIN:VEVENT".to_owned());
+        // Groups that went down in the same round start at the same time
+        lines.push(format!("UID:{}", ics_text(&format!("downtime-{}-{}@icc", downtime.start_epoch_millis, downtime.group.as_ref().map_or("", |group| group.as_str())))));
+        lines.push(format!("DTSTAMP:{}", stamp));
+        lines.push(format!("DTSTART:{}", ics_time(downtime.start_epoch_millis)));
+        lines.push(format!("DTEND:{}", ics_time(downtime.end_epoch_millis)));
+        lines.push(format!("SUMMARY:{}", ics_text(&format!("Internet down ({})", location))));
+        lines.push(format!("DESCRIPTION:{}", ics_text(&description)));
+        lines.push("END:VEVENT".to_owned());
+    }
+    lines.push("END:VCALENDAR".to_owned());
+
+    for line in lines {
+        out.write_all(ics_fold(&line).as_bytes())?;
+        out.write_all(b"\r\n")?;
+    }
+    Ok(())
+}
+
+fn ics_time(epoch_millis : i64) -> String {
+    Utc.timestamp_millis(epoch_millis).format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn ics_text(value : &str) -> String {
+    value.replace('\\', "\\\\").replace(';', "\\;").replace(',', "\\,").replace('\n', "\\n")
+}
+
+fn ics_fold(line : &str) -> String {
+    let mut folded = String::new();
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("isp"), "isp");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn escapes_ics_text() {
+        assert_eq!(ics_text("a,b;c\\d\ne"), r"a\,b\;c\\d\ne");
+        assert_eq!(ics_time(1_551_557_643_250), "20190302T201403Z");
+    }
+
+    #[test]
+    fn folds_ics_lines() {
+        assert_eq!(ics_fold("SUMMARY:short"), "SUMMARY:short");
+
+        let long = "x".repeat(160);
+        let folded = ics_fold(&long);
+        let lines : Vec<&str> = folded.split("\r\n").collect();
+        assert_eq!(lines.iter().map(|line| line.len()).collect::<Vec<_>>(), vec![75, 75, 12]);
+        assert!(lines[1..].iter().all(|line| line.starts_with(' ')));
+        assert_eq!(folded.replace("\r\n ", ""), long);
+
+        // Multi-byte characters aren't split, and lines stay within 75 octets
+        let long = "é".repeat(60);
+        let folded = ics_fold(&long);
+        assert!(folded.split("\r\n").all(|line| line.len() <= 75));
+        assert_eq!(folded.replace("\r\n ", ""), long);
+    }
+
+    #[test]
+    fn exports_csv() {
+        let db = Db::new(":memory:").unwrap();
+        db.import_downtime(&DowntimeRecord {start: 1000, end: 61_000, duration: 60_000, start_uncertainty: Some(500), end_uncertainty: None,
+            location: Some("isp".to_owned()), traceroute: None, interrupted: false, group: Some("isp".to_owned()), targets: vec!["8.8.8.8".to_owned()]}).unwrap();
+
+        let mut out = Vec::new();
+        export(&db, Format::Csv, 0, 100_000, &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines : Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].ends_with(",1000,61000,60000,500,,isp,false,isp,8.8.8.8"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn ics_uids_differ_per_group() {
+        let db = Db::new(":memory:").unwrap();
+        for group in &["default", "isp"] {
+            db.import_downtime(&DowntimeRecord {start: 1000, end: 61_000, duration: 60_000, start_uncertainty: None, end_uncertainty: None,
+                location: None, traceroute: None, interrupted: false, group: Some(group.to_string()), targets: Vec::new()}).unwrap();
+        }
+
+        let mut out = Vec::new();
+        export(&db, Format::Ics, 0, 100_000, &mut out).unwrap();
+        let ics = String::from_utf8(out).unwrap();
+        let uids : Vec<&str> = ics.split("\r\n").filter(|line| line.starts_with("UID:")).collect();
+        assert_eq!(uids, vec!["UID:downtime-1000-default@icc", "UID:downtime-1000-isp@icc"]);
+    }
+}
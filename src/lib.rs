@@ -1,8 +1,16 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+extern crate serde_json;
 extern crate toml;
 extern crate rand;
 
+pub mod control;
+pub mod detector;
+pub mod error;
+pub mod export;
 pub mod ping;
-pub mod util;
\ No newline at end of file
+pub mod report;
+pub mod util;
+
+pub use self::error::Error;
\ No newline at end of file
@@ -5,21 +5,22 @@ extern crate log;
 #[macro_use]
 
 pub use std::sync::{Arc, Mutex, RwLock};
-pub use std::thread;
-pub use std::sync::mpsc::{channel, Sender, Receiver};
-pub use std::collections::HashMap;
+pub use std::thread::{self, JoinHandle};
+pub use std::io;
+pub use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+pub use std::collections::{HashMap, HashSet};
 pub use pnet::transport::{TransportSender, TransportReceiver};
 pub use pnet::transport::TransportChannelType::Layer4;
 pub use pnet::transport::TransportProtocol::{Ipv4, Ipv6};
 pub use pnet::packet::Packet;
-pub use pnet::packet::icmp::{IcmpTypes, echo_reply, echo_request};
+pub use pnet::packet::icmp::{IcmpPacket, IcmpTypes, echo_reply, echo_request};
 pub use pnet::packet::icmpv6::{Icmpv6Types, MutableIcmpv6Packet};
 pub use pnet::transport::transport_channel;
 pub use pnet::packet::ip::IpNextHeaderProtocols;
 pub use std::time::{Duration, Instant};
-pub use std::net::{IpAddr};
+pub use std::net::{IpAddr, ToSocketAddrs};
 pub use std::collections::BTreeMap;
 pub use pnet::transport::{icmp_packet_iter, icmpv6_packet_iter};
 pub use rand::random;
 pub use pnet::util;
-pub use log::{info, debug, error};
\ No newline at end of file
+pub use log::{info, debug, warn, error};
\ No newline at end of file
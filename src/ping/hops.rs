@@ -0,0 +1,382 @@
+use super::deps::*;
+use super::PingUtility;
+use super::model::{duration_millis, FaultLocation, Hop, Trace};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// TTL of probes that aren't limited to a hop, and put back on the socket after a hop probe if its own can't be read
+const DEFAULT_TTL : u8 = 64;
+
+// Replies to hop probes, by identifier and sequence number of the echo request
+pub(crate) type HopWaiters = Arc<Mutex<HashMap<(u16, u16), Sender<IpAddr>>>>;
+
+// TTL set on a socket, before a hop probe lowers it
+#[cfg(unix)]
+fn get_ttl(fd : libc::c_int) -> io::Result<u8> {
+    let mut ttl : libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(fd, libc::IPPROTO_IP, libc::IP_TTL, &mut ttl as *mut libc::c_int as *mut libc::c_void, &mut length)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ttl as u8)
+}
+
+#[cfg(not(unix))]
+fn get_ttl<T>(_fd : T) -> io::Result<u8> {
+    Err(io::Error::new(io::ErrorKind::Other, "reading the TTL isn't supported"))
+}
+
+// Default gateway from the kernel routing table
+#[cfg(target_os = "linux")]
+pub fn default_gateway() -> Option<IpAddr> {
+    parse_default_gateway(&fs::read_to_string("/proc/net/route").ok()?)
+}
+
+// The gateway of the default route in /proc/net/route
+#[cfg(any(target_os = "linux", test))]
+fn parse_default_gateway(routes : &str) -> Option<IpAddr> {
+    // Iface Destination Gateway Flags ..., addresses in hex, in host byte order
+    for line in routes.lines().skip(1) {
+        let columns : Vec<&str> = line.split_whitespace().collect();
+        if columns.len() < 3 || columns[1] != "00000000" {
+            continue;
+        }
+
+        if let Ok(gateway) = u32::from_str_radix(columns[2], 16) {
+            if gateway != 0 {
+                return Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(gateway))));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn default_gateway() -> Option<IpAddr> {
+    None
+}
+
+// Addresses a home network uses for itself, hops with these are still on our side of the ISP
+fn is_local(address : &IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => address.is_private() || address.is_loopback() || address.is_link_local(),
+        IpAddr::V6(address) => address.is_loopback(),
+    }
+}
+
+// Route towards a destination, as far as it matters for telling where an outage is
+#[derive(Clone, Debug)]
+pub struct NetworkPath {
+    pub destination: IpAddr,
+    pub gateway: Option<IpAddr>,
+    // Hop number (TTL) and address of the first hop outside the home network
+    pub isp_hop: Option<(u8, IpAddr)>,
+}
+
+// Sends TTL-limited echo requests, sharing the sockets of the PingUtility it was made from
+#[derive(Clone)]
+pub struct HopProber {
+    tx_sender: Arc<Mutex<TransportSender>>,
+    waiters: HopWaiters,
+}
+
+impl HopProber {
+    pub(crate) fn new(tx_sender : Arc<Mutex<TransportSender>>, waiters : HopWaiters) -> Self {
+        Self {tx_sender: tx_sender, waiters: waiters}
+    }
+
+    // Address of whatever answered an echo request towards destination sent with the given TTL:
+    // the router at that hop, or the destination itself if it is that close. IPv4 only.
+    pub fn probe(&self, destination : IpAddr, ttl : u8, timeout : Duration) -> Option<IpAddr> {
+        self.probe_timed(destination, ttl, timeout).map(|(hop, _)| hop)
+    }
+
+    // Same as probe, with the round-trip time of the reply
+    pub fn probe_timed(&self, destination : IpAddr, ttl : u8, timeout : Duration) -> Option<(IpAddr, Duration)> {
+        if !destination.is_ipv4() {
+            return None;
+        }
+
+        let identifier = random::<u16>();
+        let sequence = random::<u16>();
+        let (sender, receiver) = channel();
+        self.waiters.lock().unwrap().insert((identifier, sequence), sender);
+
+        let start = Instant::now();
+        let sent = {
+            let mut buf : Vec<u8> = vec![0; 16];
+            let mut echo_request_packet = echo_request::MutableEchoRequestPacket::new(&mut buf[..]).unwrap();
+            echo_request_packet.set_sequence_number(sequence);
+            echo_request_packet.set_identifier(identifier);
+            echo_request_packet.set_icmp_type(IcmpTypes::EchoRequest);
+            let csum = PingUtility::icmp_checksum(&echo_request_packet);
+            echo_request_packet.set_checksum(csum);
+
+            let mut tx = self.tx_sender.lock().unwrap();
+            let original = get_ttl(tx.socket.fd).unwrap_or_else(|e| {
+                debug!("Unable to read TTL before hop probe, {} is put back: {}", DEFAULT_TTL, e);
+                DEFAULT_TTL
+            });
+            let sent = tx.set_ttl(ttl).and_then(|_| tx.send_to(echo_request_packet, destination));
+            if let Err(e) = tx.set_ttl(original) {
+                error!("Unable to reset TTL after hop probe: {}", e);
+            }
+            sent
+        };
+
+        let hop = match sent {
+            Ok(_) => receiver.recv_timeout(timeout).ok().map(|hop| (hop, start.elapsed())),
+            Err(e) => {
+                debug!("Unable to send hop probe to {} with TTL {}: {}", destination, ttl, e);
+                None
+            }
+        };
+
+        self.waiters.lock().unwrap().remove(&(identifier, sequence));
+        hop
+    }
+
+    // Probes every hop up to max_hops at once, so a trace takes about one timeout.
+    // Hops past the one the destination answered from are left out.
+    pub fn traceroute(&self, target : &str, destination : IpAddr, max_hops : u8, timeout : Duration) -> Trace {
+        let probes : Vec<(u8, thread::JoinHandle<Option<(IpAddr, Duration)>>)> = (1..=max_hops).map(|ttl| {
+            let prober = self.clone();
+            (ttl, thread::spawn(move || prober.probe_timed(destination, ttl, timeout)))
+        }).collect();
+
+        let mut hops = Vec::new();
+        for (ttl, probe) in probes {
+            let reply = probe.join().unwrap_or(None);
+            hops.push(Hop {
+                ttl: ttl,
+                address: reply.map(|(address, _)| address),
+                rtt: reply.map(|(_, rtt)| duration_millis(rtt)),
+            });
+        }
+
+        if let Some(reached) = hops.iter().position(|hop| hop.address == Some(destination)) {
+            hops.truncate(reached + 1);
+        }
+
+        Trace {target: target.to_owned(), destination: destination, hops: hops}
+    }
+
+    // Finds the gateway and the first ISP hop towards destination, looking at most max_hops out
+    pub fn discover(&self, destination : IpAddr, max_hops : u8, timeout : Duration) -> NetworkPath {
+        let mut path = NetworkPath {destination: destination, gateway: default_gateway(), isp_hop: None};
+
+        for ttl in 1..=max_hops {
+            let hop = match self.probe(destination, ttl, timeout) {
+                Some(hop) => hop,
+                None => continue
+            };
+
+            if ttl == 1 && path.gateway.is_none() {
+                path.gateway = Some(hop);
+            }
+
+            if hop == destination {
+                break;
+            }
+
+            if ttl > 1 && !is_local(&hop) {
+                path.isp_hop = Some((ttl, hop));
+                break;
+            }
+        }
+
+        path
+    }
+
+    // Probes the layers of the path outwards, the outage is at the first one that doesn't answer
+    pub fn locate(&self, path : &NetworkPath, timeout : Duration) -> FaultLocation {
+        locate(path, |destination, ttl| self.probe(destination, ttl, timeout))
+    }
+}
+
+// Where an outage is on the path, given what answers a probe towards a destination with a TTL
+fn locate<F : Fn(IpAddr, u8) -> Option<IpAddr>>(path : &NetworkPath, probe : F) -> FaultLocation {
+    let gateway_reachable = probe(path.destination, 1).is_some()
+        || path.gateway.map_or(false, |gateway| probe(gateway, DEFAULT_TTL).is_some());
+    if !gateway_reachable {
+        return if path.gateway.is_some() { FaultLocation::Gateway } else { FaultLocation::Unknown };
+    }
+
+    match path.isp_hop {
+        Some((ttl, _)) => {
+            if probe(path.destination, ttl).is_some() {
+                FaultLocation::Internet
+            } else {
+                FaultLocation::Isp
+            }
+        },
+        None => FaultLocation::Unknown
+    }
+}
+
+// Where a downtime is, and the routes towards its failing targets
+pub struct Finding {
+    pub group: String,
+    // Start of the downtime it was found for, the group may be up again or down for another reason by the time it is read
+    pub start_epoch_millis: i64,
+    pub location: FaultLocation,
+    pub traces: Vec<Trace>,
+}
+
+// Keeps the network path towards a destination, and tells where outages are.
+// Probing takes up to max_hops timeouts, so it happens on threads of its own and the results are read with findings().
+pub struct Locator {
+    prober: HopProber,
+    path: Arc<Mutex<NetworkPath>>,
+    discovering: Arc<AtomicBool>,
+    max_hops: u8,
+    timeout: Duration,
+    sender: Sender<Finding>,
+    findings: Receiver<Finding>,
+}
+
+impl Locator {
+    // Starts discovering the path, outages located before it is known only get as far as the default gateway
+    pub fn new(prober : HopProber, destination : IpAddr, max_hops : u8, timeout : Duration) -> Self {
+        let (sender, findings) = channel();
+        let locator = Self {
+            prober: prober,
+            path: Arc::new(Mutex::new(NetworkPath {destination: destination, gateway: default_gateway(), isp_hop: None})),
+            discovering: Arc::new(AtomicBool::new(false)),
+            max_hops: max_hops,
+            timeout: timeout,
+            sender: sender,
+            findings: findings,
+        };
+        locator.discover();
+        locator
+    }
+
+    pub fn path(&self) -> NetworkPath {
+        self.path.lock().unwrap().clone()
+    }
+
+    // Whether both the gateway and the first ISP hop are known
+    pub fn is_discovered(&self) -> bool {
+        let path = self.path.lock().unwrap();
+        path.gateway.is_some() && path.isp_hop.is_some()
+    }
+
+    // Looks for the path again, unless that is already under way
+    pub fn discover(&self) {
+        if self.discovering.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let prober = self.prober.clone();
+        let path = self.path.clone();
+        let discovering = self.discovering.clone();
+        let (max_hops, timeout) = (self.max_hops, self.timeout);
+        thread::spawn(move || {
+            let destination = path.lock().unwrap().destination;
+            let found = prober.discover(destination, max_hops, timeout);
+            info!("Network path to {}: gateway {:?}, first ISP hop {:?}", found.destination, found.gateway, found.isp_hop);
+            *path.lock().unwrap() = found;
+            discovering.store(false, Ordering::SeqCst);
+        });
+    }
+
+    // Locates the downtime of a group and traces the routes towards its failing targets, the Finding comes out of findings()
+    pub fn locate(&self, group : &str, start_epoch_millis : i64, targets : Vec<(String, IpAddr)>) {
+        let prober = self.prober.clone();
+        let path = self.path();
+        let sender = self.sender.clone();
+        let group = group.to_owned();
+        let (max_hops, timeout) = (self.max_hops, self.timeout);
+        thread::spawn(move || {
+            let location = prober.locate(&path, timeout);
+            let traces = targets.iter()
+                .map(|(target, address)| prober.traceroute(target, *address, max_hops, timeout))
+                .collect();
+            // The receiving end is gone once icc stops
+            let _ = sender.send(Finding {group: group, start_epoch_millis: start_epoch_millis, location: location, traces: traces});
+        });
+    }
+
+    // Findings since the last call, doesn't block
+    pub fn findings(&self) -> Vec<Finding> {
+        self.findings.try_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTES : &str = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t0000A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+wlan0\t00000000\t0102A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+";
+
+    #[test]
+    fn reads_the_default_gateway() {
+        assert_eq!(parse_default_gateway(ROUTES), Some(IpAddr::from([192, 168, 1, 1])));
+
+        // Only the header, a default route without a gateway, and lines that can't be read
+        assert_eq!(parse_default_gateway(ROUTES.lines().next().unwrap()), None);
+        assert_eq!(parse_default_gateway("Iface\tDestination\tGateway\ntun0\t00000000\t00000000\t0001\n"), None);
+        assert_eq!(parse_default_gateway("Iface\tDestination\tGateway\neth0\t00000000\nppp0\t00000000\tnothex\n"), None);
+        assert_eq!(parse_default_gateway(""), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reads_the_ttl_of_a_socket() {
+        use std::os::unix::io::AsRawFd;
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_ttl(42).unwrap();
+        assert_eq!(get_ttl(socket.as_raw_fd()).unwrap(), 42);
+        assert!(get_ttl(-1).is_err());
+    }
+
+    // Hops towards 203.0.113.1: the gateway, another router at home, the ISP and the destination
+    const HOPS : [[u8; 4]; 4] = [[192, 168, 1, 1], [10, 0, 0, 1], [198, 51, 100, 1], [203, 0, 113, 1]];
+
+    fn path(gateway : bool, isp_hop : bool) -> NetworkPath {
+        NetworkPath {
+            destination: IpAddr::from(HOPS[3]),
+            gateway: if gateway { Some(IpAddr::from(HOPS[0])) } else { None },
+            isp_hop: if isp_hop { Some((3, IpAddr::from(HOPS[2]))) } else { None },
+        }
+    }
+
+    // Answers probes towards the destination from the hops up to reachable, probes at other addresses only if the gateway is reachable
+    fn probe(reachable : usize) -> impl Fn(IpAddr, u8) -> Option<IpAddr> {
+        move |destination, ttl| {
+            let ttl = (ttl as usize).min(HOPS.len());
+            if destination != IpAddr::from(HOPS[3]) {
+                return if reachable >= 1 && destination == IpAddr::from(HOPS[0]) { Some(destination) } else { None };
+            }
+            if ttl <= reachable { Some(IpAddr::from(HOPS[ttl - 1])) } else { None }
+        }
+    }
+
+    #[test]
+    fn locates_outages_by_the_last_hop_answering() {
+        assert_eq!(locate(&path(true, true), probe(0)), FaultLocation::Gateway);
+        assert_eq!(locate(&path(true, true), probe(2)), FaultLocation::Isp);
+        assert_eq!(locate(&path(true, true), probe(3)), FaultLocation::Internet);
+        assert_eq!(locate(&path(true, true), probe(4)), FaultLocation::Internet);
+
+        // The gateway drops probes with a TTL of 1, but answers a ping
+        let gateway_only_pinged = |destination : IpAddr, ttl : u8| if ttl == 1 { None } else { probe(1)(destination, ttl) };
+        assert_eq!(locate(&path(true, true), gateway_only_pinged), FaultLocation::Isp);
+
+        // Without knowing the path it isn't clear where the outage is
+        assert_eq!(locate(&path(false, true), probe(0)), FaultLocation::Unknown);
+        assert_eq!(locate(&path(true, false), probe(2)), FaultLocation::Unknown);
+        assert_eq!(locate(&path(false, false), probe(4)), FaultLocation::Unknown);
+    }
+}
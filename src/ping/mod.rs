@@ -1,21 +1,174 @@
 mod deps;
 pub mod model;
+pub mod hops;
+pub mod probe;
+mod track;
+mod stop;
 use self::deps::*;
+use self::model::Timestamp;
+use self::hops::{HopProber, HopWaiters};
+use self::track::{RequestKey, RequestTracker, Tracked};
+pub use self::track::TrackingStats;
+use self::stop::StopSignal;
+use crate::error::Error;
 
+// `target` is the name the target was added with, `addr` what its address resolved to when the probe was sent.
+// `sent` is when the probe the result belongs to was sent.
+// ICMP errors are matched to the request by the header quoted in them, `from` is the router or host that sent the error.
+#[derive(Clone)]
 pub enum PingResult {
-    Timeout{addr: IpAddr},
-    Response{addr: IpAddr, rtt: Duration, sequence: u16, identifier: u16},
-    Request{addr: IpAddr, sequence: u16, identifier: u16, sent_success: bool}
+    // addr is None if the hostname hasn't been resolved yet
+    Timeout{target: String, addr: Option<IpAddr>, sent: Timestamp},
+    Response{target: String, addr: IpAddr, rtt: Duration, sequence: u16, identifier: u16, sent: Timestamp},
+    Request{addr: IpAddr, sequence: u16, identifier: u16},
+    // code is the ICMP code, e.g. 0 network unreachable, 1 host unreachable
+    DestinationUnreachable{target: String, addr: IpAddr, from: IpAddr, code: u8, sequence: u16, identifier: u16, sent: Timestamp},
+    TimeExceeded{target: String, addr: IpAddr, from: IpAddr, sequence: u16, identifier: u16, sent: Timestamp},
+    // Any other ICMP error quoting the request, e.g. parameter problem or source quench
+    IcmpError{target: String, addr: IpAddr, from: IpAddr, icmp_type: u8, code: u8, sequence: u16, identifier: u16, sent: Timestamp},
 }
 
-pub type PingUtilityResult = Result<(PingUtility, Receiver<PingResult>), String>;
+impl PingResult {
+    // The probe failed, with no need to wait for the timeout
+    pub fn is_error(&self) -> bool {
+        match self {
+            PingResult::DestinationUnreachable{..} | PingResult::TimeExceeded{..} | PingResult::IcmpError{..} => true,
+            _ => false
+        }
+    }
+
+    // Same result, reported for one of the targets pinged at the address
+    fn with_target(mut self, name : &str) -> Self {
+        match &mut self {
+            PingResult::Timeout{target, ..}
+            | PingResult::Response{target, ..}
+            | PingResult::DestinationUnreachable{target, ..}
+            | PingResult::TimeExceeded{target, ..}
+            | PingResult::IcmpError{target, ..} => *target = name.to_owned(),
+            PingResult::Request{..} => {}
+        }
+        self
+    }
+}
+
+// Destination, identifier and sequence number of the echo request quoted in an ICMP error.
+// The quote is the original IPv4 header followed by the first 8 bytes of the ICMP message.
+fn quoted_echo(original : &[u8]) -> Option<(IpAddr, u16, u16)> {
+    let header_length = (*original.get(0)? & 0x0f) as usize * 4;
+    if header_length < 20 {
+        return None;
+    }
+
+    let destination = original.get(16..20)?;
+    let echo = original.get(header_length..header_length + 8)?;
+    if echo[0] != IcmpTypes::EchoRequest.0 {
+        return None;
+    }
+
+    let destination = IpAddr::from([destination[0], destination[1], destination[2], destination[3]]);
+    let identifier = u16::from_be_bytes([echo[4], echo[5]]);
+    let sequence = u16::from_be_bytes([echo[6], echo[7]]);
+    Some((destination, identifier, sequence))
+}
+
+// The echo request quoted in an ICMP error, after the 4 bytes that are unused or specific to the error type
+fn quoted_request(packet : &IcmpPacket) -> Option<(IpAddr, u16, u16)> {
+    packet.payload().get(4..).and_then(quoted_echo)
+}
+
+// What an ICMP error from a router, or the destination, means for the request it quoted
+fn error_result(packet : &IcmpPacket, quoted : (IpAddr, u16, u16), from : IpAddr, sent : Timestamp) -> PingResult {
+    let (destination, identifier, sequence) = quoted;
+    let code = packet.get_icmp_code().0;
+    match packet.get_icmp_type() {
+        IcmpTypes::DestinationUnreachable => PingResult::DestinationUnreachable{target: String::new(), addr: destination, from: from, code: code, sequence: sequence, identifier: identifier, sent: sent},
+        IcmpTypes::TimeExceeded => PingResult::TimeExceeded{target: String::new(), addr: destination, from: from, sequence: sequence, identifier: identifier, sent: sent},
+        icmp_type => PingResult::IcmpError{target: String::new(), addr: destination, from: from, icmp_type: icmp_type.0, code: code, sequence: sequence, identifier: identifier, sent: sent}
+    }
+}
+
+// Identifier and sequence number of an echo reply, which has the same layout for ICMP and ICMPv6.
+// None if the packet is too short, as a reply may come from anyone.
+fn echo_reply_ids(packet : &[u8]) -> Option<(u16, u16)> {
+    let reply = echo_reply::EchoReplyPacket::new(packet)?;
+    Some((reply.get_identifier(), reply.get_sequence_number()))
+}
+
+// Logs the first of the failures to send to an address as a warning, the rest while it keeps failing at debug level
+fn send_failed(failing : &mut HashSet<IpAddr>, address : IpAddr, e : Error) {
+    if failing.insert(address) {
+        warn!("{}, further failures are not logged until sending to it works again", e);
+    } else {
+        debug!("{}", e);
+    }
+}
+
+// Passed from the listener threads to the pinging thread
+enum Message {
+    Result(PingResult),
+    // Sent by stop_pinging, so the pinging thread doesn't wait out the round
+    Wake,
+}
+
+// An address being pinged, and the targets it was resolved from
+struct Probed {
+    targets: Vec<String>,
+}
+
+// Per target overrides of how often it is pinged, and how long to wait for a reply
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct ProbeOptions {
+    // Time between pings. Targets are pinged at most once per round, so anything shorter than the timeout pings every round.
+    pub interval: Option<Duration>,
+    // Replies slower than this count as timeouts. A round lasts as long as the longest timeout.
+    pub timeout: Option<Duration>,
+}
+
+struct Target {
+    // IP address or hostname
+    address: String,
+    // The address being pinged
+    resolved: Option<IpAddr>,
+    // Everything the hostname resolved to, its first A and AAAA record
+    resolved_all: Vec<IpAddr>,
+    options: ProbeOptions,
+    last_sent: Option<Instant>,
+    // Pinged this round and not answered yet
+    awaiting: bool,
+    // Added and not resolved yet, it isn't pinged until then
+    resolving: bool,
+}
+
+impl Target {
+    fn is_due(&self, now : Instant) -> bool {
+        match (self.last_sent, self.options.interval) {
+            (Some(last_sent), Some(interval)) => now.duration_since(last_sent) >= interval,
+            _ => true
+        }
+    }
+}
+
+pub type PingUtilityResult = Result<(PingUtility, Receiver<PingResult>), Error>;
+
+fn socket_error(e : io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => Error::Permission(e),
+        _ => Error::Io(e)
+    }
+}
 
 pub struct PingUtility {
     // Time before ICMP request gets dropped
-    timeout: Arc<Duration>,
+    timeout: Arc<RwLock<Duration>>,
 
     // Holds IP addresses to be pinged
-    addresses: Arc<Mutex<BTreeMap<IpAddr, bool>>>,
+    addresses: Arc<Mutex<BTreeMap<IpAddr, Probed>>>,
+
+    // Targets by name, with the address they currently resolve to
+    targets: Arc<Mutex<BTreeMap<String, Target>>>,
+
+    // How often hostnames are resolved again
+    resolve_interval: Arc<RwLock<Duration>>,
 
     // Size of ICMP payload to be sent
     size: i32,
@@ -36,46 +189,52 @@ pub struct PingUtility {
     rxv6_receiver: Arc<Mutex<TransportReceiver>>,
 
     // Sender for passing data between threads
-    thread_tx: Sender<PingResult>,
+    thread_tx: Sender<Message>,
 
     // Receiver for passing data between threads,
-    thread_rx: Arc<Mutex<Receiver<PingResult>>>,
+    thread_rx: Arc<Mutex<Receiver<Message>>>,
 
-    // Timer for tracking RTT,
-    timer: Arc<RwLock<Instant>>,
+    // Timer for tracking RTT, set when a round of pings has been sent
+    timer: Arc<RwLock<Timestamp>>,
 
-    flag_stop: Arc<Mutex<bool>>,
+    // Hop probes waiting for a reply
+    hop_waiters: HopWaiters,
+
+    // Echo requests sent by the pinger that haven't been answered
+    tracker: Arc<Mutex<RequestTracker>>,
+
+    // Set while pinging, stops the threads started by start_pinging
+    stop: Arc<Mutex<Option<StopSignal>>>,
+
+    // Threads started by start_pinging, joined when stopping
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
 
     flag_ipv6_enable : bool,
 }
 
 impl PingUtility {
     pub fn new(max_timeout: Option<u64>) -> PingUtilityResult {
-        let timeout : Arc<Duration>;
+        let timeout : Arc<RwLock<Duration>>;
         if let Some(timeout_value) = max_timeout {
-            timeout = Arc::new(Duration::from_millis(timeout_value));
+            timeout = Arc::new(RwLock::new(Duration::from_millis(timeout_value)));
         } else {
-            timeout = Arc::new(Duration::from_millis(1000));
+            timeout = Arc::new(RwLock::new(Duration::from_millis(1000)));
         }
 
         let protocol = Layer4(Ipv4(IpNextHeaderProtocols::Icmp));
-        let (tx, rx) = match transport_channel(4096, protocol) {
-            Ok((tx, rx)) => (tx, rx),
-            Err(e) => return Err(e.to_string()),
-        };
+        let (tx, rx) = transport_channel(4096, protocol).map_err(socket_error)?;
 
         let protocolv6 = Layer4(Ipv6(IpNextHeaderProtocols::Icmpv6));
-        let (txv6, rxv6) = match transport_channel(4096, protocolv6) {
-            Ok((txv6, rxv6)) => (txv6, rxv6),
-            Err(e) => return Err(e.to_string()),
-        };
+        let (txv6, rxv6) = transport_channel(4096, protocolv6).map_err(socket_error)?;
 
         let (sender, receiver) = channel();
         let (thread_tx, thread_rx) = channel();
 
-        let mut payload = PingUtility {
+        let payload = PingUtility {
             timeout: timeout,
             addresses: Arc::new(Mutex::new(BTreeMap::new())),
+            targets: Arc::new(Mutex::new(BTreeMap::new())),
+            resolve_interval: Arc::new(RwLock::new(Duration::from_secs(300))),
             size: 16,
             results_channel_sender: sender,
             tx_sender: Arc::new(Mutex::new(tx)),
@@ -84,44 +243,91 @@ impl PingUtility {
             rxv6_receiver: Arc::new(Mutex::new(rxv6)),
             thread_rx: Arc::new(Mutex::new(thread_rx)),
             thread_tx: thread_tx,
-            timer: Arc::new(RwLock::new(Instant::now())),
-            flag_stop: Arc::new(Mutex::new(false)),
+            timer: Arc::new(RwLock::new(Timestamp::now())),
+            hop_waiters: Arc::new(Mutex::new(HashMap::new())),
+            tracker: Arc::new(Mutex::new(RequestTracker::new())),
+            stop: Arc::new(Mutex::new(None)),
+            threads: Arc::new(Mutex::new(Vec::new())),
             flag_ipv6_enable: false
         };
 
-        payload.start_listener();
-
         Ok((payload, receiver))
     }
 
-    fn start_listener(&self) {
+    // The listeners only read from the sockets when there is something to read, so they can be stopped while idle
+    fn start_listener(&self, stop : &StopSignal) {
         // IPV4 ICMP packet dumping
-        let thread_tx : Sender<PingResult> = self.thread_tx.clone();
+        let thread_tx : Sender<Message> = self.thread_tx.clone();
         let rx : Arc<Mutex<TransportReceiver>> = self.rx_receiver.clone();
-        let timer : Arc<RwLock<Instant>> = self.timer.clone();
+        let timer : Arc<RwLock<Timestamp>> = self.timer.clone();
+        let hop_waiters : HopWaiters = self.hop_waiters.clone();
+        let stopv4 = stop.clone();
 
-        thread::spawn(move || {
+        let listener = thread::spawn(move || {
             let mut receiver = rx.lock().unwrap();
+            let fd = receiver.socket.fd;
             let mut iter = icmp_packet_iter(&mut receiver);
 
             loop {
+                if !stopv4.wait_readable(fd) {
+                    debug!("IPv4 listener stopped");
+                    return
+                }
+
                 match iter.next() {
                     Ok((packet, addr)) => {
                         let mut identifier : u16 = 0;
                         let mut seq : u16 = 0;
                         match packet.get_icmp_type() {
                             IcmpTypes::EchoReply => {
-                                let echo_reply_packet = echo_reply::EchoReplyPacket::new(packet.packet()).unwrap();
-                                seq = echo_reply_packet.get_sequence_number();
-                                identifier = echo_reply_packet.get_identifier();
-                                //debug!("EchoReply -> {:?}", echo_reply_packet);
+                                match echo_reply_ids(packet.packet()) {
+                                    Some((reply_identifier, reply_sequence)) => {
+                                        identifier = reply_identifier;
+                                        seq = reply_sequence;
+                                    },
+                                    None => {
+                                        debug!("Ignoring echo reply from {} too short to be one", addr);
+                                        continue;
+                                    }
+                                }
+
+                                // Hop probe that reached its destination
+                                if let Some(waiter) = hop_waiters.lock().unwrap().remove(&(identifier, seq)) {
+                                    let _ = waiter.send(addr);
+                                    continue;
+                                }
                             },
-                            _ => {}
+                            IcmpTypes::DestinationUnreachable
+                            | IcmpTypes::TimeExceeded
+                            | IcmpTypes::ParameterProblem
+                            | IcmpTypes::SourceQuench => {
+                                let quoted = match quoted_request(&packet) {
+                                    Some(quoted) => quoted,
+                                    None => continue
+                                };
+                                let (_, identifier, sequence) = quoted;
+
+                                // Hop probe answered by a router on the way
+                                if let Some(waiter) = hop_waiters.lock().unwrap().remove(&(identifier, sequence)) {
+                                    let _ = waiter.send(addr);
+                                    continue;
+                                }
+
+                                let result = error_result(&packet, quoted, addr, *timer.read().unwrap());
+                                if let Err(e) = thread_tx.send(Message::Result(result)) {
+                                    error!("Error sending ping result on channel: {}", e)
+                                }
+                                continue;
+                            },
+                            icmp_type => {
+                                debug!("Ignoring ICMP type {} from {}", icmp_type.0, addr);
+                                continue;
+                            }
                         };
 
                         debug!("{:?}", packet);
                         let start_time = timer.read().unwrap();
-                        match thread_tx.send(PingResult::Response{addr: addr, rtt: Instant::now().duration_since(*start_time), sequence: seq, identifier: identifier}) {
+                        match thread_tx.send(Message::Result(PingResult::Response{target: String::new(), addr: addr, rtt: Instant::now().duration_since(start_time.instant), sequence: seq, identifier: identifier, sent: *start_time})) {
                             Ok(_) => {},
                             Err(e) => {
                                 error!("Error sending ping result on channel: {}", e)
@@ -138,6 +344,7 @@ impl PingUtility {
                 }
             }
         });
+        self.keep_listener(listener);
 
 
         if self.flag_ipv6_enable {
@@ -145,26 +352,40 @@ impl PingUtility {
             let thread_txv6 = self.thread_tx.clone();
             let rxv6 = self.rxv6_receiver.clone();
             let timerv6 = self.timer.clone();
-            thread::spawn(move || {
+            let stopv6 = stop.clone();
+            let listener = thread::spawn(move || {
                 let mut receiver = rxv6.lock().unwrap();
+                let fd = receiver.socket.fd;
                 let mut iter = icmpv6_packet_iter(&mut receiver);
                 loop {
+                    if !stopv6.wait_readable(fd) {
+                        debug!("IPv6 listener stopped");
+                        return
+                    }
+
                     match iter.next() {
                         Ok((packet, addr)) => {
                             let mut identifier : u16 = 0;
                             let mut seq : u16 = 0;
                             match packet.get_icmpv6_type() {
                                 Icmpv6Types::EchoReply => {
-                                    let echo_reply_packet = echo_reply::EchoReplyPacket::new(packet.packet()).unwrap();
-                                    seq = echo_reply_packet.get_sequence_number();
-                                    identifier = echo_reply_packet.get_identifier();
-                                    //debug!("EchoReply -> {:?}", echo_reply_packet);
+                                    match echo_reply_ids(packet.packet()) {
+                                        Some((reply_identifier, reply_sequence)) => {
+                                            identifier = reply_identifier;
+                                            seq = reply_sequence;
+                                        },
+                                        None => {
+                                            debug!("Ignoring echo reply from {} too short to be one", addr);
+                                            continue;
+                                        }
+                                    }
                                 },
-                                _ => {}
+                                // Errors can't be matched to requests, IPv6 requests aren't tracked
+                                _ => continue
                             };
 
                             let start_time = timerv6.read().unwrap();
-                            match thread_txv6.send(PingResult::Response{addr: addr, rtt: Instant::now().duration_since(*start_time), sequence: seq, identifier: identifier}) {
+                            match thread_txv6.send(Message::Result(PingResult::Response{target: String::new(), addr: addr, rtt: Instant::now().duration_since(start_time.instant), sequence: seq, identifier: identifier, sent: *start_time})) {
                                 Ok(_) => {},
                                 Err(e) => {
                                     error!("Error sending ping result on channel: {}", e)
@@ -181,69 +402,152 @@ impl PingUtility {
                     }
                 }
             });
+            self.keep_listener(listener);
         }
 
     }
 
-    pub fn start_pinging(&self) {
-        {
-            let mut stop = self.flag_stop.lock().unwrap();
-            *stop = false;
-        }
+    // Does nothing if already pinging
+    pub fn start_pinging(&self) -> Result<(), Error> {
+        let stop = {
+            let mut running = self.stop.lock().unwrap();
+            if running.is_some() {
+                return Ok(());
+            }
+
+            let stop = StopSignal::new()?;
+            *running = Some(stop.clone());
+            stop
+        };
 
         let thread_rx = self.thread_rx.clone();
         let tx_sender = self.tx_sender.clone();
         let txv6_sender = self.txv6_sender.clone();
         let results_channel_sender = self.results_channel_sender.clone();
-        let flag_stop = self.flag_stop.clone();
         let addresses = self.addresses.clone();
+        let targets = self.targets.clone();
         let timer = self.timer.clone();
         let timeout = self.timeout.clone();
 
-        // While on Windows pnet only receives the pings it sends itself, that is not the case on Linux/OSX.
-        // Therefore this keeps track of sequence numbers and identifiers that have been sent, to make sure that only pings that have
-        // been sent by icc, is monitored.
-        let mut ping_track : HashMap<String, bool> = HashMap::new();
+        let tracker = self.tracker.clone();
+
+        self.start_listener(&stop);
+        self.start_resolver(&stop);
+
+        let pinger = thread::spawn(move || {
+            // Addresses sending to has failed for since the last request that went out
+            let mut failing : HashSet<IpAddr> = HashSet::new();
 
-        thread::spawn(move || {
             loop {
-                for (address, seen) in addresses.lock().unwrap().iter_mut() {
-                    if address.is_ipv4() {
-                        let res : PingResult = Self::send_echo_request(&mut tx_sender.lock().unwrap(), *address);
+                // Set before sending, as this is also the send time reported with the results of this round
+                {
+                    let mut timer = timer.write().unwrap();
+                    *timer = Timestamp::now();
+                }
 
-                        if let PingResult::Request{addr, sequence, identifier, sent_success} = res {
-                            ping_track.insert(format!("{};{};{}", addr.to_string(), sequence, identifier).to_owned(), true);
+                // Only targets whose interval has passed are pinged this round
+                let mut due : Vec<IpAddr> = Vec::new();
+                let mut round_timeout = *timeout.read().unwrap();
+                {
+                    let now = timer.read().unwrap().instant;
+                    for target in targets.lock().unwrap().values_mut() {
+                        if target.resolving || !target.is_due(now) {
+                            continue;
                         }
 
-                    } else if address.is_ipv6() {
-                        Self::send_echov6_request(&mut txv6_sender.lock().unwrap(), *address);
+                        target.last_sent = Some(now);
+                        target.awaiting = true;
+                        if let Some(address) = target.resolved {
+                            if !due.contains(&address) {
+                                due.push(address);
+                            }
+                        }
+                        if let Some(target_timeout) = target.options.timeout {
+                            round_timeout = round_timeout.max(target_timeout);
+                        }
                     }
-                    *seen = false;
                 }
 
-                {
-                    let mut timer = timer.write().unwrap();
-                    *timer = Instant::now();
+                // Replies after this count as late, whenever the request is expired
+                let deadline = timer.read().unwrap().instant + round_timeout;
+                for address in due {
+                    if address.is_ipv4() {
+                        // Requests that couldn't be sent aren't tracked, their targets time out.
+                        // Sending fails every round while the network is down, e.g. with "No route to host" (error 65) on macOS,
+                        // so only the first failure is logged as a warning.
+                        match Self::send_echo_request(&mut tx_sender.lock().unwrap(), address) {
+                            Ok(PingResult::Request{addr, sequence, identifier}) => {
+                                tracker.lock().unwrap().insert(RequestKey {addr: addr, identifier: identifier, sequence: sequence}, deadline);
+                                failing.remove(&address);
+                            },
+                            Ok(_) => {},
+                            Err(e) => send_failed(&mut failing, address, e)
+                        }
+
+                    } else if address.is_ipv6() {
+                        match Self::send_echov6_request(&mut txv6_sender.lock().unwrap(), address) {
+                            Ok(_) => {
+                                failing.remove(&address);
+                            },
+                            Err(e) => send_failed(&mut failing, address, e)
+                        }
+                    }
                 }
 
+                // Waits for replies until the round is over, or pinging is stopped
                 loop {
-                    match thread_rx.lock().unwrap().try_recv() {
-                        Ok(result) => {
-                            match result {
-                                PingResult::Response {addr: addr, rtt: _, sequence: sequence, identifier: identifier} => {
-                                    if let Some(seen) = addresses.lock().unwrap().get_mut(&addr) {
-                                        *seen = true;
+                    let elapsed = Instant::now().duration_since(timer.read().unwrap().instant);
+                    if elapsed >= round_timeout || stop.is_stopped() {
+                        break
+                    }
+
+                    match thread_rx.lock().unwrap().recv_timeout(round_timeout - elapsed) {
+                        Ok(Message::Result(result)) => {
+                            match result.clone() {
+                                PingResult::Response {addr, sequence, identifier, ..}
+                                | PingResult::DestinationUnreachable {addr, sequence, identifier, ..}
+                                | PingResult::TimeExceeded {addr, sequence, identifier, ..}
+                                | PingResult::IcmpError {addr, sequence, identifier, ..} => {
+                                    // Errors fail the probe straight away, replies only count within the target's timeout
+                                    let rtt = match result {
+                                        PingResult::Response {rtt, ..} => Some(rtt),
+                                        _ => None
+                                    };
+                                    let response_targets = match addresses.lock().unwrap().get(&addr) {
+                                        Some(probed) => probed.targets.clone(),
+                                        None => Vec::new()
+                                    };
+
+                                    let tracked = tracker.lock().unwrap().take(&RequestKey {addr: addr, identifier: identifier, sequence: sequence}, Instant::now());
+                                    if tracked == Tracked::Late {
+                                        debug!("Late reply from {}, seq = {}, identifier = {}", addr, sequence, identifier);
                                     }
 
-                                    let sum = format!("{};{};{}", addr.to_string(), sequence, identifier);
-                                    if ping_track.contains_key(sum.as_str()) {
-                                        ping_track.remove(sum.as_str());
-                                        match results_channel_sender.send(result) {
-                                            Ok(_) => {
-                                                debug!("PingResult sent to results_channel_receiver")
-                                            },
-                                            Err(e) => {
-                                                error!("Error sending ping result on channel: {}", e)
+                                    if tracked == Tracked::Pending {
+                                        // Reported once for every target resolving to the address that is waiting for it.
+                                        // Replies slower than the target's own timeout are left to be reported as timeouts.
+                                        let mut answered = Vec::new();
+                                        {
+                                            let default_timeout = *timeout.read().unwrap();
+                                            let mut targets = targets.lock().unwrap();
+                                            for name in response_targets {
+                                                if let Some(target) = targets.get_mut(&name) {
+                                                    if target.awaiting && rtt.map_or(true, |rtt| rtt <= target.options.timeout.unwrap_or(default_timeout)) {
+                                                        target.awaiting = false;
+                                                        answered.push(name);
+                                                    }
+                                                }
+                                            }
+                                        }
+
+                                        for target in answered {
+                                            match results_channel_sender.send(result.clone().with_target(&target)) {
+                                                Ok(_) => {
+                                                    debug!("PingResult sent to results_channel_receiver")
+                                                },
+                                                Err(e) => {
+                                                    error!("Error sending ping result on channel: {}", e)
+                                                }
                                             }
                                         }
                                     }
@@ -252,38 +556,81 @@ impl PingUtility {
                                 _ => {}
                             }
                         },
-                        Err(_) => {
-                            let start_time = timer.read().unwrap();
-                            if Instant::now().duration_since(*start_time) > *timeout {
-                                break
-                            }
-                            use std::{thread, time};
-                            thread::sleep(time::Duration::from_millis(50));
-                        }
+                        Ok(Message::Wake) => {},
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => return
                     }
                 }
 
-                for (addr, seen) in addresses.lock().unwrap().iter() {
-                    if *seen == false {
-                        match results_channel_sender.send(PingResult::Timeout {addr: *addr}) {
-                            Ok(_) => {},
-                            Err(e) => {
-                                error!("Error sending ping Idle result on channel: {}", e)
-                            }
-                        }
+                // Replies to the interrupted round are left to the tracker, nothing is reported for it
+                if stop.is_stopped() {
+                    for target in targets.lock().unwrap().values_mut() {
+                        target.awaiting = false;
                     }
+                    debug!("Pinging stopped");
+                    return
                 }
 
+                {
+                    let mut tracker = tracker.lock().unwrap();
+                    tracker.expire(Instant::now());
+                    let stats = tracker.stats();
+                    debug!("Tracking {} pending and {} expired requests, {} late replies", stats.pending, stats.expired, stats.late_replies);
+                }
 
-                if *flag_stop.lock().unwrap() {
-                    debug!("flag_stop activated");
-                    return
+                let sent = *timer.read().unwrap();
+                // Includes hostnames that couldn't be resolved yet, they can't be pinged so they count as timed out
+                let mut idle : Vec<PingResult> = Vec::new();
+                for (name, target) in targets.lock().unwrap().iter_mut() {
+                    if target.awaiting {
+                        target.awaiting = false;
+                        idle.push(PingResult::Timeout {target: name.clone(), addr: target.resolved, sent: sent});
+                    }
+                }
+
+                for result in idle {
+                    match results_channel_sender.send(result) {
+                        Ok(_) => {},
+                        Err(e) => {
+                            error!("Error sending ping Idle result on channel: {}", e)
+                        }
+                    }
                 }
             }
         });
+        self.threads.lock().unwrap().push(pinger);
+        Ok(())
+    }
+
+    // Stops the pinging, listener and resolver threads, and waits for them to end. Pinging can be started again afterwards,
+    // except on Windows: its listeners keep the sockets until the next packet arrives, so a new listener would never get to read.
+    pub fn stop_pinging(&self) {
+        self.handle().stop();
+    }
+
+    pub fn is_pinging(&self) -> bool {
+        self.handle().is_pinging()
     }
 
-    pub fn send_echo_request(tx: &mut TransportSender, address: IpAddr) -> PingResult {
+    // For stopping or waiting on the pinger from another thread
+    pub fn handle(&self) -> PingHandle {
+        PingHandle {
+            stop: self.stop.clone(),
+            threads: self.threads.clone(),
+            thread_tx: self.thread_tx.clone(),
+        }
+    }
+
+    // Listeners can't be woken up on Windows, so they aren't waited for there. They end on the next packet received.
+    #[cfg(unix)]
+    fn keep_listener(&self, listener : JoinHandle<()>) {
+        self.threads.lock().unwrap().push(listener);
+    }
+
+    #[cfg(not(unix))]
+    fn keep_listener(&self, _listener : JoinHandle<()>) {}
+
+    pub fn send_echo_request(tx: &mut TransportSender, address: IpAddr) -> Result<PingResult, Error> {
         let mut buf : Vec<u8> = vec![0; 16];
 
         let mut echo_request_packet = echo_request::MutableEchoRequestPacket::new(&mut buf[..]).unwrap();
@@ -300,33 +647,17 @@ impl PingUtility {
         match tx.send_to(echo_request_packet, address) {
             Ok(n) => {
                 debug!("Using payload {} {} {}", &n, sequence_number, identifier_number);
-                PingResult::Request {
+                Ok(PingResult::Request {
                     addr: address.clone(),
                     sequence: sequence_number,
                     identifier: identifier_number,
-                    sent_success: true
-                }
-            },
-            Err(e) => {
-                let mut ignore_err : bool = true;
-
-                // OSX
-                if cfg!(target_os = "macos") {
-                    if e.raw_os_error().unwrap() == 65 {
-                        ignore_err = false;
-                    }
-                }
-
-                if !ignore_err {
-                    panic!("failed to send packet: {}", e);
-                }
-
-                PingResult::Timeout { addr: address.clone() }
+                })
             },
+            Err(e) => Err(Error::Send {addr: address, error: e}),
         }
     }
 
-    pub fn send_echov6_request(tx: &mut TransportSender, address: IpAddr) {
+    pub fn send_echov6_request(tx: &mut TransportSender, address: IpAddr) -> Result<(), Error> {
         let mut buf : Vec<u8> = vec![0; 16];
 
         let mut echo_request_packet = MutableIcmpv6Packet::new(&mut buf[..]).unwrap();
@@ -338,11 +669,32 @@ impl PingUtility {
         match tx.send_to(echo_request_packet, address) {
             Ok(n) => {
                 debug!("Using payload {}", &n);
+                Ok(())
             },
-            Err(e) => panic!("failed to send packet: {}", e),
+            Err(e) => Err(Error::Send {addr: address, error: e}),
         }
     }
 
+    // Size of the request tracking table, and how many replies came in after their timeout. IPv6 requests aren't tracked.
+    pub fn tracking_stats(&self) -> TrackingStats {
+        self.tracker.lock().unwrap().stats()
+    }
+
+    // Address a target was last resolved to, and is being pinged
+    pub fn resolved(&self, name: &str) -> Option<IpAddr> {
+        self.targets.lock().unwrap().get(name).and_then(|target| target.resolved)
+    }
+
+    // IPv4 and IPv6 address a target was last resolved to, whether or not it is pinged at them
+    pub fn resolved_all(&self, name: &str) -> Vec<IpAddr> {
+        self.targets.lock().unwrap().get(name).map_or(Vec::new(), |target| target.resolved_all.clone())
+    }
+
+    // For TTL-limited probes, sharing this utility's sockets
+    pub fn hop_prober(&self) -> HopProber {
+        HopProber::new(self.tx_sender.clone(), self.hop_waiters.clone())
+    }
+
     fn icmp_checksum(packet: &echo_request::MutableEchoRequestPacket) -> u16 {
         util::checksum(packet.packet(), 1)
     }
@@ -351,6 +703,11 @@ impl PingUtility {
         util::checksum(packet.packet(), 1)
     }
 
+    // Takes effect from the next round of pings
+    pub fn set_timeout(&self, max_timeout: u64) {
+        *self.timeout.write().unwrap() = Duration::from_millis(max_timeout);
+    }
+
     pub fn enable_ipv6(&mut self) {
         self.flag_ipv6_enable = true;
     }
@@ -359,29 +716,353 @@ impl PingUtility {
         self.flag_ipv6_enable = false;
     }
 
+    // How often hostnames are resolved again, takes effect after the next resolve
+    pub fn set_resolve_interval(&self, interval: Duration) {
+        *self.resolve_interval.write().unwrap() = interval;
+    }
+
+    // Accepts IP addresses as well as hostnames, the address is also the name of the target
     pub fn add_ipaddress(&self, ipaddress: &str) {
-        let address = ipaddress.parse::<IpAddr>();
-        match address {
-            Ok(valid_address) => {
-                debug!("Address added {}", valid_address);
-                self.addresses.lock().unwrap().insert(valid_address, true);
+        self.add_target(ipaddress, ipaddress, ProbeOptions::default());
+    }
+
+    pub fn remove_ipaddress(&self, ipaddress: &str) {
+        self.remove_target(ipaddress);
+    }
+
+    // Hostnames are resolved in the background, and again every resolve interval. Results are reported under the name.
+    // A slow DNS server holds up neither the caller nor the other targets, the target is only pinged once resolved.
+    pub fn add_target(&self, name: &str, address: &str, options: ProbeOptions) {
+        {
+            let mut targets = self.targets.lock().unwrap();
+            if targets.contains_key(name) {
+                return;
+            }
+            targets.insert(name.to_owned(), Target {
+                address: address.to_owned(),
+                resolved: None,
+                resolved_all: Vec::new(),
+                options: options,
+                last_sent: None,
+                awaiting: false,
+                resolving: true,
+            });
+        }
+
+        let targets = self.targets.clone();
+        let addresses = self.addresses.clone();
+        let ipv6 = self.flag_ipv6_enable;
+        if address.parse::<IpAddr>().is_ok() {
+            Self::resolve_added(&targets, &addresses, name, address, ipv6);
+        } else {
+            let (name, address) = (name.to_owned(), address.to_owned());
+            thread::spawn(move || Self::resolve_added(&targets, &addresses, &name, &address, ipv6));
+        }
+    }
+
+    // Starts pinging a newly added target, unless it was removed or replaced while resolving
+    fn resolve_added(targets: &Mutex<BTreeMap<String, Target>>, addresses: &Mutex<BTreeMap<IpAddr, Probed>>, name: &str, address: &str, ipv6: bool) {
+        let resolved_all = Self::resolve(address);
+        let resolved = Self::pinged(&resolved_all, ipv6);
+
+        let mut targets = targets.lock().unwrap();
+        match targets.get_mut(name) {
+            Some(target) if target.address == address && target.resolving => {
+                target.resolved = resolved;
+                target.resolved_all = resolved_all.clone();
+                target.resolving = false;
             },
-            Err(e) => {
-                error!("Error adding ip address {}. Error: {}", ipaddress, e);
+            _ => return
+        }
+        Self::assign(&mut addresses.lock().unwrap(), name, None, resolved);
+
+        match resolved {
+            Some(valid_address) => {
+                debug!("Target added {} {} ({})", name, address, valid_address);
+            },
+            None if resolved_all.is_empty() => {
+                error!("Error resolving {} for target {}, it counts as timed out until it resolves", address, name);
+            },
+            None => {
+                warn!("{} for target {} only has IPv6 addresses and IPv6 isn't enabled, it counts as timed out", address, name);
             }
         }
     }
 
-    pub fn remove_ipaddress(&self, ipaddress: &str) {
-        let address = ipaddress.parse::<IpAddr>();
-        match address {
-            Ok(valid_address) => {
-                debug!("Address removed {}", valid_address);
-                self.addresses.lock().unwrap().remove(&valid_address);
+    pub fn remove_target(&self, name: &str) {
+        match self.targets.lock().unwrap().remove(name) {
+            Some(target) => {
+                debug!("Target removed {}", name);
+                Self::assign(&mut self.addresses.lock().unwrap(), name, target.resolved, None);
+            },
+            None => {
+                error!("Error removing target {}. Error: not being monitored", name);
+            }
+        }
+    }
+
+    // The first IPv4 and the first IPv6 address the target resolves to, or the target itself if it is an IP address
+    fn resolve(target: &str) -> Vec<IpAddr> {
+        if let Ok(address) = target.parse::<IpAddr>() {
+            return vec![address];
+        }
+
+        match (target, 0).to_socket_addrs() {
+            Ok(resolved) => {
+                let resolved : Vec<IpAddr> = resolved.map(|socket_address| socket_address.ip()).collect();
+                resolved.iter().find(|address| address.is_ipv4())
+                    .into_iter()
+                    .chain(resolved.iter().find(|address| address.is_ipv6()))
+                    .cloned()
+                    .collect()
             },
             Err(e) => {
-                error!("Error adding ip address {}. Error: {}", ipaddress, e);
+                debug!("Unable to resolve {}: {}", target, e);
+                Vec::new()
             }
         }
     }
-}
\ No newline at end of file
+
+    // The address to ping out of the ones a target resolved to. IPv6 is preferred if it is enabled. Otherwise its replies
+    // aren't listened for, so only IPv4 addresses are pinged.
+    fn pinged(resolved: &[IpAddr], ipv6: bool) -> Option<IpAddr> {
+        if !ipv6 {
+            return resolved.iter().find(|address| address.is_ipv4()).cloned();
+        }
+        resolved.iter()
+            .find(|address| address.is_ipv6())
+            .or_else(|| resolved.first())
+            .cloned()
+    }
+
+    // Moves a target from the address it used to resolve to, to the one it resolves to now
+    fn assign(addresses: &mut BTreeMap<IpAddr, Probed>, target: &str, previous: Option<IpAddr>, current: Option<IpAddr>) {
+        if let Some(previous) = previous {
+            let mut unused = false;
+            if let Some(probed) = addresses.get_mut(&previous) {
+                probed.targets.retain(|name| name != target);
+                unused = probed.targets.is_empty();
+            }
+            if unused {
+                addresses.remove(&previous);
+            }
+        }
+
+        if let Some(current) = current {
+            addresses.entry(current)
+                .or_insert_with(|| Probed {targets: Vec::new()})
+                .targets.push(target.to_owned());
+        }
+    }
+
+    // Periodically resolves hostname targets again, and swaps the pinged address when it changes
+    fn start_resolver(&self, stop : &StopSignal) {
+        let targets = self.targets.clone();
+        let addresses = self.addresses.clone();
+        let resolve_interval = self.resolve_interval.clone();
+        let stop = stop.clone();
+        let ipv6 = self.flag_ipv6_enable;
+
+        let resolver = thread::spawn(move || {
+            loop {
+                let interval = *resolve_interval.read().unwrap();
+                if stop.wait_timeout(interval) {
+                    debug!("Resolver stopped");
+                    return
+                }
+
+                let hostnames : Vec<(String, String, Option<IpAddr>, Vec<IpAddr>)> = targets.lock().unwrap().iter()
+                    .filter(|(_, target)| !target.resolving && target.address.parse::<IpAddr>().is_err())
+                    .map(|(name, target)| (name.clone(), target.address.clone(), target.resolved, target.resolved_all.clone()))
+                    .collect();
+
+                for (name, hostname, previous, previous_all) in hostnames {
+                    let current_all = Self::resolve(&hostname);
+                    let current = match Self::pinged(&current_all, ipv6) {
+                        Some(current) => current,
+                        // Keep pinging the last known address, DNS failing is likely part of the outage being monitored
+                        None => continue
+                    };
+
+                    if previous_all == current_all {
+                        continue;
+                    }
+
+                    let mut targets = targets.lock().unwrap();
+                    match targets.get_mut(&name) {
+                        // Changed while resolving
+                        Some(target) if target.address == hostname && target.resolved == previous => {
+                            target.resolved = Some(current);
+                            target.resolved_all = current_all.clone();
+                        },
+                        _ => continue
+                    }
+
+                    if previous == Some(current) {
+                        debug!("{} now resolves to {:?}", hostname, current_all);
+                        continue;
+                    }
+                    info!("{} now resolves to {}", hostname, current);
+                    Self::assign(&mut addresses.lock().unwrap(), &name, previous, Some(current));
+                }
+            }
+        });
+        self.threads.lock().unwrap().push(resolver);
+    }
+}
+
+impl Drop for PingUtility {
+    fn drop(&mut self) {
+        self.stop_pinging();
+    }
+}
+
+// Stops the threads of a PingUtility, or waits for them to end, from any thread
+#[derive(Clone)]
+pub struct PingHandle {
+    stop: Arc<Mutex<Option<StopSignal>>>,
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    thread_tx: Sender<Message>,
+}
+
+impl PingHandle {
+    pub fn stop(&self) {
+        if let Some(stop) = self.stop.lock().unwrap().take() {
+            stop.stop();
+            let _ = self.thread_tx.send(Message::Wake);
+        }
+        self.join();
+    }
+
+    pub fn is_pinging(&self) -> bool {
+        self.stop.lock().unwrap().is_some()
+    }
+
+    // Blocks until the threads have ended, i.e. until stopped from elsewhere
+    pub fn join(&self) {
+        let threads : Vec<JoinHandle<()>> = self.threads.lock().unwrap().drain(..).collect();
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("A pinger thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_addresses() {
+        assert_eq!(PingUtility::resolve("192.0.2.1"), vec![IpAddr::from([192, 0, 2, 1])]);
+        assert_eq!(PingUtility::resolve("2001:db8::1"), vec!["2001:db8::1".parse::<IpAddr>().unwrap()]);
+        assert!(PingUtility::resolve("nonexistent.invalid").is_empty());
+    }
+
+    #[test]
+    fn pings_the_enabled_family() {
+        let v4 = IpAddr::from([192, 0, 2, 1]);
+        let v6 : IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(PingUtility::pinged(&[v4, v6], false), Some(v4));
+        assert_eq!(PingUtility::pinged(&[v4, v6], true), Some(v6));
+        // IPv6 replies aren't listened for unless it is enabled
+        assert_eq!(PingUtility::pinged(&[v6], false), None);
+        assert_eq!(PingUtility::pinged(&[v4], true), Some(v4));
+        assert_eq!(PingUtility::pinged(&[], false), None);
+    }
+
+    #[test]
+    fn reads_echo_reply_ids() {
+        assert_eq!(echo_reply_ids(&[0, 0, 0x12, 0x34, 0x12, 0x34, 0x00, 0x07]), Some((0x1234, 7)));
+        assert_eq!(echo_reply_ids(&[0, 0, 0, 0, 0x12, 0x34, 0x00, 0x07, 1, 2, 3]), Some((0x1234, 7)));
+
+        // Long enough to be read as an ICMP packet, but not as an echo reply
+        let short = [0, 0, 0xff, 0xff, 0x12];
+        assert!(IcmpPacket::new(&short).is_some());
+        assert_eq!(echo_reply_ids(&short), None);
+        assert_eq!(echo_reply_ids(&[]), None);
+    }
+
+    // An ICMP error quoting an echo request to 192.0.2.1 with identifier 0x1234 and sequence 7.
+    // header_words is the IHL of the quoted IPv4 header, the options are zeroed.
+    fn icmp_error(icmp_type : u8, code : u8, header_words : u8) -> Vec<u8> {
+        let mut packet = vec![icmp_type, code, 0, 0, 0, 0, 0, 0];
+        let mut header = vec![0; header_words as usize * 4];
+        header[0] = 0x40 | header_words;
+        header[9] = 1;
+        header[12..16].copy_from_slice(&[192, 0, 2, 100]);
+        header[16..20].copy_from_slice(&[192, 0, 2, 1]);
+        packet.extend(header);
+        packet.extend(&[IcmpTypes::EchoRequest.0, 0, 0, 0, 0x12, 0x34, 0x00, 0x07]);
+        packet
+    }
+
+    #[test]
+    fn reads_quoted_echo_requests() {
+        let destination = IpAddr::from([192, 0, 2, 1]);
+        let packet = icmp_error(11, 0, 5);
+        assert_eq!(quoted_echo(&packet[8..]), Some((destination, 0x1234, 7)));
+        // Options in the quoted header
+        assert_eq!(quoted_echo(&icmp_error(11, 0, 6)[8..]), Some((destination, 0x1234, 7)));
+
+        // Routers may quote more than the 8 bytes needed
+        let mut long = packet.clone();
+        long.extend(&[0; 32]);
+        assert_eq!(quoted_echo(&long[8..]), Some((destination, 0x1234, 7)));
+
+        // Cut off anywhere before the end of the quoted echo request
+        for length in 8..packet.len() {
+            assert_eq!(quoted_echo(&packet[8..length]), None, "{} bytes", length);
+        }
+
+        // Not quoting an echo request
+        let mut reply = packet.clone();
+        reply[28] = IcmpTypes::EchoReply.0;
+        assert_eq!(quoted_echo(&reply[8..]), None);
+
+        // Header length below the minimum
+        let mut invalid = packet.clone();
+        invalid[8] = 0x44;
+        assert_eq!(quoted_echo(&invalid[8..]), None);
+
+        for garbage in &[vec![0xff; 64], vec![0x4f; 3], vec![0; 28]] {
+            assert_eq!(quoted_echo(garbage), None);
+        }
+    }
+
+    #[test]
+    fn maps_icmp_errors_to_results() {
+        let destination = IpAddr::from([192, 0, 2, 1]);
+        let router = IpAddr::from([198, 51, 100, 1]);
+        let sent = Timestamp::now();
+        let result = |bytes : &[u8]| {
+            let packet = IcmpPacket::new(bytes).unwrap();
+            quoted_request(&packet).map(|quoted| error_result(&packet, quoted, router, sent))
+        };
+
+        match result(&icmp_error(3, 1, 5)) {
+            Some(PingResult::DestinationUnreachable {addr, from, code, identifier, sequence, ..}) => {
+                assert_eq!((addr, from, code, identifier, sequence), (destination, router, 1, 0x1234, 7));
+            },
+            _ => panic!("expected destination unreachable")
+        }
+
+        match result(&icmp_error(11, 0, 5)) {
+            Some(PingResult::TimeExceeded {addr, from, identifier, sequence, ..}) => {
+                assert_eq!((addr, from, identifier, sequence), (destination, router, 0x1234, 7));
+            },
+            _ => panic!("expected time exceeded")
+        }
+
+        match result(&icmp_error(12, 2, 5)) {
+            Some(ref error @ PingResult::IcmpError {icmp_type: 12, code: 2, ..}) => assert!(error.is_error()),
+            _ => panic!("expected parameter problem")
+        }
+
+        // Too short to quote anything
+        assert!(result(&[3, 1, 0, 0]).is_none());
+        assert!(result(&[3, 1, 0, 0, 0, 0, 0, 0, 0x45]).is_none());
+        assert!(result(&icmp_error(3, 1, 5)[..30]).is_none());
+    }
+}
@@ -3,23 +3,130 @@ extern crate time;
 
 use std::time::{Duration as DurationStd, Instant};
 use std::clone::Clone;
-use chrono::prelude::{Local, DateTime, TimeZone};
+use chrono::prelude::{Local, Utc, TimeZone};
+use chrono::SecondsFormat;
 use time::Duration;
-use std::sync::{Arc, Mutex};
-use std::fs::File as fsFile;
-
-pub type File = Arc<Mutex<Option<fsFile>>>;
+use std::net::IpAddr;
 
+// A point in time, read from both the wall clock and the monotonic clock.
+// The wall clock is what gets stored, the monotonic clock is what durations are measured with,
+// so NTP adjustments and DST changes can't skew them.
 #[derive(Clone, Copy)]
+pub struct Timestamp {
+    // Milliseconds since the unix epoch, UTC
+    pub epoch_millis : i64,
+    pub instant : Instant,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self {epoch_millis: Utc::now().timestamp_millis(), instant: Instant::now()}
+    }
+
+    // Monotonic time elapsed between self and a later timestamp
+    pub fn until(&self, later : &Timestamp) -> DurationStd {
+        if later.instant > self.instant {
+            later.instant.duration_since(self.instant)
+        } else {
+            DurationStd::from_secs(0)
+        }
+    }
+
+    pub fn text(&self) -> String {
+        timestamp_text(self.epoch_millis)
+    }
+}
+
+// Local time with millisecond precision and UTC offset, e.g. "2019-03-02T21:14:03.250+01:00"
+pub fn timestamp_text(epoch_millis : i64) -> String {
+    Local.timestamp_millis(epoch_millis).to_rfc3339_opts(SecondsFormat::Millis, false)
+}
+
+pub fn duration_millis(duration : DurationStd) -> i64 {
+    (duration.as_secs() * 1000 + duration.subsec_millis() as u64) as i64
+}
+
+// Milliseconds with fractions, for round-trip times
+pub fn fractional_millis(duration : DurationStd) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+// The outage began somewhere between start_earliest and start, and ended somewhere between end_earliest and end.
+// start is the send time of the first probe that went unanswered, start_earliest the send time of the last probe
+// answered before it. end is the send time of the first probe answered again, end_earliest the send time of the
+// last probe that went unanswered.
+#[derive(Clone)]
 pub struct ConnectivityDown {
-    start : Option<i64>,
-    end : Option<i64>,
+    start : Option<Timestamp>,
+    start_earliest : Option<Timestamp>,
+    end : Option<Timestamp>,
+    end_earliest : Option<Timestamp>,
     is_started : bool,
+    location : Option<FaultLocation>,
+    // Traceroutes towards the failing targets, taken when the outage started
+    traces : Vec<Trace>,
+    // icc was stopped while the outage was still going on, so end is when it stopped rather than when the outage ended
+    interrupted : bool,
+    // Group that went down, and its targets that had reached the timeout limit
+    group : Option<String>,
+    targets : Vec<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Hop {
+    pub ttl : u8,
+    // None if nothing answered at this hop
+    pub address : Option<IpAddr>,
+    // Milliseconds
+    pub rtt : Option<i64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Trace {
+    pub target : String,
+    pub destination : IpAddr,
+    pub hops : Vec<Hop>,
+}
+
+impl Trace {
+    // e.g. "8.8.8.8 (8.8.8.8): 1 192.168.1.1 1 ms, 2 *, 3 *"
+    pub fn text(&self) -> String {
+        let hops : Vec<String> = self.hops.iter().map(|hop| match (hop.address, hop.rtt) {
+            (Some(address), Some(rtt)) => format!("{} {} {} ms", hop.ttl, address, rtt),
+            (Some(address), None) => format!("{} {}", hop.ttl, address),
+            _ => format!("{} *", hop.ttl)
+        }).collect();
+        format!("{} ({}): {}", self.target, self.destination, hops.join(", "))
+    }
+}
+
+// Where an outage is, going by the furthest layer that was still reachable when it started
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FaultLocation {
+    // The default gateway, our own router, didn't answer
+    Gateway,
+    // The gateway answered, the first ISP hop didn't
+    Isp,
+    // The first ISP hop answered, so the outage is further out
+    Internet,
+    // The network path isn't known, or couldn't be probed
+    Unknown,
+}
+
+impl FaultLocation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FaultLocation::Gateway => "gateway",
+            FaultLocation::Isp => "isp",
+            FaultLocation::Internet => "internet",
+            FaultLocation::Unknown => "unknown",
+        }
+    }
 }
 
 impl ConnectivityDown {
     pub fn new() -> Self {
-        Self {start: None, end: None, is_started: false}
+        Self {start: None, start_earliest: None, end: None, end_earliest: None, is_started: false, location: None, traces: Vec::new(), interrupted: false, group: None, targets: Vec::new()}
     }
 
     pub fn is_ready(&self) -> bool {
@@ -39,54 +146,215 @@ impl ConnectivityDown {
     }
 
     pub fn start(&mut self) {
+        self.start_at(Timestamp::now());
+    }
+
+    pub fn start_at(&mut self, timestamp : Timestamp) {
+        self.start_between(None, timestamp);
+    }
+
+    pub fn start_between(&mut self, earliest : Option<Timestamp>, latest : Timestamp) {
         if self.start.is_none() {
-            self.start = Some(Local::now().timestamp());
+            self.start = Some(latest);
+            self.start_earliest = earliest;
             self.is_started = true;
         }
     }
 
     pub fn end(&mut self) {
+        self.end_at(Timestamp::now());
+    }
+
+    pub fn end_at(&mut self, timestamp : Timestamp) {
+        self.end_between(None, timestamp);
+    }
+
+    pub fn end_between(&mut self, earliest : Option<Timestamp>, latest : Timestamp) {
         if self.end.is_none() {
-            self.end = Some(Local::now().timestamp());
+            self.end = Some(latest);
+            self.end_earliest = earliest;
         }
     }
 
+    // Ends the outage because icc is stopping
+    pub fn interrupt_at(&mut self, timestamp : Timestamp) {
+        self.end_at(timestamp);
+        self.interrupted = true;
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted
+    }
+
+    pub fn set_targets(&mut self, group : &str, targets : Vec<String>) {
+        self.group = Some(group.to_owned());
+        self.targets = targets;
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_ref().map(|group| group.as_str())
+    }
+
+    pub fn targets(&self) -> &[String] {
+        &self.targets
+    }
+
+    pub fn set_location(&mut self, location : FaultLocation) {
+        self.location = Some(location);
+    }
+
+    pub fn location(&self) -> Option<FaultLocation> {
+        self.location
+    }
+
+    pub fn location_text(&self) -> &'static str {
+        self.location.unwrap_or(FaultLocation::Unknown).as_str()
+    }
+
+    pub fn set_traces(&mut self, traces : Vec<Trace>) {
+        self.traces = traces;
+    }
+
+    pub fn traces(&self) -> &[Trace] {
+        &self.traces
+    }
+
+    // How much earlier than start the outage may have begun. None if there is no earlier probe to go by.
+    pub fn start_uncertainty(&self) -> Option<DurationStd> {
+        self.start_earliest.map(|earliest| earliest.until(&self.start.unwrap()))
+    }
+
+    // How much earlier than end the outage may have ended. None if there is no earlier probe to go by.
+    pub fn end_uncertainty(&self) -> Option<DurationStd> {
+        self.end_earliest.map(|earliest| earliest.until(&self.end.unwrap()))
+    }
+
+    // Measured on the monotonic clock, not the difference between the stored wall clock timestamps
     pub fn duration(&self) -> Duration {
-        let start = Local.timestamp(self.start.unwrap(), 0);
-        let end = Local.timestamp(self.end.unwrap(), 0);
-        end.signed_duration_since(start)
+        Duration::from_std(self.duration_std()).unwrap()
+    }
+
+    pub fn duration_std(&self) -> DurationStd {
+        self.start.unwrap().until(&self.end.unwrap())
+    }
+
+    pub fn duration_millis(&self) -> i64 {
+        duration_millis(self.duration_std())
     }
 
     pub fn start_end_text(&self) -> String {
-        let start = Local.timestamp(self.start.unwrap(), 0);
-        let end = Local.timestamp(self.end.unwrap(), 0);
-        format!("{} - {}", start.to_rfc2822(), end.to_rfc2822())
+        format!("{} - {}", self.start_text(), self.end_text())
     }
 
     pub fn start_text(&self) -> String {
-        let start = Local.timestamp(self.start.unwrap(), 0);
-        start.to_rfc2822()
+        self.start.unwrap().text()
     }
 
-    pub fn start_epoch_timestamp(&self) -> i64 {
-        self.start.unwrap()
+    pub fn start_epoch_millis(&self) -> i64 {
+        self.start.unwrap().epoch_millis
     }
 
     pub fn end_text(&self) -> String {
-        let end = Local.timestamp(self.end.unwrap(), 0);
-        end.to_rfc2822()
+        self.end.unwrap().text()
     }
 
-    pub fn end_epoch_timestamp(&self) -> i64 {
-        self.end.unwrap()
+    pub fn end_epoch_millis(&self) -> i64 {
+        self.end.unwrap().epoch_millis
     }
 
     pub fn duration_text(&self) -> String {
-        let v = self.duration();
-        let hours = v.num_hours();
-        let minutes = v.num_minutes() - (v.num_hours() * 60);
-        let seconds = v.num_seconds() - ((v.num_hours() * 60 * 60) + (minutes * 60));
-        format!("{} hours, {} minutes, {} seconds", hours, minutes, seconds).to_owned()
+        self.duration().as_text()
+    }
+
+    pub fn uncertainty_text(&self) -> String {
+        let text = |uncertainty : Option<DurationStd>| match uncertainty {
+            Some(uncertainty) => format!("up to {} ms earlier", duration_millis(uncertainty)),
+            None => "unknown".to_owned()
+        };
+        format!("start {}, end {}", text(self.start_uncertainty()), text(self.end_uncertainty()))
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectivityDegraded {
+    target : String,
+    start : Option<Timestamp>,
+    end : Option<Timestamp>,
+    // Worst packet loss seen while degraded, in percent
+    loss_percent : f64,
+    // Worst average round-trip time seen while degraded
+    rtt : Option<DurationStd>,
+}
+
+impl ConnectivityDegraded {
+    pub fn new(target : &str) -> Self {
+        Self {target: target.to_owned(), start: None, end: None, loss_percent: 0.0, rtt: None}
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.start.is_some() && self.end.is_some()
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.start.is_some()
+    }
+
+    pub fn start_at(&mut self, timestamp : Timestamp) {
+        if self.start.is_none() {
+            self.start = Some(timestamp);
+        }
+    }
+
+    pub fn end_at(&mut self, timestamp : Timestamp) {
+        if self.end.is_none() {
+            self.end = Some(timestamp);
+        }
+    }
+
+    // Records a sample of the link quality, keeping the worst values seen
+    pub fn update(&mut self, loss_percent : f64, rtt : Option<DurationStd>) {
+        if loss_percent > self.loss_percent {
+            self.loss_percent = loss_percent;
+        }
+
+        if let Some(rtt) = rtt {
+            if self.rtt.map_or(true, |worst| rtt > worst) {
+                self.rtt = Some(rtt);
+            }
+        }
+    }
+
+    pub fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        self.loss_percent
+    }
+
+    pub fn rtt(&self) -> Option<DurationStd> {
+        self.rtt
+    }
+
+    pub fn start_epoch_millis(&self) -> i64 {
+        self.start.unwrap().epoch_millis
+    }
+
+    pub fn end_epoch_millis(&self) -> i64 {
+        self.end.unwrap().epoch_millis
+    }
+
+    // Measured on the monotonic clock
+    pub fn duration(&self) -> Duration {
+        Duration::from_std(self.start.unwrap().until(&self.end.unwrap())).unwrap()
+    }
+
+    pub fn duration_millis(&self) -> i64 {
+        duration_millis(self.start.unwrap().until(&self.end.unwrap()))
+    }
+
+    pub fn start_end_text(&self) -> String {
+        format!("{} - {}", self.start.unwrap().text(), self.end.unwrap().text())
     }
 }
 
@@ -95,7 +363,8 @@ impl DurationFormat for Duration {
         let hours = self.num_hours();
         let minutes = self.num_minutes() - (self.num_hours() * 60);
         let seconds = self.num_seconds() - ((self.num_hours() * 60 * 60) + (minutes * 60));
-        format!("{} hours, {} minutes, {} seconds", hours, minutes, seconds).to_owned()
+        let millis = self.num_milliseconds() - (self.num_seconds() * 1000);
+        format!("{} hours, {} minutes, {}.{:03} seconds", hours, minutes, seconds, millis).to_owned()
     }
 }
 
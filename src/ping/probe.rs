@@ -0,0 +1,231 @@
+use super::deps::*;
+use super::{PingResult, PingUtility, ProbeOptions};
+use super::model::{duration_millis, fractional_millis};
+use crate::error::Error;
+
+// Answer to one probe. Round-trip times are milliseconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProbeReply {
+    // Counted from 1 per target
+    pub sequence : u32,
+    pub address : Option<IpAddr>,
+    pub rtt : Option<f64>,
+    // Why there was no reply, e.g. "timeout" or "destination unreachable (code 1) from 192.168.1.1"
+    pub error : Option<String>,
+}
+
+// Probes sent to a target, summarized like the statistics ping prints
+#[derive(Clone, Debug, Serialize)]
+pub struct ProbeSummary {
+    pub target : String,
+    pub address : Option<IpAddr>,
+    pub transmitted : u32,
+    pub received : u32,
+    pub loss_percent : f64,
+    // Minimum, average, maximum and standard deviation of the round-trip times, None without replies
+    pub rtt_min : Option<f64>,
+    pub rtt_avg : Option<f64>,
+    pub rtt_max : Option<f64>,
+    pub rtt_mdev : Option<f64>,
+    pub replies : Vec<ProbeReply>,
+}
+
+impl ProbeSummary {
+    fn new(target : &str, replies : Vec<ProbeReply>) -> Self {
+        let rtts : Vec<f64> = replies.iter().filter_map(|reply| reply.rtt).collect();
+        let transmitted = replies.len() as u32;
+        let received = rtts.len() as u32;
+
+        let (rtt_min, rtt_avg, rtt_max, rtt_mdev) = if rtts.is_empty() {
+            (None, None, None, None)
+        } else {
+            let avg = rtts.iter().sum::<f64>() / rtts.len() as f64;
+            let square_avg = rtts.iter().map(|rtt| rtt * rtt).sum::<f64>() / rtts.len() as f64;
+            (
+                rtts.iter().cloned().fold(None, |min : Option<f64>, rtt| Some(min.map_or(rtt, |min| min.min(rtt)))),
+                Some(avg),
+                rtts.iter().cloned().fold(None, |max : Option<f64>, rtt| Some(max.map_or(rtt, |max| max.max(rtt)))),
+                Some((square_avg - avg * avg).max(0.0).sqrt()),
+            )
+        };
+
+        Self {
+            target: target.to_owned(),
+            address: replies.iter().rev().filter_map(|reply| reply.address).next(),
+            transmitted: transmitted,
+            received: received,
+            loss_percent: if transmitted > 0 { (transmitted - received) as f64 * 100.0 / transmitted as f64 } else { 0.0 },
+            rtt_min: rtt_min,
+            rtt_avg: rtt_avg,
+            rtt_max: rtt_max,
+            rtt_mdev: rtt_mdev,
+            replies: replies,
+        }
+    }
+
+    // Answered at least one probe
+    pub fn is_reachable(&self) -> bool {
+        self.received > 0
+    }
+}
+
+// Pings every target count times, a round every timeout, and calls on_reply for each answer as it comes in.
+// Summaries are in the order the targets were given.
+pub fn probe<F : FnMut(&str, &ProbeReply)>(targets : &[String], count : u32, timeout : Duration, mut on_reply : F) -> Result<Vec<ProbeSummary>, Error> {
+    let (p_utility, results) = PingUtility::new(Some(duration_millis(timeout) as u64))?;
+    for target in targets {
+        p_utility.add_target(target, target, ProbeOptions::default());
+    }
+    p_utility.start_pinging()?;
+
+    let mut replies : HashMap<&str, Vec<ProbeReply>> = targets.iter().map(|target| (target.as_str(), Vec::new())).collect();
+    // Rounds last the timeout, with some time for the results of the last one
+    let deadline = Instant::now() + timeout * (count + 1) + Duration::from_secs(1);
+
+    while replies.values().any(|replies| (replies.len() as u32) < count) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+
+        let result = match results.recv_timeout(deadline - now) {
+            Ok(result) => result,
+            Err(_) => break
+        };
+        if let Some((target, reply)) = add_reply(&mut replies, count, result) {
+            on_reply(&target, &reply);
+        }
+    }
+    p_utility.stop_pinging();
+
+    Ok(summarize(targets, count, replies))
+}
+
+// Adds what a result says about a probe to the replies of its target, up to count of them.
+// Returns the reply as added, numbered, or None if the result isn't counted.
+fn add_reply(replies : &mut HashMap<&str, Vec<ProbeReply>>, count : u32, result : PingResult) -> Option<(String, ProbeReply)> {
+    let (target, reply) = probe_reply(result)?;
+    let target_replies = replies.get_mut(target.as_str())?;
+    if target_replies.len() as u32 >= count {
+        return None;
+    }
+
+    let reply = ProbeReply {sequence: target_replies.len() as u32 + 1, ..reply};
+    target_replies.push(reply.clone());
+    Some((target, reply))
+}
+
+// Summaries in the order the targets were given. Probes that got no result at all before the deadline count as timed out.
+fn summarize(targets : &[String], count : u32, mut replies : HashMap<&str, Vec<ProbeReply>>) -> Vec<ProbeSummary> {
+    targets.iter().map(|target| {
+        let mut target_replies = replies.remove(target.as_str()).unwrap_or_default();
+        while (target_replies.len() as u32) < count {
+            target_replies.push(ProbeReply {sequence: target_replies.len() as u32 + 1, address: None, rtt: None, error: Some("timeout".to_owned())});
+        }
+        ProbeSummary::new(target, target_replies)
+    }).collect()
+}
+
+// The target a result is for, and what it says about the probe
+fn probe_reply(result : PingResult) -> Option<(String, ProbeReply)> {
+    let reply = |address : Option<IpAddr>, rtt : Option<f64>, error : Option<String>| ProbeReply {sequence: 0, address: address, rtt: rtt, error: error};
+    match result {
+        PingResult::Response{target, addr, rtt, ..} => Some((target, reply(Some(addr), Some(fractional_millis(rtt)), None))),
+        PingResult::Timeout{target, addr: None, ..} => Some((target, reply(None, None, Some("unable to resolve".to_owned())))),
+        PingResult::Timeout{target, addr, ..} => Some((target, reply(addr, None, Some("timeout".to_owned())))),
+        PingResult::DestinationUnreachable{target, addr, from, code, ..} => Some((target, reply(Some(addr), None, Some(format!("destination unreachable (code {}) from {}", code, from))))),
+        PingResult::TimeExceeded{target, addr, from, ..} => Some((target, reply(Some(addr), None, Some(format!("time exceeded at {}", from))))),
+        PingResult::IcmpError{target, addr, from, icmp_type, code, ..} => Some((target, reply(Some(addr), None, Some(format!("ICMP error type {} code {} from {}", icmp_type, code, from))))),
+        PingResult::Request{..} => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::model::Timestamp;
+
+    fn reply(rtt : Option<f64>) -> ProbeReply {
+        ProbeReply {sequence: 0, address: rtt.map(|_| IpAddr::from([192, 0, 2, 1])), rtt: rtt, error: None}
+    }
+
+    fn response(target : &str, millis : u64) -> PingResult {
+        PingResult::Response {target: target.to_owned(), addr: IpAddr::from([192, 0, 2, 1]), rtt: Duration::from_millis(millis), sequence: 0, identifier: 0, sent: Timestamp::now()}
+    }
+
+    fn timeout(target : &str) -> PingResult {
+        PingResult::Timeout {target: target.to_owned(), addr: Some(IpAddr::from([192, 0, 2, 1])), sent: Timestamp::now()}
+    }
+
+    #[test]
+    fn summarizes_round_trip_times() {
+        let summary = ProbeSummary::new("a", vec![reply(Some(10.0)), reply(None), reply(Some(30.0)), reply(Some(20.0))]);
+        assert_eq!((summary.transmitted, summary.received), (4, 3));
+        assert_eq!(summary.loss_percent, 25.0);
+        assert_eq!(summary.rtt_min, Some(10.0));
+        assert_eq!(summary.rtt_avg, Some(20.0));
+        assert_eq!(summary.rtt_max, Some(30.0));
+        assert!((summary.rtt_mdev.unwrap() - (200.0f64 / 3.0).sqrt()).abs() < 1e-9);
+        assert_eq!(summary.address, Some(IpAddr::from([192, 0, 2, 1])));
+        assert!(summary.is_reachable());
+
+        // A single reply doesn't deviate
+        let summary = ProbeSummary::new("a", vec![reply(Some(12.5))]);
+        assert_eq!(summary.rtt_mdev, Some(0.0));
+    }
+
+    #[test]
+    fn summarizes_without_replies() {
+        let summary = ProbeSummary::new("a", vec![reply(None), reply(None)]);
+        assert_eq!((summary.transmitted, summary.received), (2, 0));
+        assert_eq!(summary.loss_percent, 100.0);
+        assert_eq!((summary.rtt_min, summary.rtt_avg, summary.rtt_max, summary.rtt_mdev), (None, None, None, None));
+        assert_eq!(summary.address, None);
+        assert!(!summary.is_reachable());
+
+        // Nothing sent at all
+        let summary = ProbeSummary::new("a", Vec::new());
+        assert_eq!(summary.loss_percent, 0.0);
+        assert!(summary.rtt_avg.is_none());
+        assert!(!summary.is_reachable());
+    }
+
+    #[test]
+    fn collects_replies_per_target() {
+        let targets = vec!["b".to_owned(), "a".to_owned()];
+        let mut replies : HashMap<&str, Vec<ProbeReply>> = targets.iter().map(|target| (target.as_str(), Vec::new())).collect();
+
+        let (target, first) = add_reply(&mut replies, 2, response("a", 15)).unwrap();
+        assert_eq!((target.as_str(), first.sequence, first.rtt), ("a", 1, Some(15.0)));
+        let (_, second) = add_reply(&mut replies, 2, timeout("a")).unwrap();
+        assert_eq!((second.sequence, second.error.as_ref().map(|e| e.as_str())), (2, Some("timeout")));
+
+        // Past the count, for another target, or not about a probe
+        assert!(add_reply(&mut replies, 2, response("a", 15)).is_none());
+        assert!(add_reply(&mut replies, 2, response("c", 15)).is_none());
+        assert!(add_reply(&mut replies, 2, PingResult::Request {addr: IpAddr::from([192, 0, 2, 1]), sequence: 0, identifier: 0}).is_none());
+
+        // b never answered, its probes count as timed out
+        let summaries = summarize(&targets, 2, replies);
+        assert_eq!(summaries.iter().map(|summary| summary.target.as_str()).collect::<Vec<_>>(), vec!["b", "a"]);
+        assert_eq!(summaries[0].replies.iter().map(|reply| reply.sequence).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(summaries[0].loss_percent, 100.0);
+        assert!(!summaries[0].is_reachable());
+        assert_eq!(summaries[1].loss_percent, 50.0);
+        assert!(summaries[1].is_reachable());
+    }
+
+    #[test]
+    fn describes_failed_probes() {
+        let from = IpAddr::from([198, 51, 100, 1]);
+        let addr = IpAddr::from([192, 0, 2, 1]);
+        let error = |result : PingResult| probe_reply(result).and_then(|(_, reply)| reply.error);
+
+        assert_eq!(error(PingResult::Timeout {target: "a".to_owned(), addr: None, sent: Timestamp::now()}), Some("unable to resolve".to_owned()));
+        assert_eq!(error(PingResult::DestinationUnreachable {target: "a".to_owned(), addr: addr, from: from, code: 1, sequence: 0, identifier: 0, sent: Timestamp::now()}),
+            Some("destination unreachable (code 1) from 198.51.100.1".to_owned()));
+        assert_eq!(error(PingResult::TimeExceeded {target: "a".to_owned(), addr: addr, from: from, sequence: 0, identifier: 0, sent: Timestamp::now()}),
+            Some("time exceeded at 198.51.100.1".to_owned()));
+        assert_eq!(error(response("a", 1)), None);
+    }
+}
@@ -0,0 +1,121 @@
+use super::deps::*;
+use std::sync::Condvar;
+
+// Cancels the threads started by one start_pinging. Sleeping threads wait on the condvar,
+// threads blocked on a socket poll it together with a pipe that becomes readable when stopped.
+#[derive(Clone)]
+pub struct StopSignal {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    #[cfg(unix)]
+    wake: Arc<WakePipe>,
+}
+
+impl StopSignal {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            stopped: Arc::new((Mutex::new(false), Condvar::new())),
+            #[cfg(unix)]
+            wake: Arc::new(WakePipe::new()?),
+        })
+    }
+
+    pub fn stop(&self) {
+        let (stopped, condvar) = &*self.stopped;
+        *stopped.lock().unwrap() = true;
+        condvar.notify_all();
+
+        #[cfg(unix)]
+        self.wake.wake();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.stopped.0.lock().unwrap()
+    }
+
+    // Sleeps for duration, or until stopped. Returns whether it was stopped.
+    pub fn wait_timeout(&self, duration : Duration) -> bool {
+        let (stopped, condvar) = &*self.stopped;
+        let deadline = Instant::now() + duration;
+        let mut is_stopped = stopped.lock().unwrap();
+        while !*is_stopped {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            is_stopped = condvar.wait_timeout(is_stopped, deadline - now).unwrap().0;
+        }
+        *is_stopped
+    }
+
+    // Blocks until fd is readable, without using any CPU. Returns false once stopped.
+    #[cfg(unix)]
+    pub fn wait_readable(&self, fd : libc::c_int) -> bool {
+        let mut fds = [
+            libc::pollfd {fd: fd, events: libc::POLLIN, revents: 0},
+            libc::pollfd {fd: self.wake.read, events: libc::POLLIN, revents: 0},
+        ];
+
+        loop {
+            if self.is_stopped() {
+                return false;
+            }
+
+            let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if ready < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Error polling socket: {}", e);
+                return false;
+            }
+
+            if fds[1].revents != 0 || self.is_stopped() {
+                return false;
+            }
+
+            if fds[0].revents != 0 {
+                return true;
+            }
+        }
+    }
+
+    // Sockets can't be polled alongside a pipe on Windows, the listener only sees the stop after the next packet
+    #[cfg(not(unix))]
+    pub fn wait_readable<T>(&self, _fd : T) -> bool {
+        !self.is_stopped()
+    }
+}
+
+// A pipe that is written to once, to wake up every thread polling its read end
+#[cfg(unix)]
+struct WakePipe {
+    read: libc::c_int,
+    write: libc::c_int,
+}
+
+#[cfg(unix)]
+impl WakePipe {
+    fn new() -> io::Result<Self> {
+        let mut fds : [libc::c_int; 2] = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {read: fds[0], write: fds[1]})
+    }
+
+    fn wake(&self) {
+        let byte : [u8; 1] = [1];
+        unsafe { libc::write(self.write, byte.as_ptr() as *const libc::c_void, 1) };
+    }
+}
+
+#[cfg(unix)]
+impl Drop for WakePipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use super::deps::*;
+
+// How long expired requests are remembered, so replies arriving after their timeout are recognised as late
+const LATE_WINDOW : Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct RequestKey {
+    pub addr: IpAddr,
+    pub identifier: u16,
+    pub sequence: u16,
+}
+
+// What a reply was matched to
+#[derive(PartialEq, Debug)]
+pub enum Tracked {
+    // A request still waiting for its reply
+    Pending,
+    // A request that had already timed out
+    Late,
+    // Not a request sent by icc, or one forgotten since
+    Unknown,
+}
+
+// Only IPv4 requests are tracked, IPv6 requests carry no identifier or sequence number to match replies by
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct TrackingStats {
+    // Requests waiting for a reply
+    pub pending: usize,
+    // Timed out requests remembered to recognise late replies
+    pub expired: usize,
+    // Replies that arrived after their request timed out, since start
+    pub late_replies: u64,
+}
+
+// Echo requests sent by icc that haven't been answered yet.
+// While on Windows pnet only receives the pings it sends itself, that is not the case on Linux/OSX,
+// so replies are only counted when they match a request in here.
+pub struct RequestTracker {
+    // By key, when the round the request was sent in is over
+    pending: HashMap<RequestKey, Instant>,
+    // By key, when the request timed out
+    expired: HashMap<RequestKey, Instant>,
+    late_replies: u64,
+}
+
+impl RequestTracker {
+    pub fn new() -> Self {
+        Self {pending: HashMap::new(), expired: HashMap::new(), late_replies: 0}
+    }
+
+    pub fn insert(&mut self, key : RequestKey, deadline : Instant) {
+        self.pending.insert(key, deadline);
+    }
+
+    // Matches a reply received at now to its request, forgetting the request.
+    // Replies after the deadline are late even if the request hasn't been expired yet, their round already counted them as timed out.
+    pub fn take(&mut self, key : &RequestKey, now : Instant) -> Tracked {
+        if let Some(deadline) = self.pending.remove(key) {
+            if now <= deadline {
+                return Tracked::Pending;
+            }
+            self.late_replies += 1;
+            return Tracked::Late;
+        }
+
+        if self.expired.remove(key).is_some() {
+            self.late_replies += 1;
+            return Tracked::Late;
+        }
+
+        Tracked::Unknown
+    }
+
+    // Moves requests past their deadline to expired, and forgets expired requests past the late window
+    pub fn expire(&mut self, now : Instant) {
+        let expired : Vec<RequestKey> = self.pending.iter()
+            .filter(|(_, deadline)| now >= **deadline)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in expired {
+            self.pending.remove(&key);
+            self.expired.insert(key, now);
+        }
+
+        self.expired.retain(|_, expired_at| now.duration_since(*expired_at) < LATE_WINDOW);
+    }
+
+    pub fn stats(&self) -> TrackingStats {
+        TrackingStats {
+            pending: self.pending.len(),
+            expired: self.expired.len(),
+            late_replies: self.late_replies,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(sequence : u16) -> RequestKey {
+        RequestKey {addr: "192.0.2.1".parse().unwrap(), identifier: 7, sequence: sequence}
+    }
+
+    #[test]
+    fn answers_pending_requests_once() {
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(500);
+        let mut tracker = RequestTracker::new();
+        tracker.insert(key(1), deadline);
+
+        assert_eq!(tracker.take(&key(1), start + Duration::from_millis(20)), Tracked::Pending);
+        assert_eq!(tracker.take(&key(1), start + Duration::from_millis(30)), Tracked::Unknown);
+        assert_eq!(tracker.stats().pending, 0);
+        assert_eq!(tracker.stats().late_replies, 0);
+    }
+
+    #[test]
+    fn ignores_unknown_replies() {
+        let start = Instant::now();
+        let mut tracker = RequestTracker::new();
+        tracker.insert(key(1), start + Duration::from_millis(500));
+
+        assert_eq!(tracker.take(&key(2), start), Tracked::Unknown);
+        assert_eq!(tracker.take(&RequestKey {identifier: 8, ..key(1)}, start), Tracked::Unknown);
+        assert_eq!(tracker.stats().pending, 1);
+        assert_eq!(tracker.stats().late_replies, 0);
+    }
+
+    #[test]
+    fn expires_requests_then_forgets_them() {
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(500);
+        let mut tracker = RequestTracker::new();
+        tracker.insert(key(1), deadline);
+        tracker.insert(key(2), deadline);
+        tracker.insert(key(3), deadline + LATE_WINDOW * 2);
+
+        tracker.expire(deadline);
+        let stats = tracker.stats();
+        assert_eq!((stats.pending, stats.expired), (1, 2));
+
+        assert_eq!(tracker.take(&key(1), deadline + Duration::from_secs(2)), Tracked::Late);
+        assert_eq!(tracker.stats().late_replies, 1);
+
+        // Still recognised just before the late window is over, forgotten after
+        tracker.expire(deadline + LATE_WINDOW - Duration::from_millis(1));
+        assert_eq!(tracker.stats().expired, 1);
+        tracker.expire(deadline + LATE_WINDOW);
+        assert_eq!(tracker.stats().expired, 0);
+        assert_eq!(tracker.take(&key(2), deadline + LATE_WINDOW), Tracked::Unknown);
+        assert_eq!(tracker.stats().late_replies, 1);
+    }
+
+    #[test]
+    fn replies_past_the_deadline_are_late_before_expiring() {
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(500);
+        let mut tracker = RequestTracker::new();
+        tracker.insert(key(1), deadline);
+
+        // The next round has started, but the request wasn't expired yet
+        assert_eq!(tracker.take(&key(1), deadline + Duration::from_millis(1)), Tracked::Late);
+        assert_eq!(tracker.stats().late_replies, 1);
+        assert_eq!(tracker.stats().pending, 0);
+    }
+}
@@ -0,0 +1,450 @@
+use std::str::FromStr;
+use chrono::prelude::{DateTime, Datelike, Local, NaiveDate, TimeZone, Utc};
+use chrono::{Duration, FixedOffset};
+use crate::error::Error;
+use crate::util::db::Db;
+
+// Calendar periods availability is reported for
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+}
+
+impl Period {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Week => "week",
+            Period::Month => "month",
+        }
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Period::Day),
+            "week" => Ok(Period::Week),
+            "month" => Ok(Period::Month),
+            _ => Err(format!("unknown period '{}', expected day, week or month", s))
+        }
+    }
+}
+
+// Most periods a report covers, a year of days
+pub const MAX_PERIODS : u32 = 366;
+
+// Number of periods to report, from 1 to MAX_PERIODS
+pub fn parse_count(s : &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(count) if count >= 1 && count <= MAX_PERIODS => Ok(count),
+        _ => Err(format!("invalid count '{}', expected 1 to {}", s, MAX_PERIODS))
+    }
+}
+
+// Timezone the calendar periods begin and end in
+#[derive(Clone, Copy, Debug)]
+pub enum Zone {
+    Local,
+    Utc,
+    Fixed(FixedOffset),
+}
+
+impl FromStr for Zone {
+    type Err = String;
+
+    // "local", "utc", or an offset from UTC like "+02:00", "-0530" or "+2"
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "local" => return Ok(Zone::Local),
+            "utc" | "z" => return Ok(Zone::Utc),
+            _ => {}
+        }
+
+        let invalid = || format!("invalid timezone '{}', expected local, utc or an offset like +02:00", s);
+        let sign = match s.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(invalid())
+        };
+        let digits : String = s[1..].chars().filter(|c| *c != ':').collect();
+        if !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let (hours, minutes) = match digits.len() {
+            1 | 2 => (digits.as_str(), "0"),
+            4 => (&digits[..2], &digits[2..]),
+            _ => return Err(invalid())
+        };
+        let hours : i32 = hours.parse().map_err(|_| invalid())?;
+        let minutes : i32 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+
+        Ok(Zone::Fixed(FixedOffset::east(sign * (hours * 3600 + minutes * 60))))
+    }
+}
+
+impl Zone {
+    // Start and end of the period containing epoch_millis, in milliseconds since the unix epoch
+    pub fn bounds(&self, period : Period, epoch_millis : i64) -> (i64, i64) {
+        let date = self.date(epoch_millis);
+        let (start, end) = match period {
+            Period::Day => (date, date.succ()),
+            Period::Week => {
+                let start = date - Duration::days(date.weekday().num_days_from_monday() as i64);
+                (start, start + Duration::days(7))
+            },
+            Period::Month => {
+                let start = NaiveDate::from_ymd(date.year(), date.month(), 1);
+                let end = if date.month() == 12 {
+                    NaiveDate::from_ymd(date.year() + 1, 1, 1)
+                } else {
+                    NaiveDate::from_ymd(date.year(), date.month() + 1, 1)
+                };
+                (start, end)
+            }
+        };
+        (self.midnight(start), self.midnight(end))
+    }
+
+    // The period containing epoch_millis, e.g. "2019-03-02", "2019-W09" or "2019-03"
+    pub fn label(&self, period : Period, epoch_millis : i64) -> String {
+        let date = self.date(epoch_millis);
+        let format = match period {
+            Period::Day => "%Y-%m-%d",
+            Period::Week => "%G-W%V",
+            Period::Month => "%Y-%m",
+        };
+        date.format(format).to_string()
+    }
+
+    // A time given as RFC 3339, e.g. "2019-03-02T21:14:03+01:00", or a date, e.g. "2019-03-02", which is midnight in this timezone
+    pub fn parse_time(&self, s : &str) -> Result<i64, String> {
+        if let Ok(datetime) = DateTime::parse_from_rfc3339(s) {
+            return Ok(datetime.timestamp_millis());
+        }
+        match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            Ok(date) => Ok(self.midnight(date)),
+            Err(_) => Err(format!("invalid time '{}', expected a date like 2019-03-02 or an RFC 3339 time", s))
+        }
+    }
+
+    fn date(&self, epoch_millis : i64) -> NaiveDate {
+        match self {
+            Zone::Local => Local.timestamp_millis(epoch_millis).naive_local().date(),
+            Zone::Utc => Utc.timestamp_millis(epoch_millis).naive_local().date(),
+            Zone::Fixed(offset) => offset.timestamp_millis(epoch_millis).naive_local().date(),
+        }
+    }
+
+    fn midnight(&self, date : NaiveDate) -> i64 {
+        match self {
+            Zone::Local => midnight(&Local, date),
+            Zone::Utc => midnight(&Utc, date),
+            Zone::Fixed(offset) => midnight(offset, date),
+        }
+    }
+}
+
+// When a day begins. Days that skip midnight for DST begin at the first hour that exists.
+fn midnight<Tz : TimeZone>(tz : &Tz, date : NaiveDate) -> i64 {
+    (0..24)
+        .filter_map(|hour| tz.from_local_datetime(&date.and_hms(hour, 0, 0)).earliest())
+        .map(|datetime| datetime.timestamp_millis())
+        .next()
+        .unwrap_or_else(|| Utc.from_utc_date(&date).and_hms(0, 0, 0).timestamp_millis())
+}
+
+// Availability over one calendar period. Durations are in milliseconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct PeriodReport {
+    pub label : String,
+    pub start : i64,
+    pub end : i64,
+    // Time icc was monitoring during the period, up to now
+    pub monitored : i64,
+    // Time icc wasn't running, or the host was suspended
+    pub unmonitored : i64,
+    pub downtime : i64,
+    // Percentage of the monitored time without downtime, None if nothing was monitored
+    pub availability : Option<f64>,
+    // Outages that began in the period
+    pub outages : usize,
+    pub longest_outage : Option<i64>,
+    // Mean time to repair, the average duration of the outages that began in the period
+    pub mttr : Option<i64>,
+    // Mean time between failures, the time up divided by the number of outages
+    pub mtbf : Option<i64>,
+}
+
+impl PeriodReport {
+    // Whether availability was below an SLA percentage
+    pub fn is_below(&self, sla : f64) -> bool {
+        self.availability.map_or(false, |availability| availability < sla)
+    }
+}
+
+// Reports for the last count periods up to now, oldest first, at most MAX_PERIODS. Time before icc first ran counts as unmonitored.
+pub fn report(db : &Db, period : Period, zone : Zone, count : u32, now : i64) -> Result<Vec<PeriodReport>, Error> {
+    let mut periods : Vec<(i64, i64)> = Vec::new();
+    let mut at = now;
+    for _ in 0..count.min(MAX_PERIODS) {
+        let (start, end) = zone.bounds(period, at);
+        periods.push((start, end));
+        at = start - 1;
+    }
+    periods.reverse();
+
+    let (from, to) = match (periods.first(), periods.last()) {
+        (Some(first), Some(last)) => (first.0, last.1),
+        _ => return Ok(Vec::new())
+    };
+
+    let since = db.monitored_since()?;
+    // Groups that are down at the same time are one outage
+    let downtimes = merge(db.downtimes_between(from, to)?.iter().map(|record| (record.start, record.end, record.duration)).collect());
+    let unmonitored = merge(db.unmonitored_between(from, to)?.iter().map(|record| (record.start, record.end, record.duration)).collect());
+
+    Ok(periods.into_iter().map(|(start, end)| {
+        let window_start = since.map_or(end, |since| since.max(start));
+        let window_end = end.min(now).max(window_start);
+
+        let unmonitored_time : i64 = unmonitored.iter()
+            .map(|&(from, to, _)| overlap(from, to, window_start, window_end))
+            .sum();
+        let monitored = (window_end - window_start - unmonitored_time).max(0);
+        // Downtime while icc wasn't monitoring isn't counted twice
+        let monitored_downtime = |from : i64, to : i64| {
+            let (from, to) = (from.max(window_start), to.min(window_end));
+            overlap(from, to, window_start, window_end)
+                - unmonitored.iter().map(|&(unmonitored_from, unmonitored_to, _)| overlap(from, to, unmonitored_from, unmonitored_to)).sum::<i64>()
+        };
+        let downtime : i64 = downtimes.iter()
+            .map(|&(from, to, _)| monitored_downtime(from, to))
+            .sum();
+
+        let durations : Vec<i64> = downtimes.iter()
+            .filter(|&&(from, to, _)| from >= start && from < end && monitored_downtime(from, to) > 0)
+            .map(|&(_, _, duration)| duration)
+            .collect();
+        let outages = durations.len();
+
+        PeriodReport {
+            label: zone.label(period, start),
+            start: start,
+            end: end,
+            monitored: monitored,
+            unmonitored: (end.min(now).max(start) - start) - monitored,
+            downtime: downtime,
+            availability: if monitored > 0 { Some((monitored - downtime) as f64 * 100.0 / monitored as f64) } else { None },
+            outages: outages,
+            longest_outage: durations.iter().cloned().max(),
+            mttr: if outages > 0 { Some(durations.iter().sum::<i64>() / outages as i64) } else { None },
+            mtbf: if outages > 0 { Some((monitored - downtime) / outages as i64) } else { None },
+        }
+    }).collect())
+}
+
+// Sorts (start, end, duration) intervals and merges the overlapping ones. Merged intervals last from the first start to the last end.
+fn merge(mut intervals : Vec<(i64, i64, i64)>) -> Vec<(i64, i64, i64)> {
+    intervals.sort();
+    let mut merged : Vec<(i64, i64, i64)> = Vec::new();
+    for (start, end, duration) in intervals {
+        match merged.last_mut() {
+            Some(last) if start < last.1 => {
+                if end > last.1 {
+                    last.1 = end;
+                    last.2 = end - last.0;
+                }
+            },
+            _ => merged.push((start, end, duration))
+        }
+    }
+    merged
+}
+
+fn overlap(start : i64, end : i64, from : i64, to : i64) -> i64 {
+    (end.min(to) - start.max(from)).max(0)
+}
+
+// Short duration for tables, e.g. "2d 03h", "1h 05m", "4m 09s" or "12.345s"
+pub fn duration_text(millis : i64) -> String {
+    let seconds = millis / 1000;
+    if seconds >= 86400 {
+        format!("{}d {:02}h", seconds / 86400, seconds % 86400 / 3600)
+    } else if seconds >= 3600 {
+        format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60)
+    } else if seconds >= 60 {
+        format!("{}m {:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}.{:03}s", seconds, millis % 1000)
+    }
+}
+
+// Plain text table of the reports, marking the periods below the SLA if one is given
+pub fn table(reports : &[PeriodReport], sla : Option<f64>) -> String {
+    let mut table = format!("{:<12} {:>12} {:>8} {:>10} {:>10} {:>10} {:>10} {:>14}\n",
+        "Period", "Availability", "Outages", "Downtime", "Longest", "MTTR", "MTBF", "Not monitored");
+
+    let optional = |millis : Option<i64>| millis.map_or("-".to_owned(), duration_text);
+    for report in reports {
+        let availability = report.availability.map_or("-".to_owned(), |availability| format!("{:.3}%", availability));
+        table.push_str(&format!("{:<12} {:>12} {:>8} {:>10} {:>10} {:>10} {:>10} {:>14}",
+            report.label,
+            availability,
+            report.outages,
+            duration_text(report.downtime),
+            optional(report.longest_outage),
+            optional(report.mttr),
+            optional(report.mtbf),
+            duration_text(report.unmonitored)));
+        if let Some(sla) = sla {
+            if report.is_below(sla) {
+                table.push_str(&format!("  below SLA of {}%", sla));
+            }
+        }
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::db::{DowntimeRecord, UnmonitoredRecord};
+
+    const HOUR : i64 = 3_600_000;
+    const DAY : i64 = 24 * HOUR;
+    // 2019-03-04T00:00:00Z, a Monday
+    const MONDAY : i64 = 1_551_657_600_000;
+
+    fn fixed(s : &str) -> i32 {
+        match s.parse::<Zone>() {
+            Ok(Zone::Fixed(offset)) => offset.local_minus_utc(),
+            other => panic!("{} parsed as {:?}", s, other)
+        }
+    }
+
+    fn downtime(start : i64, end : i64) -> DowntimeRecord {
+        DowntimeRecord {start: start, end: end, duration: end - start, start_uncertainty: None, end_uncertainty: None, location: None, traceroute: None, interrupted: false, group: None, targets: Vec::new()}
+    }
+
+    #[test]
+    fn parses_zones() {
+        assert_eq!(fixed("+02:00"), 7200);
+        assert_eq!(fixed("-0530"), -19800);
+        assert_eq!(fixed("+2"), 7200);
+        assert!(match "UTC".parse::<Zone>() { Ok(Zone::Utc) => true, _ => false });
+        assert!(match "local".parse::<Zone>() { Ok(Zone::Local) => true, _ => false });
+
+        for invalid in &["", "2", "+", "+24", "+0160", "+123", "+1é1", "+é", "-1:x0"] {
+            assert!(invalid.parse::<Zone>().is_err(), "{} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_counts() {
+        assert_eq!(parse_count("1"), Ok(1));
+        assert_eq!(parse_count("366"), Ok(366));
+        assert!(parse_count("0").is_err());
+        assert!(parse_count("367").is_err());
+        assert!(parse_count("4000000000").is_err());
+        assert!(parse_count("-1").is_err());
+    }
+
+    #[test]
+    fn bounds_of_periods() {
+        let wednesday = MONDAY + 2 * DAY + 15 * HOUR;
+        assert_eq!(Zone::Utc.bounds(Period::Day, wednesday), (MONDAY + 2 * DAY, MONDAY + 3 * DAY));
+        assert_eq!(Zone::Utc.bounds(Period::Week, wednesday), (MONDAY, MONDAY + 7 * DAY));
+        // March 2019 began on a Friday
+        assert_eq!(Zone::Utc.bounds(Period::Month, wednesday), (MONDAY - 3 * DAY, MONDAY + 28 * DAY));
+        assert_eq!(Zone::Utc.label(Period::Week, wednesday), "2019-W10");
+
+        // 23:00 UTC on Sunday is already Monday at +02:00
+        let zone : Zone = "+02:00".parse().unwrap();
+        assert_eq!(zone.bounds(Period::Day, MONDAY - HOUR), (MONDAY - 2 * HOUR, MONDAY + DAY - 2 * HOUR));
+        assert_eq!(zone.label(Period::Day, MONDAY - HOUR), "2019-03-04");
+    }
+
+    #[test]
+    fn overlaps() {
+        assert_eq!(overlap(0, 10, 5, 20), 5);
+        assert_eq!(overlap(5, 20, 0, 10), 5);
+        assert_eq!(overlap(0, 10, 10, 20), 0);
+        assert_eq!(overlap(2, 4, 0, 10), 2);
+    }
+
+    #[test]
+    fn reports_days() {
+        let db = Db::new(":memory:").unwrap();
+        db.heartbeat(MONDAY + 12 * HOUR).unwrap();
+        // Down for two hours over midnight, and not monitored for an hour on Tuesday
+        db.import_downtime(&downtime(MONDAY + 23 * HOUR, MONDAY + 25 * HOUR)).unwrap();
+        db.insert_unmonitored(&UnmonitoredRecord::new(MONDAY + 30 * HOUR, MONDAY + 31 * HOUR, "stopped")).unwrap();
+
+        let now = MONDAY + 2 * DAY + 6 * HOUR;
+        let reports = report(&db, Period::Day, Zone::Utc, 4, now).unwrap();
+        assert_eq!(reports.iter().map(|report| report.label.as_str()).collect::<Vec<_>>(), vec!["2019-03-03", "2019-03-04", "2019-03-05", "2019-03-06"]);
+
+        // Before icc first ran
+        assert_eq!(reports[0].monitored, 0);
+        assert_eq!(reports[0].unmonitored, DAY);
+        assert_eq!(reports[0].availability, None);
+
+        assert_eq!(reports[1].monitored, 12 * HOUR);
+        assert_eq!(reports[1].downtime, HOUR);
+        assert_eq!(reports[1].outages, 1);
+        assert_eq!(reports[1].longest_outage, Some(2 * HOUR));
+
+        assert_eq!(reports[2].monitored, 23 * HOUR);
+        assert_eq!(reports[2].unmonitored, HOUR);
+        assert_eq!(reports[2].downtime, HOUR);
+        assert_eq!(reports[2].outages, 0);
+
+        // Up to now
+        assert_eq!(reports[3].monitored, 6 * HOUR);
+        assert_eq!(reports[3].availability, Some(100.0));
+
+        assert_eq!(report(&db, Period::Day, Zone::Utc, u32::max_value(), now).unwrap().len(), MAX_PERIODS as usize);
+    }
+
+    #[test]
+    fn merges_overlapping_intervals() {
+        assert_eq!(merge(vec![(5, 20, 15), (0, 10, 10), (30, 40, 9)]), vec![(0, 20, 20), (30, 40, 9)]);
+        assert_eq!(merge(vec![(0, 10, 10), (2, 4, 2)]), vec![(0, 10, 10)]);
+        assert_eq!(merge(vec![(0, 10, 10), (10, 20, 10)]), vec![(0, 10, 10), (10, 20, 10)]);
+        assert_eq!(merge(Vec::new()), Vec::new());
+    }
+
+    #[test]
+    fn counts_overlapping_downtimes_once() {
+        let db = Db::new(":memory:").unwrap();
+        db.heartbeat(MONDAY).unwrap();
+        // The default and the isp group were down at the same time
+        let group = |group : &str, start : i64, end : i64| DowntimeRecord {group: Some(group.to_owned()), ..downtime(start, end)};
+        db.import_downtime(&group("default", MONDAY + HOUR, MONDAY + 3 * HOUR)).unwrap();
+        db.import_downtime(&group("isp", MONDAY + 2 * HOUR, MONDAY + 4 * HOUR)).unwrap();
+        // Down while icc wasn't monitoring
+        db.import_downtime(&group("default", MONDAY + 10 * HOUR, MONDAY + 12 * HOUR)).unwrap();
+        db.insert_unmonitored(&UnmonitoredRecord::new(MONDAY + 9 * HOUR, MONDAY + 11 * HOUR, "suspended")).unwrap();
+        db.insert_unmonitored(&UnmonitoredRecord::new(MONDAY + 10 * HOUR, MONDAY + 11 * HOUR, "not_running")).unwrap();
+        db.import_downtime(&group("default", MONDAY + 14 * HOUR, MONDAY + 15 * HOUR)).unwrap();
+        db.insert_unmonitored(&UnmonitoredRecord::new(MONDAY + 13 * HOUR, MONDAY + 16 * HOUR, "suspended")).unwrap();
+
+        let reports = report(&db, Period::Day, Zone::Utc, 2, MONDAY + DAY + HOUR).unwrap();
+        assert_eq!(reports[0].unmonitored, 5 * HOUR);
+        assert_eq!(reports[0].monitored, 19 * HOUR);
+        assert_eq!(reports[0].downtime, 4 * HOUR);
+        // The outage that was entirely unmonitored isn't one
+        assert_eq!(reports[0].outages, 2);
+        assert_eq!(reports[0].longest_outage, Some(3 * HOUR));
+        assert_eq!(reports[0].availability, Some(15.0 * 100.0 / 19.0));
+    }
+}
@@ -0,0 +1,371 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use chrono::prelude::Utc;
+use crate::ping::model::{ConnectivityDown, ConnectivityDegraded, DurationFormat, Trace, timestamp_text};
+use super::config::{Config, LogFormat};
+
+// None if no clear_text_log is configured
+pub type LogFile = Arc<Mutex<Option<ClearTextLog>>>;
+
+// When the log is moved aside for a new one. Checked before writing to it.
+#[derive(Clone, Copy, Debug)]
+pub struct Rotation {
+    // Bytes
+    pub max_size : Option<u64>,
+    pub max_age : Option<Duration>,
+    // Rotated logs kept, <path>.1 is the newest
+    pub keep : u32,
+}
+
+pub struct ClearTextLog {
+    path : PathBuf,
+    file : File,
+    format : LogFormat,
+    rotation : Rotation,
+    // When the current log was started, for max_age
+    started : SystemTime,
+}
+
+impl ClearTextLog {
+    // The log configured by clear_text_log, log_format and log_max_*, None if there is none
+    pub fn from_config(config : &Config) -> io::Result<Option<Self>> {
+        let rotation = Rotation {
+            max_size: config.log_max_size.map(|megabytes| megabytes * 1024 * 1024),
+            max_age: config.log_max_age.map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            keep: config.log_keep.unwrap_or(5),
+        };
+
+        match config.clear_text_log.as_ref() {
+            Some(path) => Self::open(Path::new(path), config.log_format.unwrap_or_default(), rotation)
+                .map(Some)
+                .map_err(|e| io::Error::new(e.kind(), format!("unable to open {}: {}", path, e))),
+            None => Ok(None)
+        }
+    }
+
+    pub fn open(path : &Path, format : LogFormat, rotation : Rotation) -> io::Result<Self> {
+        let file = Self::open_file(path)?;
+        let metadata = file.metadata()?;
+        let started = metadata.created().unwrap_or_else(|_| SystemTime::now());
+
+        Ok(Self {path: path.to_owned(), file: file, format: format, rotation: rotation, started: started})
+    }
+
+    fn open_file(path : &Path) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .append(true)
+            .open(path)
+    }
+
+    pub fn write_downtime(&mut self, cd : &ConnectivityDown) -> io::Result<()> {
+        let entry = downtime_entry(self.format, cd);
+        self.write(&entry)
+    }
+
+    pub fn write_degraded(&mut self, degraded : &ConnectivityDegraded) -> io::Result<()> {
+        let entry = degraded_entry(self.format, degraded);
+        self.write(&entry)
+    }
+
+    // Flushes the log to disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn write(&mut self, entry : &str) -> io::Result<()> {
+        if self.should_rotate()? {
+            self.rotate()?;
+        }
+        self.file.write_all(entry.as_bytes())
+    }
+
+    fn should_rotate(&self) -> io::Result<bool> {
+        let size = self.file.metadata()?.len();
+        if size == 0 {
+            return Ok(false);
+        }
+
+        let too_large = self.rotation.max_size.map_or(false, |max_size| size >= max_size);
+        let too_old = self.rotation.max_age.map_or(false, |max_age| {
+            SystemTime::now().duration_since(self.started).map_or(false, |age| age >= max_age)
+        });
+        Ok(too_large || too_old)
+    }
+
+    // <path>.N-1 becomes <path>.N, down to <path> becoming <path>.1, and a new log is started at <path>
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n : u32| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+
+        let oldest = rotated(self.rotation.keep);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for n in (1..self.rotation.keep).rev() {
+            let from = rotated(n);
+            if from.exists() {
+                fs::rename(&from, rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(1))?;
+
+        self.file = Self::open_file(&self.path)?;
+        self.started = SystemTime::now();
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct DowntimeEntry<'a> {
+    time : String,
+    event : &'static str,
+    group : Option<&'a str>,
+    targets : &'a [String],
+    // Where the outage was, "gateway", "isp", "internet" or "unknown"
+    classification : &'static str,
+    start : String,
+    end : String,
+    start_epoch_millis : i64,
+    end_epoch_millis : i64,
+    duration_s : f64,
+    start_uncertainty_ms : Option<i64>,
+    end_uncertainty_ms : Option<i64>,
+    interrupted : bool,
+    traceroute : &'a [Trace],
+}
+
+#[derive(Serialize)]
+struct DegradedEntry<'a> {
+    time : String,
+    event : &'static str,
+    target : &'a str,
+    start : String,
+    end : String,
+    start_epoch_millis : i64,
+    end_epoch_millis : i64,
+    duration_s : f64,
+    loss_percent : f64,
+    rtt_ms : Option<i64>,
+}
+
+fn seconds(millis : i64) -> f64 {
+    millis as f64 / 1000.0
+}
+
+fn millis(duration : Duration) -> i64 {
+    crate::ping::model::duration_millis(duration)
+}
+
+fn now_text() -> String {
+    timestamp_text(Utc::now().timestamp_millis())
+}
+
+pub fn downtime_entry(format : LogFormat, cd : &ConnectivityDown) -> String {
+    match format {
+        LogFormat::Text => {
+            let mut payload : String = format!("Downtime:\n ({}) {} - ({}) {}\n lasted for: {} ({} ms)\n uncertainty: {}\n location: {}\n",
+                cd.start_epoch_millis(),
+                cd.start_text(),
+                cd.end_epoch_millis(),
+                cd.end_text(),
+                cd.duration_text(),
+                cd.duration_millis(),
+                cd.uncertainty_text(),
+                cd.location_text());
+            if let Some(group) = cd.group() {
+                payload.push_str(&format!(" group: {}\n targets: {}\n", group, cd.targets().join(", ")));
+            }
+            if cd.is_interrupted() {
+                payload.push_str(" interrupted: icc was stopped before the outage ended\n");
+            }
+            for trace in cd.traces() {
+                payload.push_str(&format!(" traceroute: {}\n", trace.text()));
+            }
+            payload
+        },
+        LogFormat::Logfmt => {
+            let optional = |value : Option<Duration>| value.map_or(String::new(), |value| millis(value).to_string());
+            format!("time={} event=downtime group={} targets={} classification={} start={} end={} duration_s={:.3} start_uncertainty_ms={} end_uncertainty_ms={} interrupted={}\n",
+                now_text(),
+                logfmt_value(cd.group().unwrap_or("")),
+                logfmt_value(&cd.targets().join(",")),
+                cd.location_text(),
+                cd.start_text(),
+                cd.end_text(),
+                seconds(cd.duration_millis()),
+                optional(cd.start_uncertainty()),
+                optional(cd.end_uncertainty()),
+                cd.is_interrupted())
+        },
+        LogFormat::Json => {
+            let entry = DowntimeEntry {
+                time: now_text(),
+                event: "downtime",
+                group: cd.group(),
+                targets: cd.targets(),
+                classification: cd.location_text(),
+                start: cd.start_text(),
+                end: cd.end_text(),
+                start_epoch_millis: cd.start_epoch_millis(),
+                end_epoch_millis: cd.end_epoch_millis(),
+                duration_s: seconds(cd.duration_millis()),
+                start_uncertainty_ms: cd.start_uncertainty().map(millis),
+                end_uncertainty_ms: cd.end_uncertainty().map(millis),
+                interrupted: cd.is_interrupted(),
+                traceroute: cd.traces(),
+            };
+            json_line(&entry)
+        }
+    }
+}
+
+pub fn degraded_entry(format : LogFormat, degraded : &ConnectivityDegraded) -> String {
+    match format {
+        LogFormat::Text => {
+            let rtt = match degraded.rtt() {
+                Some(rtt) => format!("{:?}", rtt),
+                None => "n/a".to_owned()
+            };
+            format!("Degraded ({}):\n ({}) - ({}) {}\n lasted for: {} ({} ms), worst loss: {:.1}%, worst rtt: {}\n",
+                degraded.target(),
+                degraded.start_epoch_millis(),
+                degraded.end_epoch_millis(),
+                degraded.start_end_text(),
+                degraded.duration().as_text(),
+                degraded.duration_millis(),
+                degraded.loss_percent(),
+                rtt)
+        },
+        LogFormat::Logfmt => {
+            format!("time={} event=degraded target={} start={} end={} duration_s={:.3} loss_percent={:.1} rtt_ms={}\n",
+                now_text(),
+                logfmt_value(degraded.target()),
+                timestamp_text(degraded.start_epoch_millis()),
+                timestamp_text(degraded.end_epoch_millis()),
+                seconds(degraded.duration_millis()),
+                degraded.loss_percent(),
+                degraded.rtt().map_or(String::new(), |rtt| millis(rtt).to_string()))
+        },
+        LogFormat::Json => {
+            let entry = DegradedEntry {
+                time: now_text(),
+                event: "degraded",
+                target: degraded.target(),
+                start: timestamp_text(degraded.start_epoch_millis()),
+                end: timestamp_text(degraded.end_epoch_millis()),
+                start_epoch_millis: degraded.start_epoch_millis(),
+                end_epoch_millis: degraded.end_epoch_millis(),
+                duration_s: seconds(degraded.duration_millis()),
+                loss_percent: degraded.loss_percent(),
+                rtt_ms: degraded.rtt().map(millis),
+            };
+            json_line(&entry)
+        }
+    }
+}
+
+// Quoted if empty or containing spaces, quotes or =
+fn logfmt_value(value : &str) -> String {
+    if value.is_empty() || value.contains(|c : char| c.is_whitespace() || c == '"' || c == '=') {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn json_line<T : serde::Serialize>(entry : &T) -> String {
+    let mut line = serde_json::to_string(entry).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::ping::model::Timestamp;
+
+    fn downtime(start : u64, end : u64) -> ConnectivityDown {
+        let base = Instant::now();
+        let at = |millis : u64| Timestamp {epoch_millis: 1_551_557_643_250 + millis as i64, instant: base + Duration::from_millis(millis)};
+        let mut cd = ConnectivityDown::new();
+        cd.start_at(at(start));
+        cd.set_targets("default", vec!["8.8.8.8".to_owned()]);
+        cd.end_at(at(end));
+        cd
+    }
+
+    #[test]
+    fn quotes_logfmt_values() {
+        assert_eq!(logfmt_value("8.8.8.8"), "8.8.8.8");
+        assert_eq!(logfmt_value(""), "\"\"");
+        assert_eq!(logfmt_value("my router"), "\"my router\"");
+        assert_eq!(logfmt_value("a=b"), "\"a=b\"");
+        assert_eq!(logfmt_value(r#"say "hi" \o/"#), r#""say \"hi\" \\o/""#);
+    }
+
+    #[test]
+    fn writes_single_lines() {
+        let cd = downtime(0, 1500);
+        let logfmt = downtime_entry(LogFormat::Logfmt, &cd);
+        assert_eq!(logfmt.lines().count(), 1);
+        assert!(logfmt.contains(" event=downtime group=default targets=8.8.8.8 classification=unknown "), "{}", logfmt);
+        assert!(logfmt.contains(" duration_s=1.500 "), "{}", logfmt);
+
+        let json : serde_json::Value = serde_json::from_str(&downtime_entry(LogFormat::Json, &cd)).unwrap();
+        assert_eq!(json["event"], "downtime");
+        assert_eq!(json["start_epoch_millis"], 1_551_557_643_250i64);
+        assert_eq!(json["duration_s"], 1.5);
+    }
+
+    #[test]
+    fn rotates() {
+        let dir = std::env::temp_dir().join(format!("icc-rotation-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("icc.log");
+        let rotated = |n : u32| dir.join(format!("icc.log.{}", n));
+
+        let rotation = Rotation {max_size: Some(1), max_age: None, keep: 2};
+        let mut log = ClearTextLog::open(&path, LogFormat::Logfmt, rotation).unwrap();
+        for seconds in 1..=4 {
+            log.write_downtime(&downtime(0, seconds * 1000)).unwrap();
+        }
+
+        // Every write but the first found the log full, and the oldest fell off
+        let read = |path : &Path| fs::read_to_string(path).unwrap();
+        assert!(read(&path).contains("duration_s=4.000"));
+        assert!(read(&rotated(1)).contains("duration_s=3.000"));
+        assert!(read(&rotated(2)).contains("duration_s=2.000"));
+        assert!(!rotated(3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn doesnt_rotate_below_limits() {
+        let dir = std::env::temp_dir().join(format!("icc-no-rotation-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("icc.log");
+
+        let rotation = Rotation {max_size: Some(1024 * 1024), max_age: Some(Duration::from_secs(3600)), keep: 2};
+        let mut log = ClearTextLog::open(&path, LogFormat::Text, rotation).unwrap();
+        log.write_downtime(&downtime(0, 1000)).unwrap();
+        log.write_downtime(&downtime(0, 2000)).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap().matches("Downtime:").count(), 2);
+        assert!(!dir.join("icc.log.1").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,30 @@
-use std::fs::{File, OpenOptions};
-use std::io::Read;
-use std::io::Write;
-use rand::{thread_rng, Rng};
-use rand::distributions::Alphanumeric;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use crate::detector::{DetectionPolicy, DegradedThresholds};
+use crate::ping::ProbeOptions;
+use super::webhook::WebhookUrl;
+use serde::{Serialize, Serializer};
+use toml::Value;
+use toml::value::Table;
+use log::{debug, error};
+
+// Environment variable pointing at the config file, same as --config
+pub const CONFIG_ENV : &str = "ICC_CONFIG";
 
 // Config
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, Default)]
 pub struct Config {
     // Address + port for web interface, e.g. "0.0.0.0:4017"
     pub bind_address: Option<String>,
-    // An array of addresses to use when monitoring network connectivity, e.g. ["8.8.8.8", "1.1.1.1"]
+    // An array of IP addresses or hostnames to use when monitoring network connectivity, e.g. ["8.8.8.8", "one.one.one.one"]
     pub addresses_to_monitor: Option<Vec<String>>,
     // Maximum ping timeouts before it counts as "downtime"
     pub max_timeouts: Option<u32>,
@@ -17,29 +32,616 @@ pub struct Config {
     pub max_ping_timeout: Option<u64>,
     // Local database file
     pub db: Option<String>,
+    // Unix domain socket the running icc is queried and controlled through, e.g. by icc status. Not available on Windows.
+    pub control_socket: Option<String>,
     // If set, logs downtimes in clear text at the specified path
-    pub clear_text_log: Option<String>
+    pub clear_text_log: Option<String>,
+    // Format of the clear text log: "text", "logfmt" or "json" (one object per line)
+    pub log_format: Option<LogFormat>,
+    // Rotate the clear text log once it is larger than this many megabytes
+    pub log_max_size: Option<u64>,
+    // Rotate the clear text log once it is older than this many days
+    pub log_max_age: Option<u64>,
+    // Number of rotated logs kept, as <clear_text_log>.1 (newest) to <clear_text_log>.N
+    pub log_keep: Option<u32>,
+    // Packet loss in percent, over the last degraded_window pings, before a target counts as degraded. 0 turns it off
+    pub degraded_loss_percent: Option<f64>,
+    // Average round-trip time in milliseconds, over the last degraded_window pings, before a target counts as degraded. 0 turns it off
+    pub degraded_rtt: Option<u64>,
+    // Number of pings packet loss and round-trip time are measured over
+    pub degraded_window: Option<u32>,
+    // Seconds between resolving hostname targets again
+    pub resolve_interval: Option<u64>,
+    // URL downtimes are posted to as JSON when they start, are located and end, e.g. "http://192.168.1.10:8123/api/webhook/icc". Only http.
+    pub webhook: Option<String>,
+    // Named targets, e.g. [[target]] name = "isp-dns", address = "8.8.8.8", group = "isp"
+    #[serde(rename = "target")]
+    pub targets: Option<Vec<Target>>,
+    // Groups of targets that are judged together, e.g. [[group]] name = "dns", addresses = ["8.8.8.8", "1.1.1.1"], policy = "majority"
+    #[serde(rename = "group")]
+    pub groups: Option<Vec<TargetGroup>>
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct Target {
+    // Used instead of the address in logs, reports and groups
+    pub name: String,
+    // IP address or hostname
+    pub address: String,
+    #[serde(default)]
+    pub probe: ProbeType,
+    // Seconds between pings, every round if not set
+    pub interval: Option<u64>,
+    // Milliseconds to wait for a reply, max_ping_timeout if not set
+    pub timeout: Option<u64>,
+    // Group the target is judged in, e.g. "gateway", "isp" or "internet". Created with policy "all" if there is no [[group]] by that name.
+    pub group: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Target {
+    // A target configured by address only, named after its address
+    fn from_address(address : &str) -> Self {
+        Self {
+            name: address.to_owned(),
+            address: address.to_owned(),
+            probe: ProbeType::Icmp,
+            interval: None,
+            timeout: None,
+            group: None,
+            tags: Vec::new(),
+        }
+    }
+
+    pub fn probe_options(&self) -> ProbeOptions {
+        ProbeOptions {
+            interval: self.interval.map(Duration::from_secs),
+            timeout: self.timeout.map(Duration::from_millis),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeType {
+    Icmp,
+}
+
+impl Default for ProbeType {
+    fn default() -> Self {
+        ProbeType::Icmp
+    }
+}
+
+impl Serialize for ProbeType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ProbeType::Icmp => serializer.serialize_str("icmp"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    // The multi-line blocks icc has always written
+    Text,
+    Logfmt,
+    Json,
+}
+
+impl LogFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Logfmt => "logfmt",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "logfmt" => Ok(LogFormat::Logfmt),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', expected text, logfmt or json", s))
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct TargetGroup {
+    pub name: String,
+    // Target names, or addresses. Can be left out when the targets join the group with their group key.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    // How many of the addresses have to be down before the group counts as down. "all", "majority" or { at_least = N }
+    #[serde(default)]
+    pub policy: DetectionPolicy,
+}
+
+impl Config {
+    // Every target to ping: the [[target]] tables, plus targets named after their address
+    // for addresses in addresses_to_monitor and groups that don't refer to a [[target]]
+    pub fn targets(&self) -> Vec<Target> {
+        let mut targets : Vec<Target> = self.targets.clone().unwrap_or_default();
+
+        let addresses = self.addresses_to_monitor.iter().flatten()
+            .chain(self.groups.iter().flatten().flat_map(|group| group.addresses.iter()));
+        for address in addresses {
+            if self.target_name(address).is_none() && !targets.iter().any(|target| &target.name == address) {
+                targets.push(Target::from_address(address));
+            }
+        }
+
+        targets
+    }
+
+    // Name of the [[target]] an address or name in a group refers to
+    fn target_name(&self, address : &str) -> Option<&str> {
+        let targets = self.targets.as_ref().map(|targets| targets.as_slice()).unwrap_or(&[]);
+        targets.iter().find(|target| target.name == address)
+            .or_else(|| targets.iter().find(|target| target.address == address))
+            .map(|target| target.name.as_str())
+    }
+
+    // Configured groups with their addresses as target names, groups named by [[target]] group,
+    // plus a "default" group (all targets down) for targets not covered by any group
+    pub fn target_groups(&self) -> Vec<TargetGroup> {
+        let mut groups : Vec<TargetGroup> = self.groups.clone().unwrap_or_default();
+        for group in groups.iter_mut() {
+            let mut names : Vec<String> = Vec::new();
+            for address in &group.addresses {
+                let name = self.target_name(address).unwrap_or(address).to_owned();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+            group.addresses = names;
+        }
+
+        for target in self.targets.iter().flatten() {
+            let group_name = match &target.group {
+                Some(group_name) => group_name,
+                None => continue
+            };
+
+            match groups.iter_mut().find(|group| &group.name == group_name) {
+                Some(group) => {
+                    if !group.addresses.contains(&target.name) {
+                        group.addresses.push(target.name.clone());
+                    }
+                },
+                None => groups.push(TargetGroup {
+                    name: group_name.clone(),
+                    addresses: vec!(target.name.clone()),
+                    policy: DetectionPolicy::All,
+                })
+            }
+        }
+
+        let ungrouped : Vec<String> = self.targets().into_iter()
+            .map(|target| target.name)
+            .filter(|name| !groups.iter().any(|group| group.addresses.contains(name)))
+            .collect();
+
+        if !ungrouped.is_empty() {
+            groups.push(TargetGroup {
+                name: "default".to_owned(),
+                addresses: ungrouped,
+                policy: DetectionPolicy::All,
+            });
+        }
+
+        groups
+    }
+
+    // The config with a target added, for changing the running config
+    pub fn with_target(&self, target : Target) -> Result<Config, ConfigError> {
+        let mut config = self.clone();
+        config.targets.get_or_insert_with(Vec::new).push(target);
+        config.validate()?;
+        Ok(config)
+    }
+
+    // The config without a target, which is also taken out of addresses_to_monitor and the groups.
+    // None if there is no such target.
+    pub fn without_target(&self, name : &str) -> Option<Config> {
+        let target = self.targets().into_iter().find(|target| target.name == name)?;
+        // Groups may refer to a [[target]] by its address
+        let is_reference = |address : &String| address == name || address == &target.address;
+
+        let mut config = self.clone();
+        if let Some(targets) = config.targets.as_mut() {
+            targets.retain(|target| target.name != name);
+        }
+        if let Some(addresses) = config.addresses_to_monitor.as_mut() {
+            addresses.retain(|address| !is_reference(address));
+        }
+        if let Some(groups) = config.groups.as_mut() {
+            for group in groups.iter_mut() {
+                group.addresses.retain(|address| !is_reference(address));
+            }
+        }
+
+        // Groups that are left without members
+        let members = config.target_groups();
+        if let Some(groups) = config.groups.as_mut() {
+            groups.retain(|group| members.iter().any(|members| members.name == group.name && !members.addresses.is_empty()));
+        }
+        Some(config)
+    }
+
+    pub fn degraded_thresholds(&self) -> DegradedThresholds {
+        DegradedThresholds {
+            loss_percent: self.degraded_loss_percent.filter(|&loss_percent| loss_percent > 0.0),
+            rtt: self.degraded_rtt.filter(|&rtt| rtt > 0).map(Duration::from_millis),
+            window: self.degraded_window.unwrap_or(20) as usize,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    // The config file could not be read
+    Io { path: PathBuf, error: io::Error },
+    // The config file could not be written, when changing it from the command line
+    Write { path: PathBuf, error: io::Error },
+    // The config file is not valid toml, or a key has the wrong type
+    Parse { path: PathBuf, error: toml::de::Error },
+    // A key has a value that is not allowed. For environment overrides the key is the variable name.
+    Invalid { key: String, message: String },
 }
 
-pub fn config() -> Config {
-    let mut save_to_file  : bool = false;
-    let mut config_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open("config.toml").unwrap();
-    let mut buf = Vec::new();
-    config_file.read_to_end(&mut buf);
-    let mut config : Config = toml::from_slice(&buf).unwrap();
+impl ConfigError {
+    fn invalid(key : &str, message : &str) -> Self {
+        ConfigError::Invalid { key: key.to_owned(), message: message.to_owned() }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "unable to read config file {}: {}", path.display(), error),
+            ConfigError::Write { path, error } => write!(f, "unable to write config file {}: {}", path.display(), error),
+            ConfigError::Parse { path, error } => write!(f, "invalid config file {}: {}", path.display(), error),
+            ConfigError::Invalid { key, message } => write!(f, "invalid value for `{}`: {}", key, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    // Checks values that parse fine, but can't be used
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(bind_address) = &self.bind_address {
+            if bind_address.parse::<SocketAddr>().is_err() {
+                return Err(ConfigError::invalid("bind_address", &format!("{} is not an address and port, e.g. \"0.0.0.0:4017\"", bind_address)));
+            }
+        }
+
+        for address in self.addresses_to_monitor.iter().flatten() {
+            if !is_target_address(address) {
+                return Err(ConfigError::invalid("addresses_to_monitor", &format!("{} is not an IP address or hostname", address)));
+            }
+        }
+
+        if self.control_socket.as_ref().map_or(false, |path| path.is_empty()) {
+            return Err(ConfigError::invalid("control_socket", "can't be empty"));
+        }
+
+        if self.max_timeouts == Some(0) {
+            return Err(ConfigError::invalid("max_timeouts", "has to be at least 1"));
+        }
+
+        if self.max_ping_timeout == Some(0) {
+            return Err(ConfigError::invalid("max_ping_timeout", "has to be at least 1 millisecond"));
+        }
+
+        if let Some(loss_percent) = self.degraded_loss_percent {
+            if !(loss_percent >= 0.0 && loss_percent <= 100.0) {
+                return Err(ConfigError::invalid("degraded_loss_percent", "has to be between 0 and 100"));
+            }
+        }
+
+        if self.degraded_window == Some(0) {
+            return Err(ConfigError::invalid("degraded_window", "has to be at least 1"));
+        }
+
+        if self.resolve_interval == Some(0) {
+            return Err(ConfigError::invalid("resolve_interval", "has to be at least 1 second"));
+        }
+
+        if self.log_max_size == Some(0) {
+            return Err(ConfigError::invalid("log_max_size", "has to be at least 1 megabyte"));
+        }
+
+        if self.log_max_age == Some(0) {
+            return Err(ConfigError::invalid("log_max_age", "has to be at least 1 day"));
+        }
+
+        if self.log_keep == Some(0) {
+            return Err(ConfigError::invalid("log_keep", "has to be at least 1"));
+        }
+
+        if let Some(webhook) = &self.webhook {
+            WebhookUrl::parse(webhook).map_err(|e| ConfigError::invalid("webhook", &e))?;
+        }
+
+        let targets = self.targets.as_ref().map(|targets| targets.as_slice()).unwrap_or(&[]);
+        for (index, target) in targets.iter().enumerate() {
+            let key = |field : &str| format!("target.{}.{}", target.name, field);
+
+            if target.name.is_empty() {
+                return Err(ConfigError::invalid(&format!("target[{}].name", index), "can't be empty"));
+            }
+
+            if targets[..index].iter().any(|other| other.name == target.name) {
+                return Err(ConfigError::invalid(&key("name"), "is used by more than one target"));
+            }
+
+            if !is_target_address(&target.address) {
+                return Err(ConfigError::invalid(&key("address"), &format!("{} is not an IP address or hostname", target.address)));
+            }
+
+            if target.interval == Some(0) {
+                return Err(ConfigError::invalid(&key("interval"), "has to be at least 1 second"));
+            }
+
+            if target.timeout == Some(0) {
+                return Err(ConfigError::invalid(&key("timeout"), "has to be at least 1 millisecond"));
+            }
+
+            if target.group.as_ref().map_or(false, |group| group.is_empty()) {
+                return Err(ConfigError::invalid(&key("group"), "can't be empty"));
+            }
+        }
+
+        let groups = self.groups.as_ref().map(|groups| groups.as_slice()).unwrap_or(&[]);
+        for (index, group) in groups.iter().enumerate() {
+            let key = |field : &str| format!("group.{}.{}", group.name, field);
+
+            if group.name.is_empty() {
+                return Err(ConfigError::invalid(&format!("group[{}].name", index), "can't be empty"));
+            }
+
+            if groups[..index].iter().any(|other| other.name == group.name) {
+                return Err(ConfigError::invalid(&key("name"), "is used by more than one group"));
+            }
+
+            for address in &group.addresses {
+                if self.target_name(address).is_none() && !is_target_address(address) {
+                    return Err(ConfigError::invalid(&key("addresses"), &format!("{} is not a target name, IP address or hostname", address)));
+                }
+            }
+        }
+
+        // Members are the addresses of a group as well as the targets that name it as their group
+        for group in self.target_groups().iter().filter(|group| groups.iter().any(|configured| configured.name == group.name)) {
+            let key = |field : &str| format!("group.{}.{}", group.name, field);
+
+            if group.addresses.is_empty() {
+                return Err(ConfigError::invalid(&key("addresses"), "needs at least one address, or a target with this group"));
+            }
+
+            if let DetectionPolicy::AtLeast(n) = group.policy {
+                if n == 0 || n > group.addresses.len() {
+                    return Err(ConfigError::invalid(&key("policy"), &format!("at_least has to be between 1 and {}", group.addresses.len())));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// An IP address, or something that looks like a hostname. Whether the hostname resolves is only known once pinging.
+fn is_target_address(address : &str) -> bool {
+    if address.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+
+    !address.is_empty() && address.len() <= 253 && address.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+// What differs between the running config and a reloaded one
+pub struct ConfigChanges {
+    // A target that changed is both removed and added
+    pub added_targets: Vec<Target>,
+    pub removed_targets: Vec<Target>,
+    pub max_ping_timeout: Option<u64>,
+    pub resolve_interval: Option<u64>,
+    // max_timeouts, groups or degraded thresholds changed
+    pub detection: bool,
+    // The clear text log or how it is written changed
+    pub clear_text_log: bool,
+    // Keys that only take effect after a restart
+    pub restart_required: Vec<&'static str>,
+}
+
+impl Config {
+    pub fn changes(&self, new : &Config) -> ConfigChanges {
+        let running = self.targets();
+        let reloaded = new.targets();
+
+        let mut restart_required = Vec::new();
+        if self.bind_address != new.bind_address {
+            restart_required.push("bind_address");
+        }
+        if self.control_socket != new.control_socket {
+            restart_required.push("control_socket");
+        }
+
+        ConfigChanges {
+            added_targets: reloaded.iter().filter(|target| !running.contains(target)).cloned().collect(),
+            removed_targets: running.iter().filter(|target| !reloaded.contains(target)).cloned().collect(),
+            max_ping_timeout: if self.max_ping_timeout != new.max_ping_timeout { new.max_ping_timeout } else { None },
+            resolve_interval: if self.resolve_interval != new.resolve_interval { new.resolve_interval } else { None },
+            detection: self.max_timeouts != new.max_timeouts
+                || self.groups != new.groups
+                || self.targets != new.targets
+                || self.addresses_to_monitor != new.addresses_to_monitor
+                || self.degraded_loss_percent != new.degraded_loss_percent
+                || self.degraded_rtt != new.degraded_rtt
+                || self.degraded_window != new.degraded_window,
+            clear_text_log: self.clear_text_log != new.clear_text_log
+                || self.log_format != new.log_format
+                || self.log_max_size != new.log_max_size
+                || self.log_max_age != new.log_max_age
+                || self.log_keep != new.log_keep,
+            restart_required: restart_required,
+        }
+    }
+}
+
+// Tells when the config should be reloaded: the config file was modified, or SIGHUP was received
+pub struct ConfigWatcher {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+    hangup: Arc<AtomicBool>,
+}
+
+impl ConfigWatcher {
+    // How often the modification time of the config file is checked
+    const INTERVAL : Duration = Duration::from_secs(2);
+
+    pub fn new(path : Option<PathBuf>) -> Self {
+        let hangup = Arc::new(AtomicBool::new(false));
+
+        #[cfg(unix)]
+        {
+            if let Err(e) = signal_hook::flag::register(signal_hook::SIGHUP, hangup.clone()) {
+                error!("Unable to set SIGHUP handler: {}", e);
+            }
+        }
+
+        let modified = path.as_ref().and_then(|path| Self::modified(path));
+        Self {path: path, modified: modified, last_check: Instant::now(), hangup: hangup}
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_ref().map(|path| path.as_path())
+    }
+
+    fn modified(path : &Path) -> Option<SystemTime> {
+        path.metadata().and_then(|metadata| metadata.modified()).ok()
+    }
+
+    // Cheap enough to call on every result, the file is only looked at every INTERVAL
+    pub fn should_reload(&mut self) -> bool {
+        if self.hangup.swap(false, Ordering::Relaxed) {
+            debug!("SIGHUP received, reloading config");
+            return true;
+        }
+
+        if self.last_check.elapsed() < Self::INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+
+        if let Some(path) = &self.path {
+            let modified = Self::modified(path);
+            if modified != self.modified {
+                self.modified = modified;
+                debug!("{} modified, reloading config", path.display());
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+// Value of --config <path> or --config=<path>, if passed on the command line
+pub fn config_path_arg() -> Option<PathBuf> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if arg.starts_with("--config=") {
+            return Some(PathBuf::from(&arg["--config=".len()..]));
+        }
+    }
+    None
+}
+
+// Locations searched for a config file when none is given explicitly, in order
+pub fn config_search_paths() -> Vec<PathBuf> {
+    let mut paths = vec!(PathBuf::from("config.toml"));
+
+    if let Some(config_home) = env::var_os("XDG_CONFIG_HOME") {
+        paths.push(PathBuf::from(config_home).join("icc").join("config.toml"));
+    } else if let Some(home) = env::var_os("HOME") {
+        paths.push(PathBuf::from(home).join(".config").join("icc").join("config.toml"));
+    }
+
+    if cfg!(unix) {
+        paths.push(PathBuf::from("/etc/icc/config.toml"));
+    }
+
+    paths
+}
+
+// The config file to use: the explicit path, then ICC_CONFIG, then the first of the search paths that exists.
+// None if there is no config file, in which case the defaults are used.
+pub fn find_config_file(explicit : Option<PathBuf>) -> Option<PathBuf> {
+    if let Some(path) = explicit {
+        return Some(path);
+    }
+
+    if let Some(path) = env::var_os(CONFIG_ENV) {
+        return Some(PathBuf::from(path));
+    }
+
+    config_search_paths().into_iter().find(|path| path.is_file())
+}
+
+// Reads the config from the file found by find_config_file, applies ICC_* environment overrides and defaults, and validates it
+pub fn config() -> Result<Config, ConfigError> {
+    let path = find_config_file(config_path_arg());
+    config_from(path.as_ref().map(|path| path.as_path()))
+}
+
+pub fn config_from(path : Option<&Path>) -> Result<Config, ConfigError> {
+    let mut config : Config = match path {
+        Some(path) => {
+            let mut buf = Vec::new();
+            File::open(path)
+                .and_then(|mut config_file| config_file.read_to_end(&mut buf))
+                .map_err(|e| ConfigError::Io { path: path.to_owned(), error: e })?;
+            toml::from_slice(&buf).map_err(|e| ConfigError::Parse { path: path.to_owned(), error: e })?
+        },
+        None => Config::default()
+    };
+
+    apply_env_overrides(&mut config)?;
 
     if let None = config.bind_address {
         config.bind_address = Some("0.0.0.0:4017".to_owned());
-        save_to_file = true;
     }
 
-    if let None = config.addresses_to_monitor {
+    if config.addresses_to_monitor.is_none() && config.targets.is_none() {
         config.addresses_to_monitor = Some(vec!("8.8.8.8".to_owned(), "1.1.1.1".to_owned()));
-        save_to_file = true;
     }
 
     if let None = config.max_timeouts {
@@ -50,27 +652,689 @@ pub fn config() -> Config {
         config.max_ping_timeout = Some(1000);
     }
 
+    if let None = config.degraded_loss_percent {
+        config.degraded_loss_percent = Some(10.0);
+    }
+
+    if let None = config.degraded_rtt {
+        config.degraded_rtt = Some(250);
+    }
+
+    if let None = config.degraded_window {
+        config.degraded_window = Some(20);
+    }
+
+    if let None = config.resolve_interval {
+        config.resolve_interval = Some(300);
+    }
+
+    if let None = config.log_format {
+        config.log_format = Some(LogFormat::Text);
+    }
+
+    if let None = config.log_keep {
+        config.log_keep = Some(5);
+    }
+
     if let None = config.db {
-        let rand_filename : String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(17)
-            .collect();
-        config.db = Some(rand_filename);
-        save_to_file = true;
+        config.db = Some("icc.db".to_owned());
+    }
+
+    if config.control_socket.is_none() && cfg!(unix) {
+        config.control_socket = Some("icc.sock".to_owned());
+    }
+
+    config.validate()?;
+
+    Ok(config)
+}
+
+fn env_override<T : FromStr>(key : &str) -> Result<Option<T>, ConfigError> {
+    match env::var(key) {
+        Ok(value) => value.trim().parse::<T>()
+            .map(Some)
+            .map_err(|_| ConfigError::invalid(key, &format!("unable to parse \"{}\"", value))),
+        Err(_) => Ok(None)
+    }
+}
+
+// Comma separated, e.g. ICC_ADDRESSES_TO_MONITOR="8.8.8.8,1.1.1.1"
+fn env_override_list(key : &str) -> Option<Vec<String>> {
+    env::var(key).ok().map(|value| value.split(',')
+        .map(|item| item.trim().to_owned())
+        .filter(|item| !item.is_empty())
+        .collect())
+}
+
+// Every key can be overridden by ICC_<KEY>. Targets and groups are given as a toml array,
+// e.g. ICC_GROUP='[{name = "dns", addresses = ["8.8.8.8"]}]'
+fn apply_env_overrides(config : &mut Config) -> Result<(), ConfigError> {
+    #[derive(Deserialize)]
+    struct Groups {
+        group: Vec<TargetGroup>
+    }
+
+    #[derive(Deserialize)]
+    struct Targets {
+        target: Vec<Target>
+    }
+
+    if let Some(value) = env_override("ICC_BIND_ADDRESS")? {
+        config.bind_address = Some(value);
+    }
+
+    if let Some(value) = env_override_list("ICC_ADDRESSES_TO_MONITOR") {
+        config.addresses_to_monitor = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_MAX_TIMEOUTS")? {
+        config.max_timeouts = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_MAX_PING_TIMEOUT")? {
+        config.max_ping_timeout = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_DB")? {
+        config.db = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_CONTROL_SOCKET")? {
+        config.control_socket = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_CLEAR_TEXT_LOG")? {
+        config.clear_text_log = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_LOG_FORMAT")? {
+        config.log_format = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_LOG_MAX_SIZE")? {
+        config.log_max_size = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_LOG_MAX_AGE")? {
+        config.log_max_age = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_LOG_KEEP")? {
+        config.log_keep = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_DEGRADED_LOSS_PERCENT")? {
+        config.degraded_loss_percent = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_DEGRADED_RTT")? {
+        config.degraded_rtt = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_DEGRADED_WINDOW")? {
+        config.degraded_window = Some(value);
     }
 
-    if save_to_file {
-        let payload = toml::to_string_pretty(&config).unwrap();
+    if let Some(value) = env_override("ICC_RESOLVE_INTERVAL")? {
+        config.resolve_interval = Some(value);
+    }
+
+    if let Some(value) = env_override("ICC_WEBHOOK")? {
+        config.webhook = Some(value);
+    }
+
+    if let Ok(value) = env::var("ICC_TARGET") {
+        let targets : Targets = toml::from_str(&format!("target = {}", value))
+            .map_err(|e| ConfigError::invalid("ICC_TARGET", &e.to_string()))?;
+        config.targets = Some(targets.target);
+    }
+
+    if let Ok(value) = env::var("ICC_GROUP") {
+        let groups : Groups = toml::from_str(&format!("group = {}", value))
+            .map_err(|e| ConfigError::invalid("ICC_GROUP", &e.to_string()))?;
+        config.groups = Some(groups.group);
+    }
+
+    Ok(())
+}
+
+// Adds a [[target]] to the config file, which is created if it doesn't exist.
+// The file is rewritten, so comments in it are lost. A running icc picks the change up like any other edit.
+pub fn add_target_to_file(path : &Path, target : &Target) -> Result<(), ConfigError> {
+    let mut document = read_document(path)?;
 
-        let mut config_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open("config.toml").unwrap();
+    let mut table = Table::new();
+    table.insert("name".to_owned(), Value::String(target.name.clone()));
+    table.insert("address".to_owned(), Value::String(target.address.clone()));
+    if let Some(group) = &target.group {
+        table.insert("group".to_owned(), Value::String(group.clone()));
+    }
+    if let Some(interval) = target.interval {
+        table.insert("interval".to_owned(), Value::Integer(interval as i64));
+    }
+    if let Some(timeout) = target.timeout {
+        table.insert("timeout".to_owned(), Value::Integer(timeout as i64));
+    }
+
+    match document.entry("target".to_owned()).or_insert_with(|| Value::Array(Vec::new())) {
+        Value::Array(targets) => targets.push(Value::Table(table)),
+        _ => return Err(ConfigError::invalid("target", "has to be an array of tables"))
+    }
 
-        config_file.write_all(payload.as_bytes());
+    write_document(path, document)
+}
+
+// Removes a target from [[target]], addresses_to_monitor and the groups, along with groups left without addresses.
+// Returns whether the config file had the target, the file is left alone if it didn't.
+pub fn remove_target_from_file(path : &Path, name : &str) -> Result<bool, ConfigError> {
+    let mut document = read_document(path)?;
+    // Groups may refer to a [[target]] by its address
+    let mut references = vec!(name.to_owned());
+    let mut removed = false;
+
+    let is_reference = |references : &[String], value : &Value| value.as_str().map_or(false, |value| references.iter().any(|reference| reference == value));
+
+    if let Some(Value::Array(targets)) = document.get_mut("target") {
+        let (matching, kept) : (Vec<Value>, Vec<Value>) = targets.drain(..)
+            .partition(|target| target.get("name").and_then(Value::as_str) == Some(name));
+        *targets = kept;
+        for target in matching {
+            references.extend(target.get("address").and_then(Value::as_str).map(str::to_owned));
+            removed = true;
+        }
     }
 
-    config
-}
\ No newline at end of file
+    if let Some(Value::Array(addresses)) = document.get_mut("addresses_to_monitor") {
+        let before = addresses.len();
+        addresses.retain(|address| !is_reference(&references, address));
+        removed |= addresses.len() != before;
+    }
+
+    if let Some(Value::Array(groups)) = document.get_mut("group") {
+        for group in groups.iter_mut() {
+            if let Some(Value::Array(addresses)) = group.get_mut("addresses") {
+                let before = addresses.len();
+                addresses.retain(|address| !is_reference(&references, address));
+                removed |= addresses.len() != before;
+            }
+        }
+    }
+
+    // Groups that are left without members, unless targets join them with their group key
+    let joined : Vec<String> = match document.get("target") {
+        Some(Value::Array(targets)) => targets.iter().filter_map(|target| target.get("group").and_then(Value::as_str)).map(str::to_owned).collect(),
+        _ => Vec::new()
+    };
+    if let Some(Value::Array(groups)) = document.get_mut("group") {
+        groups.retain(|group| {
+            let has_addresses = group.get("addresses").and_then(Value::as_array).map_or(false, |addresses| !addresses.is_empty());
+            has_addresses || group.get("name").and_then(Value::as_str).map_or(false, |name| joined.iter().any(|joined| joined == name))
+        });
+    }
+
+    if removed {
+        write_document(path, document)?;
+    }
+    Ok(removed)
+}
+
+// The config file as toml, empty if it doesn't exist
+fn read_document(path : &Path) -> Result<Table, ConfigError> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Table::new()),
+        Err(e) => return Err(ConfigError::Io { path: path.to_owned(), error: e })
+    };
+    toml::from_slice(&buf).map_err(|e| ConfigError::Parse { path: path.to_owned(), error: e })
+}
+
+// Validated first, so a change that makes the config invalid isn't written. Replaces the file in one go, so a running icc never reads half of it.
+fn write_document(path : &Path, document : Table) -> Result<(), ConfigError> {
+    let write_error = |e : io::Error| ConfigError::Write { path: path.to_owned(), error: e };
+    let text = toml::to_string(&Value::Table(document)).map_err(|e| write_error(io::Error::new(io::ErrorKind::InvalidData, e.to_string())))?;
+
+    // Read back from the text, a toml::Value can't be turned into the policy enum
+    let config : Config = toml::from_str(&text).map_err(|e| ConfigError::Parse { path: path.to_owned(), error: e })?;
+    config.validate()?;
+
+    let mut temporary = path.to_owned().into_os_string();
+    temporary.push(".tmp");
+    fs::write(&temporary, text).map_err(write_error)?;
+    fs::rename(&temporary, path).map_err(write_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_turns_degraded_thresholds_off() {
+        let mut config : Config = toml::from_str("degraded_loss_percent = 0.0\ndegraded_rtt = 0").unwrap();
+        config.degraded_window = Some(5);
+        let thresholds = config.degraded_thresholds();
+        assert_eq!(thresholds.loss_percent, None);
+        assert_eq!(thresholds.rtt, None);
+        assert_eq!(thresholds.window, 5);
+
+        config.degraded_loss_percent = Some(2.5);
+        config.degraded_rtt = Some(100);
+        let thresholds = config.degraded_thresholds();
+        assert_eq!(thresholds.loss_percent, Some(2.5));
+        assert_eq!(thresholds.rtt, Some(Duration::from_millis(100)));
+    }
+
+    fn parse(text : &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    fn groups(config : &Config) -> Vec<(String, Vec<String>, DetectionPolicy)> {
+        config.target_groups().into_iter().map(|group| (group.name, group.addresses, group.policy)).collect()
+    }
+
+    fn names(names : &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    const GROUPS : &str = r#"
+addresses_to_monitor = ["9.9.9.9", "8.8.4.4"]
+
+[[target]]
+name = "router"
+address = "192.168.1.1"
+group = "gateway"
+
+[[target]]
+name = "isp-dns"
+address = "8.8.8.8"
+group = "isp"
+
+[[target]]
+name = "cf"
+address = "1.1.1.1"
+
+[[group]]
+name = "isp"
+policy = { at_least = 1 }
+
+[[group]]
+name = "internet"
+addresses = ["1.1.1.1", "8.8.4.4"]
+policy = "majority"
+"#;
+
+    #[test]
+    fn merges_targets_groups_and_addresses() {
+        let config = parse(GROUPS);
+        config.validate().unwrap();
+        assert_eq!(groups(&config), vec![
+            // Joined by its target, the [[group]] only sets the policy
+            ("isp".to_owned(), names(&["isp-dns"]), DetectionPolicy::AtLeast(1)),
+            // [[target]] by address, and a plain address
+            ("internet".to_owned(), names(&["cf", "8.8.4.4"]), DetectionPolicy::Majority),
+            // Created by the target's group
+            ("gateway".to_owned(), names(&["router"]), DetectionPolicy::All),
+            ("default".to_owned(), names(&["9.9.9.9"]), DetectionPolicy::All),
+        ]);
+
+        let targets : Vec<String> = config.targets().into_iter().map(|target| target.name).collect();
+        assert_eq!(targets, names(&["router", "isp-dns", "cf", "9.9.9.9", "8.8.4.4"]));
+    }
+
+    #[test]
+    fn validates_groups_by_their_members() {
+        let invalid = |text : &str| match parse(text).validate() {
+            Err(ConfigError::Invalid {key, ..}) => key,
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("{} is valid", text)
+        };
+
+        // at_least counts the targets that joined the group
+        let mut config = parse(GROUPS);
+        config.targets.as_mut().unwrap()[2].group = Some("isp".to_owned());
+        config.groups.as_mut().unwrap()[0].policy = DetectionPolicy::AtLeast(2);
+        config.validate().unwrap();
+        config.groups.as_mut().unwrap()[0].policy = DetectionPolicy::AtLeast(3);
+        assert!(config.validate().is_err());
+
+        assert_eq!(invalid("[[group]]\nname = \"isp\""), "group.isp.addresses");
+        assert_eq!(invalid("[[group]]\nname = \"dns\"\naddresses = [\"8.8.8.8\"]\npolicy = { at_least = 2 }"), "group.dns.policy");
+    }
+
+    #[test]
+    fn removing_a_target_keeps_groups_with_members() {
+        let config = parse(GROUPS);
+        let config = config.without_target("cf").unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.groups.as_ref().unwrap().len(), 2);
+
+        // The isp group has no members left
+        let config = config.without_target("isp-dns").unwrap();
+        config.validate().unwrap();
+        let remaining : Vec<&str> = config.groups.iter().flatten().map(|group| group.name.as_str()).collect();
+        assert_eq!(remaining, vec!["internet"]);
+    }
+
+    #[test]
+    fn removing_a_target_from_the_file_keeps_joined_groups() {
+        let path = env::temp_dir().join(format!("icc-config-groups-{}.toml", std::process::id()));
+        fs::write(&path, GROUPS).unwrap();
+
+        assert!(remove_target_from_file(&path, "cf").unwrap());
+        let config : Config = toml::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let remaining : Vec<&str> = config.groups.iter().flatten().map(|group| group.name.as_str()).collect();
+        assert_eq!(remaining, vec!["isp", "internet"]);
+
+        assert!(remove_target_from_file(&path, "isp-dns").unwrap());
+        let config : Config = toml::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let remaining : Vec<&str> = config.groups.iter().flatten().map(|group| group.name.as_str()).collect();
+        assert_eq!(remaining, vec!["internet"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changes_the_file_with_group_policies() {
+        let path = env::temp_dir().join(format!("icc-config-policy-{}.toml", std::process::id()));
+        fs::write(&path, "[[group]]\nname = \"dns\"\naddresses = [\"8.8.8.8\", \"1.1.1.1\"]\npolicy = \"majority\"\n\
+            [[group]]\nname = \"isp\"\naddresses = [\"192.0.2.1\", \"192.0.2.2\"]\npolicy = { at_least = 2 }\n").unwrap();
+
+        let mut target = Target::from_address("9.9.9.9");
+        target.name = "quad9".to_owned();
+        target.group = Some("dns".to_owned());
+        add_target_to_file(&path, &target).unwrap();
+        assert!(remove_target_from_file(&path, "8.8.8.8").unwrap());
+
+        let config : Config = toml::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let groups : Vec<(String, Vec<String>, DetectionPolicy)> = config.target_groups().into_iter().map(|group| (group.name, group.addresses, group.policy)).collect();
+        assert_eq!(groups, vec![
+            ("dns".to_owned(), vec!["1.1.1.1".to_owned(), "quad9".to_owned()], DetectionPolicy::Majority),
+            ("isp".to_owned(), vec!["192.0.2.1".to_owned(), "192.0.2.2".to_owned()], DetectionPolicy::AtLeast(2)),
+        ]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_policies_written_as_tables() {
+        let config = parse("[[group]]\nname = \"dns\"\naddresses = [\"8.8.8.8\"]\n[group.policy]\nat_least = 1\n");
+        assert_eq!(config.groups.unwrap()[0].policy, DetectionPolicy::AtLeast(1));
+
+        for invalid in &["policy = \"most\"", "policy = { at_most = 1 }", "policy = {}", "policy = 1"] {
+            assert!(toml::from_str::<Config>(&format!("[[group]]\nname = \"dns\"\n{}", invalid)).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let target = |fields : &str| format!("[[target]]\nname = \"cf\"\naddress = \"1.1.1.1\"\n{}", fields);
+        let cases = vec![
+            ("bind_address = \"4017\"".to_owned(), "bind_address"),
+            ("addresses_to_monitor = [\"not an address\"]".to_owned(), "addresses_to_monitor"),
+            ("control_socket = \"\"".to_owned(), "control_socket"),
+            ("max_timeouts = 0".to_owned(), "max_timeouts"),
+            ("max_ping_timeout = 0".to_owned(), "max_ping_timeout"),
+            ("degraded_loss_percent = 100.5".to_owned(), "degraded_loss_percent"),
+            ("degraded_loss_percent = -1.0".to_owned(), "degraded_loss_percent"),
+            ("degraded_window = 0".to_owned(), "degraded_window"),
+            ("resolve_interval = 0".to_owned(), "resolve_interval"),
+            ("log_max_size = 0".to_owned(), "log_max_size"),
+            ("log_max_age = 0".to_owned(), "log_max_age"),
+            ("log_keep = 0".to_owned(), "log_keep"),
+            ("webhook = \"https://example.com/hook\"".to_owned(), "webhook"),
+            ("[[target]]\nname = \"\"\naddress = \"1.1.1.1\"".to_owned(), "target[0].name"),
+            (format!("{}\n{}", target(""), target("")), "target.cf.name"),
+            ("[[target]]\nname = \"cf\"\naddress = \"1.1.1.1:53\"".to_owned(), "target.cf.address"),
+            (target("interval = 0"), "target.cf.interval"),
+            (target("timeout = 0"), "target.cf.timeout"),
+            (target("group = \"\""), "target.cf.group"),
+            ("[[group]]\nname = \"\"\naddresses = [\"1.1.1.1\"]".to_owned(), "group[0].name"),
+            ("[[group]]\nname = \"dns\"\naddresses = [\"1.1.1.1\"]\n[[group]]\nname = \"dns\"\naddresses = [\"8.8.8.8\"]".to_owned(), "group.dns.name"),
+            ("[[group]]\nname = \"dns\"\naddresses = [\"not a target\"]".to_owned(), "group.dns.addresses"),
+            ("[[group]]\nname = \"dns\"\naddresses = [\"1.1.1.1\"]\npolicy = { at_least = 0 }".to_owned(), "group.dns.policy"),
+        ];
+
+        for (text, expected) in cases {
+            match parse(&text).validate() {
+                Err(ConfigError::Invalid {key, ..}) => assert_eq!(key, expected, "{}", text),
+                Err(e) => panic!("{}: {}", text, e),
+                Ok(_) => panic!("{} is valid", text)
+            }
+        }
+
+        parse(&target("group = \"dns\"\ninterval = 5\ntimeout = 500")).validate().unwrap();
+    }
+
+    // Tests that read or change environment variables hold this, as the environment is shared by all test threads
+    static ENV_LOCK : std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    const ENV_KEYS : &[&str] = &[CONFIG_ENV, "XDG_CONFIG_HOME", "HOME", "ICC_MAX_TIMEOUTS", "ICC_ADDRESSES_TO_MONITOR", "ICC_DB", "ICC_GROUP", "ICC_LOG_FORMAT"];
+
+    // Runs f with only the given variables of ENV_KEYS set, then puts the environment back
+    fn with_env<F : FnOnce()>(vars : &[(&str, &str)], f : F) {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let saved : Vec<(&str, Option<std::ffi::OsString>)> = ENV_KEYS.iter().map(|key| (*key, env::var_os(key))).collect();
+        for key in ENV_KEYS {
+            env::remove_var(key);
+        }
+        for (key, value) in vars {
+            env::set_var(key, value);
+        }
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+
+        for (key, value) in saved {
+            match value {
+                Some(value) => env::set_var(key, value),
+                None => env::remove_var(key)
+            }
+        }
+        if let Err(panic) = result {
+            std::panic::resume_unwind(panic);
+        }
+    }
+
+    fn temp_dir(name : &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("icc-config-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reports_unreadable_and_invalid_files() {
+        let dir = temp_dir("errors");
+        let invalid = dir.join("invalid.toml");
+        fs::write(&invalid, "max_timeouts = \"three\"").unwrap();
+        let zero = dir.join("zero.toml");
+        fs::write(&zero, "max_timeouts = 0").unwrap();
+
+        with_env(&[], || {
+            match config_from(Some(&dir.join("missing.toml"))) {
+                Err(ConfigError::Io {path, ..}) => assert_eq!(path, dir.join("missing.toml")),
+                _ => panic!("expected an io error")
+            }
+            match config_from(Some(&invalid)) {
+                Err(ConfigError::Parse {path, ..}) => assert_eq!(path, invalid),
+                _ => panic!("expected a parse error")
+            }
+            match config_from(Some(&zero)) {
+                Err(ConfigError::Invalid {key, ..}) => assert_eq!(key, "max_timeouts"),
+                _ => panic!("expected an invalid value")
+            }
+        });
+
+        // The directory the file would be written to doesn't exist
+        let unwritable = dir.join("missing").join("config.toml");
+        match add_target_to_file(&unwritable, &Target::from_address("1.1.1.1")) {
+            Err(ConfigError::Write {path, ..}) => assert_eq!(path, unwritable),
+            _ => panic!("expected a write error")
+        }
+        assert!(!unwritable.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn searches_for_the_config_file_in_order() {
+        let dir = temp_dir("search");
+        let xdg = dir.join("xdg");
+        let home = dir.join("home");
+
+        with_env(&[("XDG_CONFIG_HOME", xdg.to_str().unwrap()), ("HOME", home.to_str().unwrap())], || {
+            let paths = config_search_paths();
+            assert_eq!(paths[0], PathBuf::from("config.toml"));
+            assert_eq!(paths[1], xdg.join("icc").join("config.toml"));
+            assert_eq!(paths.len(), if cfg!(unix) { 3 } else { 2 });
+
+            // XDG_CONFIG_HOME is used instead of HOME, and only found once it exists
+            fs::create_dir_all(home.join(".config").join("icc")).unwrap();
+            fs::write(home.join(".config").join("icc").join("config.toml"), "").unwrap();
+            assert_ne!(find_config_file(None), Some(home.join(".config").join("icc").join("config.toml")));
+            fs::create_dir_all(xdg.join("icc")).unwrap();
+            fs::write(xdg.join("icc").join("config.toml"), "").unwrap();
+            assert_eq!(find_config_file(None), Some(xdg.join("icc").join("config.toml")));
+
+            // ICC_CONFIG comes before the search paths, --config before ICC_CONFIG. Neither has to exist.
+            env::set_var(CONFIG_ENV, dir.join("env.toml"));
+            assert_eq!(find_config_file(None), Some(dir.join("env.toml")));
+            assert_eq!(find_config_file(Some(dir.join("arg.toml"))), Some(dir.join("arg.toml")));
+        });
+
+        with_env(&[("HOME", home.to_str().unwrap())], || {
+            assert_eq!(config_search_paths()[1], home.join(".config").join("icc").join("config.toml"));
+            assert_eq!(find_config_file(None), Some(home.join(".config").join("icc").join("config.toml")));
+        });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let dir = temp_dir("env");
+        let path = dir.join("config.toml");
+        fs::write(&path, "addresses_to_monitor = [\"8.8.8.8\"]\nmax_timeouts = 5\ndb = \"file.db\"\nlog_format = \"json\"\n\
+            [[group]]\nname = \"dns\"\naddresses = [\"8.8.8.8\"]\n").unwrap();
+
+        with_env(&[], || {
+            let config = config_from(Some(&path)).unwrap();
+            assert_eq!(config.max_timeouts, Some(5));
+            assert_eq!(config.db.as_ref().map(|db| db.as_str()), Some("file.db"));
+            // Defaults fill in what neither sets
+            assert_eq!(config.max_ping_timeout, Some(1000));
+        });
+
+        with_env(&[
+            ("ICC_MAX_TIMEOUTS", " 7 "),
+            ("ICC_ADDRESSES_TO_MONITOR", "9.9.9.9, 1.0.0.1,"),
+            ("ICC_LOG_FORMAT", "logfmt"),
+            ("ICC_GROUP", "[{name = \"quad9\", addresses = [\"9.9.9.9\"], policy = \"majority\"}]"),
+        ], || {
+            let config = config_from(Some(&path)).unwrap();
+            assert_eq!(config.max_timeouts, Some(7));
+            assert_eq!(config.addresses_to_monitor, Some(names(&["9.9.9.9", "1.0.0.1"])));
+            assert_eq!(config.log_format.map(|format| format.as_str()), Some("logfmt"));
+            assert_eq!(groups(&config)[0], ("quad9".to_owned(), names(&["9.9.9.9"]), DetectionPolicy::Majority));
+            assert_eq!(config.groups.as_ref().unwrap().len(), 1);
+            // Not overridden
+            assert_eq!(config.db.as_ref().map(|db| db.as_str()), Some("file.db"));
+        });
+
+        // Overrides are validated like the file
+        let cases = [
+            ("ICC_MAX_TIMEOUTS", "seven", "ICC_MAX_TIMEOUTS"),
+            ("ICC_MAX_TIMEOUTS", "0", "max_timeouts"),
+            ("ICC_GROUP", "{name = \"dns\"}", "ICC_GROUP"),
+        ];
+        for (key, value, expected) in &cases {
+            with_env(&[(key, value)], || {
+                match config_from(Some(&path)) {
+                    Err(ConfigError::Invalid {key, ..}) => assert_eq!(&key, expected),
+                    _ => panic!("{} is valid", value)
+                }
+            });
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finds_reloadable_changes() {
+        let running = parse(GROUPS);
+
+        let unchanged = running.changes(&parse(GROUPS));
+        assert!(unchanged.added_targets.is_empty() && unchanged.removed_targets.is_empty());
+        assert!(!unchanged.detection && !unchanged.clear_text_log);
+        assert_eq!(unchanged.max_ping_timeout, None);
+        assert!(unchanged.restart_required.is_empty());
+
+        let added = running.changes(&parse(&format!("{}\n[[target]]\nname = \"quad9\"\naddress = \"149.112.112.112\"\n", GROUPS)));
+        let added_names : Vec<&str> = added.added_targets.iter().map(|target| target.name.as_str()).collect();
+        assert_eq!(added_names, vec!["quad9"]);
+        assert!(added.removed_targets.is_empty());
+        assert!(added.detection);
+
+        let removed = running.changes(&running.without_target("router").unwrap());
+        let removed_names : Vec<&str> = removed.removed_targets.iter().map(|target| target.name.as_str()).collect();
+        assert_eq!(removed_names, vec!["router"]);
+        assert!(removed.added_targets.is_empty());
+        assert!(removed.detection);
+
+        // A changed target is removed and added again
+        let mut new = running.clone();
+        new.targets.as_mut().unwrap()[2].timeout = Some(500);
+        let changed = running.changes(&new);
+        assert_eq!(changed.removed_targets.len(), 1);
+        assert_eq!(changed.added_targets[0].timeout, Some(500));
+
+        // Only the detector has to know about a changed policy
+        let mut new = running.clone();
+        new.groups.as_mut().unwrap()[1].policy = DetectionPolicy::All;
+        let policy = running.changes(&new);
+        assert!(policy.detection);
+        assert!(policy.added_targets.is_empty() && policy.removed_targets.is_empty());
+        assert!(policy.restart_required.is_empty());
+
+        let mut new = running.clone();
+        new.bind_address = Some("127.0.0.1:4017".to_owned());
+        new.control_socket = Some("other.sock".to_owned());
+        new.max_ping_timeout = Some(500);
+        new.log_keep = Some(2);
+        let restart = running.changes(&new);
+        assert_eq!(restart.restart_required, vec!["bind_address", "control_socket"]);
+        assert_eq!(restart.max_ping_timeout, Some(500));
+        assert!(restart.clear_text_log);
+        assert!(!restart.detection);
+    }
+
+    #[test]
+    fn reloads_on_hangup_and_modification() {
+        let dir = temp_dir("watcher");
+        let path = dir.join("config.toml");
+        fs::write(&path, "max_timeouts = 3").unwrap();
+        let mut watcher = ConfigWatcher::new(Some(path.clone()));
+        let checked_long_ago = || Instant::now() - ConfigWatcher::INTERVAL * 2;
+
+        assert!(!watcher.should_reload());
+        watcher.hangup.store(true, Ordering::Relaxed);
+        assert!(watcher.should_reload());
+        assert!(!watcher.should_reload());
+
+        // The file is only looked at once INTERVAL has passed
+        fs::remove_file(&path).unwrap();
+        assert!(!watcher.should_reload());
+        watcher.last_check = checked_long_ago();
+        assert!(watcher.should_reload());
+        watcher.last_check = checked_long_ago();
+        assert!(!watcher.should_reload());
+
+        fs::write(&path, "max_timeouts = 4").unwrap();
+        watcher.last_check = checked_long_ago();
+        assert!(watcher.should_reload());
+
+        // Without a file only SIGHUP reloads
+        let mut watcher = ConfigWatcher::new(None);
+        watcher.last_check = checked_long_ago();
+        assert!(!watcher.should_reload());
+        watcher.hangup.store(true, Ordering::Relaxed);
+        assert!(watcher.should_reload());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,33 +1,347 @@
 extern crate rusqlite;
 
-use rusqlite::{Connection, Result, NO_PARAMS, Statement, OpenFlags};
+use rusqlite::{Connection, NO_PARAMS, Row, Statement, OpenFlags};
+use rusqlite::types::ToSql;
+use crate::ping::model::{ConnectivityDown, ConnectivityDegraded, duration_millis};
+use crate::error::Error;
+
+// Schema changes, applied in order. PRAGMA user_version holds how many have been applied to a database.
+// Timestamps are milliseconds since the unix epoch (UTC), durations are milliseconds measured on a monotonic clock.
+const MIGRATIONS : &[&str] = &[
+    "create table if not exists current_downtime (
+        id integer primary key,
+        start integer,
+        end integer
+    );
+    create table if not exists degraded (
+        id integer primary key,
+        target text,
+        start integer,
+        end integer,
+        loss_percent real,
+        rtt integer
+    );",
+    // Seconds to milliseconds, plus monotonic durations. Existing rows only have the wall clock difference.
+    "alter table current_downtime add column duration integer;
+    update current_downtime set start = start * 1000, end = end * 1000, duration = (end - start) * 1000;
+    alter table degraded add column duration integer;
+    update degraded set start = start * 1000, end = end * 1000, duration = (end - start) * 1000;",
+    // How much earlier than start/end the outage may have begun/ended, in milliseconds. NULL when unknown.
+    "alter table current_downtime add column start_uncertainty integer;
+    alter table current_downtime add column end_uncertainty integer;",
+    // Where the outage was: "gateway", "isp", "internet" or "unknown". NULL for outages recorded before this was known.
+    "alter table current_downtime add column location text;",
+    // Traceroutes towards the failing targets when the outage started, a JSON array of {target, destination, hops: [{ttl, address, rtt}]}
+    "alter table current_downtime add column traceroute text;",
+    // 1 if icc was stopped during the outage, end is then when icc stopped
+    "alter table current_downtime add column interrupted integer not null default 0;",
+    // When icc last ran, and the periods it wasn't monitoring: "not_running" or "suspended". Durations are wall clock differences.
+    "create table if not exists heartbeat (
+        id integer primary key check (id = 1),
+        time integer
+    );
+    create table if not exists unmonitored (
+        id integer primary key,
+        start integer,
+        end integer,
+        duration integer,
+        reason text
+    );",
+    // When monitoring began, reports don't count the time before it. Databases from before the heartbeat began with their first downtime.
+    "alter table heartbeat add column first integer;
+    update heartbeat set first = min(time, coalesce((select min(start) from current_downtime), time));",
+    // The group that was down and its failing targets, comma separated. NULL for outages recorded before groups were stored.
+    "alter table current_downtime add column \"group\" text;
+    alter table current_downtime add column targets text;",
+];
+
+const DOWNTIME_COLUMNS : &str = "start, end, duration, start_uncertainty, end_uncertainty, location, traceroute, interrupted, \"group\", targets";
+
+pub struct DowntimeRecord {
+    pub start : i64,
+    pub end : i64,
+    pub duration : i64,
+    pub start_uncertainty : Option<i64>,
+    pub end_uncertainty : Option<i64>,
+    pub location : Option<String>,
+    pub traceroute : Option<String>,
+    pub interrupted : bool,
+    pub group : Option<String>,
+    pub targets : Vec<String>,
+}
+
+pub struct DegradedRecord {
+    pub target : String,
+    pub start : i64,
+    pub end : i64,
+    pub duration : i64,
+    pub loss_percent : f64,
+    // Worst average round-trip time in milliseconds
+    pub rtt : Option<i64>,
+}
+
+// A period icc wasn't monitoring, so neither up nor down
+pub struct UnmonitoredRecord {
+    pub start : i64,
+    pub end : i64,
+    pub duration : i64,
+    pub reason : String,
+}
+
+impl UnmonitoredRecord {
+    pub fn new(start : i64, end : i64, reason : &str) -> Self {
+        Self {start: start, end: end, duration: end - start, reason: reason.to_owned()}
+    }
+}
 
 pub struct Db {
     pub conn : Connection
 }
 
 impl Db {
-    pub fn new(filename : &str) -> Self {
+    pub fn new(filename : &str) -> Result<Self, Error> {
         //let conn = Connection::open("data").unwrap();
-        let conn = Connection::open_with_flags(filename, OpenFlags::SQLITE_OPEN_READ_WRITE
+        let mut conn = Connection::open_with_flags(filename, OpenFlags::SQLITE_OPEN_READ_WRITE
             | OpenFlags::SQLITE_OPEN_CREATE
             | OpenFlags::SQLITE_OPEN_SHARED_CACHE
             | OpenFlags::SQLITE_OPEN_NO_MUTEX
-            | OpenFlags::SQLITE_OPEN_URI).unwrap();
+            | OpenFlags::SQLITE_OPEN_URI)?;
+
+        migrate(&mut conn)?;
+        Ok(Self {conn: conn})
+    }
+
+    pub fn insert_current_downtime(&self, cd : &ConnectivityDown) -> Result<(), Error> {
+        let mut insert_current_downtime : Statement = self.conn.prepare(&format!("INSERT INTO current_downtime ({}) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)", DOWNTIME_COLUMNS))?;
+
+        let traceroute : Option<String> = serde_json::to_string(cd.traces()).ok();
+
+        insert_current_downtime.execute(&[&cd.start_epoch_millis() as &ToSql, &cd.end_epoch_millis(), &cd.duration_millis(),
+            &cd.start_uncertainty().map(duration_millis), &cd.end_uncertainty().map(duration_millis), &cd.location_text(), &traceroute, &cd.is_interrupted(),
+            &cd.group(), &targets_text(cd.targets())])?;
+        Ok(())
+    }
+
+    // Inserts a downtime read from elsewhere, unless one of the same group starting and ending within a second of it is stored already.
+    // Downtimes without a group, from before groups were stored, match any group. Returns whether it was inserted.
+    pub fn import_downtime(&self, record : &DowntimeRecord) -> Result<bool, Error> {
+        let mut insert_downtime : Statement = self.conn.prepare(&format!("INSERT INTO current_downtime ({}) SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10
+            WHERE NOT EXISTS (SELECT 1 FROM current_downtime WHERE abs(start - ?1) < 1000 AND abs(end - ?2) < 1000
+                AND (\"group\" IS ?9 OR \"group\" IS NULL OR ?9 IS NULL))", DOWNTIME_COLUMNS))?;
+
+        let inserted = insert_downtime.execute(&[&record.start as &ToSql, &record.end, &record.duration,
+            &record.start_uncertainty, &record.end_uncertainty, &record.location, &record.traceroute, &record.interrupted,
+            &record.group, &targets_text(&record.targets)])?;
+        Ok(inserted > 0)
+    }
+
+    pub fn insert_degraded(&self, degraded : &ConnectivityDegraded) -> Result<(), Error> {
+        let mut insert_degraded : Statement = self.conn.prepare("INSERT INTO degraded (target, start, end, duration, loss_percent, rtt) values (?1, ?2, ?3, ?4, ?5, ?6)")?;
+
+        let rtt : Option<i64> = degraded.rtt().map(duration_millis);
+        insert_degraded.execute(&[&degraded.target() as &ToSql, &degraded.start_epoch_millis(), &degraded.end_epoch_millis(), &degraded.duration_millis(), &degraded.loss_percent(), &rtt])?;
+        Ok(())
+    }
+
+    pub fn heartbeat(&self, epoch_millis : i64) -> Result<(), Error> {
+        self.conn.execute("INSERT OR IGNORE INTO heartbeat (id, time, first) values (1, ?1, min(?1, coalesce((select min(start) from current_downtime), ?1)))", &[&epoch_millis])?;
+        self.conn.execute("UPDATE heartbeat SET time = ?1 WHERE id = 1", &[&epoch_millis])?;
+        Ok(())
+    }
+
+    // When icc first ran with this database, None if it never did
+    pub fn monitored_since(&self) -> Result<Option<i64>, Error> {
+        match self.conn.query_row("SELECT first FROM heartbeat WHERE id = 1", NO_PARAMS, |row| row.get(0)) {
+            Ok(first) => Ok(first),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    // None if icc never ran with this database
+    pub fn last_heartbeat(&self) -> Result<Option<i64>, Error> {
+        match self.conn.query_row("SELECT time FROM heartbeat WHERE id = 1", NO_PARAMS, |row| row.get(0)) {
+            Ok(time) => Ok(Some(time)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub fn insert_unmonitored(&self, record : &UnmonitoredRecord) -> Result<(), Error> {
+        let mut insert_unmonitored : Statement = self.conn.prepare("INSERT INTO unmonitored (start, end, duration, reason) values (?1, ?2, ?3, ?4)")?;
+
+        insert_unmonitored.execute(&[&record.start as &ToSql, &record.end, &record.duration, &record.reason])?;
+        Ok(())
+    }
+
+    pub fn latest_unmonitored(&self, limit : u32) -> Result<Vec<UnmonitoredRecord>, Error> {
+        let mut select : Statement = self.conn.prepare("SELECT start, end, duration, reason FROM unmonitored ORDER BY start DESC LIMIT ?1")?;
+
+        let rows = select.query_map(&[&limit], unmonitored_record)?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    // Unmonitored periods overlapping from - to, oldest first
+    pub fn unmonitored_between(&self, from : i64, to : i64) -> Result<Vec<UnmonitoredRecord>, Error> {
+        let mut select : Statement = self.conn.prepare("SELECT start, end, duration, reason FROM unmonitored WHERE end > ?1 AND start < ?2 ORDER BY start")?;
 
-        conn.execute("create table if not exists current_downtime (\
-                            id integer primary key,\
-                            start integer,\
-                            end integer\
-                       )",
-                     NO_PARAMS).unwrap();
+        let rows = select.query_map(&[&from, &to], unmonitored_record)?;
 
-        Self {conn: conn}
+        Ok(rows.collect::<Result<_, _>>()?)
     }
 
-    pub fn insert_current_downtime(&self, start : i64, end : i64) {
-        let mut insert_current_downtime : Statement = self.conn.prepare("INSERT INTO current_downtime (start, end) values (?1, ?2)").unwrap();
+    pub fn latest_downtimes(&self, limit : u32) -> Result<Vec<DowntimeRecord>, Error> {
+        let mut select : Statement = self.conn.prepare(&format!("SELECT {} FROM current_downtime ORDER BY start DESC LIMIT ?1", DOWNTIME_COLUMNS))?;
 
-        insert_current_downtime.execute(&[&start, &end]);
+        let rows = select.query_map(&[&limit], downtime_record)?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    // Downtimes overlapping from - to, oldest first
+    pub fn downtimes_between(&self, from : i64, to : i64) -> Result<Vec<DowntimeRecord>, Error> {
+        let mut select : Statement = self.conn.prepare(&format!("SELECT {} FROM current_downtime WHERE end > ?1 AND start < ?2 ORDER BY start", DOWNTIME_COLUMNS))?;
+
+        let rows = select.query_map(&[&from, &to], downtime_record)?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
     }
-}
\ No newline at end of file
+
+    pub fn latest_degraded(&self, limit : u32) -> Result<Vec<DegradedRecord>, Error> {
+        let mut select : Statement = self.conn.prepare("SELECT target, start, end, duration, loss_percent, rtt FROM degraded ORDER BY start DESC LIMIT ?1")?;
+
+        let rows = select.query_map(&[&limit], |row| DegradedRecord {
+            target: row.get(0),
+            start: row.get(1),
+            end: row.get(2),
+            duration: row.get(3),
+            loss_percent: row.get(4),
+            rtt: row.get(5),
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+// Applies the migrations a database doesn't have yet. Each one is committed together with its user_version,
+// so a migration that was interrupted is rolled back and runs again in full, rather than converting rows twice.
+fn migrate(conn : &mut Connection) -> Result<(), Error> {
+    let version : i64 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let transaction = conn.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.execute_batch(&format!("PRAGMA user_version = {}", index + 1))?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+fn downtime_record(row : &Row) -> DowntimeRecord {
+    DowntimeRecord {
+        start: row.get(0),
+        end: row.get(1),
+        duration: row.get(2),
+        start_uncertainty: row.get(3),
+        end_uncertainty: row.get(4),
+        location: row.get(5),
+        traceroute: row.get(6),
+        interrupted: row.get(7),
+        group: row.get(8),
+        targets: row.get::<_, Option<String>>(9).map_or(Vec::new(), |targets| targets.split(',').map(str::to_owned).collect()),
+    }
+}
+
+// NULL without targets
+fn targets_text(targets : &[String]) -> Option<String> {
+    if targets.is_empty() {
+        None
+    } else {
+        Some(targets.join(","))
+    }
+}
+
+fn unmonitored_record(row : &Row) -> UnmonitoredRecord {
+    UnmonitoredRecord {
+        start: row.get(0),
+        end: row.get(1),
+        duration: row.get(2),
+        reason: row.get(3),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{Duration, Instant};
+    use crate::ping::model::Timestamp;
+
+    fn user_version(conn : &Connection) -> i64 {
+        conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn migrates_second_timestamps_once() {
+        let path = std::env::temp_dir().join(format!("icc-migration-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        {
+            // A database from before timestamps were milliseconds
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(MIGRATIONS[0]).unwrap();
+            conn.execute_batch("PRAGMA user_version = 1;
+                insert into current_downtime (start, end) values (1551557643, 1551557703);
+                insert into degraded (target, start, end, loss_percent, rtt) values ('8.8.8.8', 1551557643, 1551557653, 20.0, 300);").unwrap();
+        }
+
+        for _ in 0..2 {
+            let db = Db::new(path.to_str().unwrap()).unwrap();
+            assert_eq!(user_version(&db.conn), MIGRATIONS.len() as i64);
+
+            let downtimes = db.latest_downtimes(10).unwrap();
+            assert_eq!(downtimes.len(), 1);
+            assert_eq!((downtimes[0].start, downtimes[0].end, downtimes[0].duration), (1_551_557_643_000, 1_551_557_703_000, 60_000));
+            assert_eq!(downtimes[0].location, None);
+            assert!(!downtimes[0].interrupted);
+
+            let degraded = db.latest_degraded(10).unwrap();
+            assert_eq!((degraded[0].start, degraded[0].end, degraded[0].duration), (1_551_557_643_000, 1_551_557_653_000, 10_000));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rolls_back_failed_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute_batch("PRAGMA user_version = 1;
+            alter table degraded add column duration integer;
+            insert into current_downtime (start, end) values (1551557643, 1551557703);").unwrap();
+
+        // Migration 2 converts current_downtime, then fails on adding the column degraded already has
+        assert!(migrate(&mut conn).is_err());
+        assert_eq!(user_version(&conn), 1);
+        let start : i64 = conn.query_row("SELECT start FROM current_downtime", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(start, 1551557643);
+    }
+
+    #[test]
+    fn stores_groups_and_targets() {
+        let base = Instant::now();
+        let at = |millis : u64| Timestamp {epoch_millis: 1_551_557_643_250 + millis as i64, instant: base + Duration::from_millis(millis)};
+        let db = Db::new(":memory:").unwrap();
+        for group in &["default", "isp"] {
+            let mut cd = ConnectivityDown::new();
+            cd.start_between(None, at(0));
+            cd.set_targets(group, vec!["8.8.8.8".to_owned(), "1.1.1.1".to_owned()]);
+            cd.end_between(None, at(60_000));
+            db.insert_current_downtime(&cd).unwrap();
+        }
+
+        let downtimes = db.latest_downtimes(10).unwrap();
+        let mut groups : Vec<&str> = downtimes.iter().filter_map(|downtime| downtime.group.as_ref().map(|group| group.as_str())).collect();
+        groups.sort();
+        assert_eq!(groups, vec!["default", "isp"]);
+        assert!(downtimes.iter().all(|downtime| downtime.targets == vec!["8.8.8.8".to_owned(), "1.1.1.1".to_owned()]));
+    }
+}
@@ -0,0 +1,177 @@
+use std::time::Duration;
+use log::error;
+use crate::error::Error;
+use crate::ping::model::{Timestamp, duration_millis};
+use super::db::{Db, UnmonitoredRecord};
+
+// How often the heartbeat is stored
+pub const HEARTBEAT_INTERVAL : Duration = Duration::from_secs(10);
+
+// Time passing between two checks without icc running, e.g. while the host was suspended.
+// Shorter gaps are ordinary scheduling delays.
+const GAP_THRESHOLD : Duration = Duration::from_secs(20);
+
+pub const REASON_NOT_RUNNING : &str = "not_running";
+// The host was suspended, or icc was stopped or starved for time
+pub const REASON_SUSPENDED : &str = "suspended";
+// Paused through the control socket
+pub const REASON_PAUSED : &str = "paused";
+
+// Whether icc is monitoring with the database, judging by the last heartbeat stored in it
+pub fn is_recent(last_heartbeat : i64, now : i64) -> bool {
+    now - last_heartbeat <= duration_millis(GAP_THRESHOLD)
+}
+
+// Stores when icc was last running, so the periods it wasn't monitoring aren't mistaken for periods without outages
+pub struct Heartbeat {
+    db : Db,
+    last_check : Timestamp,
+    last_beat : Timestamp,
+}
+
+impl Heartbeat {
+    // Records the time since the last heartbeat as unmonitored, if icc ran with this database before
+    pub fn start(db_filename : &str) -> Result<(Self, Option<UnmonitoredRecord>), Error> {
+        let db = Db::new(db_filename)?;
+        let now = Timestamp::now();
+
+        let gap = match db.last_heartbeat()? {
+            Some(last) if last < now.epoch_millis => Some(UnmonitoredRecord::new(last, now.epoch_millis, REASON_NOT_RUNNING)),
+            _ => None
+        };
+        if let Some(gap) = &gap {
+            db.insert_unmonitored(gap)?;
+        }
+        db.heartbeat(now.epoch_millis)?;
+
+        Ok((Self {db: db, last_check: now, last_beat: now}, gap))
+    }
+
+    // Called regularly while monitoring. Stores the heartbeat every HEARTBEAT_INTERVAL,
+    // and records a gap if both the wall clock and the monotonic clock moved on too far since the last call.
+    // The wall clock alone is stepped by NTP, so it doesn't count without the monotonic clock. As the monotonic clock
+    // doesn't advance while the host is suspended on Linux and macOS, a suspension there is only seen by the wall clock.
+    pub fn beat(&mut self) -> Option<UnmonitoredRecord> {
+        self.beat_at(Timestamp::now())
+    }
+
+    fn beat_at(&mut self, now : Timestamp) -> Option<UnmonitoredRecord> {
+        let wall = now.epoch_millis - self.last_check.epoch_millis;
+        let monotonic = duration_millis(self.last_check.until(&now));
+
+        let threshold = duration_millis(GAP_THRESHOLD);
+        let gap = if wall > threshold && monotonic > threshold {
+            Some(UnmonitoredRecord::new(self.last_check.epoch_millis, now.epoch_millis.max(self.last_check.epoch_millis), REASON_SUSPENDED))
+        } else {
+            None
+        };
+        self.last_check = now;
+
+        if let Some(gap) = &gap {
+            if let Err(e) = self.db.insert_unmonitored(gap) {
+                error!("Error storing unmonitored period: {}", e);
+            }
+        }
+
+        if gap.is_some() || self.last_beat.until(&now) >= HEARTBEAT_INTERVAL {
+            self.store(now);
+        }
+
+        gap
+    }
+
+    // Records the time since pausing as unmonitored
+    pub fn paused(&mut self, since : i64) -> UnmonitoredRecord {
+        let now = Timestamp::now();
+        let record = UnmonitoredRecord::new(since, now.epoch_millis.max(since), REASON_PAUSED);
+        if let Err(e) = self.db.insert_unmonitored(&record) {
+            error!("Error storing unmonitored period: {}", e);
+        }
+        record
+    }
+
+    // Stores when icc stopped, where the next unmonitored period begins
+    pub fn stop(&mut self) {
+        self.store(Timestamp::now());
+    }
+
+    fn store(&mut self, now : Timestamp) {
+        match self.db.heartbeat(now.epoch_millis) {
+            Ok(_) => self.last_beat = now,
+            Err(e) => error!("Error storing heartbeat: {}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::time::Instant;
+
+    const EPOCH : i64 = 1_551_557_643_250;
+
+    // Wall clock and monotonic clock moved on by the given number of seconds
+    fn at(start : Instant, wall : i64, monotonic : u64) -> Timestamp {
+        Timestamp {epoch_millis: EPOCH + wall * 1000, instant: start + Duration::from_secs(monotonic)}
+    }
+
+    fn heartbeat(start : Instant) -> Heartbeat {
+        let mut heartbeat = Heartbeat {db: Db::new(":memory:").unwrap(), last_check: at(start, 0, 0), last_beat: at(start, 0, 0)};
+        heartbeat.store(at(start, 0, 0));
+        heartbeat
+    }
+
+    #[test]
+    fn stores_every_interval() {
+        let start = Instant::now();
+        let mut heartbeat = heartbeat(start);
+
+        assert!(heartbeat.beat_at(at(start, 5, 5)).is_none());
+        assert_eq!(heartbeat.db.last_heartbeat().unwrap(), Some(EPOCH));
+        assert!(heartbeat.beat_at(at(start, 10, 10)).is_none());
+        assert_eq!(heartbeat.db.last_heartbeat().unwrap(), Some(EPOCH + 10_000));
+        assert!(heartbeat.db.latest_unmonitored(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn gaps_need_both_clocks_to_jump() {
+        let start = Instant::now();
+        let mut heartbeat = heartbeat(start);
+
+        // The wall clock stepped forward, or back while icc was held up
+        assert!(heartbeat.beat_at(at(start, 60, 1)).is_none());
+        assert!(heartbeat.beat_at(at(start, 61, 60)).is_none());
+        assert!(heartbeat.db.latest_unmonitored(10).unwrap().is_empty());
+
+        let gap = heartbeat.beat_at(at(start, 120, 120)).unwrap();
+        assert_eq!((gap.start, gap.end, gap.duration), (EPOCH + 61_000, EPOCH + 120_000, 59_000));
+        assert_eq!(gap.reason, REASON_SUSPENDED);
+        let stored = heartbeat.db.latest_unmonitored(10).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].start, gap.start);
+        assert_eq!(heartbeat.db.last_heartbeat().unwrap(), Some(EPOCH + 120_000));
+
+        // Ordinary scheduling delays
+        assert!(heartbeat.beat_at(at(start, 135, 135)).is_none());
+    }
+
+    #[test]
+    fn records_the_time_icc_was_not_running() {
+        let path = env::temp_dir().join(format!("icc-heartbeat-{}.db", std::process::id()));
+        let last = Timestamp::now().epoch_millis - 60_000;
+        Db::new(path.to_str().unwrap()).unwrap().heartbeat(last).unwrap();
+
+        let (_, gap) = Heartbeat::start(path.to_str().unwrap()).unwrap();
+        let gap = gap.unwrap();
+        assert_eq!(gap.start, last);
+        assert!(gap.duration >= 60_000);
+        assert_eq!(gap.reason, REASON_NOT_RUNNING);
+
+        // Started again right away
+        let (_, gap) = Heartbeat::start(path.to_str().unwrap()).unwrap();
+        assert!(gap.map_or(true, |gap| gap.duration < 1000));
+        fs::remove_file(&path).unwrap();
+    }
+}
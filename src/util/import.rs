@@ -0,0 +1,406 @@
+use std::io::BufRead;
+use chrono::DateTime;
+use serde_json::Value;
+use crate::error::Error;
+use super::db::{Db, DowntimeRecord};
+
+// Epoch timestamps below this are seconds, as written by icc before timestamps became milliseconds
+const MILLIS_SINCE : i64 = 100_000_000_000;
+
+// Downtimes read from a clear text log
+pub struct ParsedLog {
+    pub downtimes : Vec<DowntimeRecord>,
+    // Downtime blocks and lines whose start and end couldn't be read, and lines in none of the log formats
+    pub skipped : usize,
+}
+
+pub struct ImportSummary {
+    pub imported : usize,
+    // Already in the database, within a second
+    pub duplicates : usize,
+    pub skipped : usize,
+}
+
+// Reads the "Downtime:" blocks written by log_cd, in the current format as well as the one with timestamps in seconds:
+//
+// Downtime:
+//  (1551557643) Sat, 02 Mar 2019 21:14:03 +0100 - (1551557703) Sat, 02 Mar 2019 21:15:03 +0100
+//  lasted for: 0 hours, 1 minutes, 0 seconds
+//
+// with newer blocks adding the location, group and targets, as well as the single lines of log_format = "logfmt" and "json":
+//
+// time=... event=downtime group=default targets=8.8.8.8 classification=isp start=2019-03-02T21:14:03.250+01:00 end=... duration_s=60.000 ...
+// {"time":"...","event":"downtime",...,"start_epoch_millis":1551557643250,"end_epoch_millis":1551557703250,"duration_s":60.0,...}
+//
+// Other blocks and events, e.g. degraded periods, are ignored. Traceroutes are only imported from json.
+pub fn parse_log<R : BufRead>(reader : R) -> Result<ParsedLog, Error> {
+    let mut parsed = ParsedLog {downtimes: Vec::new(), skipped: 0};
+    let mut in_downtime = false;
+    let mut current : Option<DowntimeRecord> = None;
+
+    for line in reader.lines() {
+        let line = line?;
+
+        let structured = if line.starts_with('{') {
+            Some(json_downtime(&line))
+        } else if line.starts_with("time=") {
+            Some(logfmt_downtime(&line))
+        } else {
+            None
+        };
+        if let Some(downtime) = structured {
+            parsed.downtimes.extend(current.take());
+            in_downtime = false;
+            match downtime {
+                Ok(downtime) => parsed.downtimes.extend(downtime),
+                Err(_) => parsed.skipped += 1
+            }
+            continue;
+        }
+
+        // Block headers aren't indented
+        if !line.starts_with(' ') {
+            parsed.downtimes.extend(current.take());
+            in_downtime = line.trim_end() == "Downtime:";
+            if !in_downtime && !line.trim().is_empty() && !line.starts_with("Degraded (") {
+                parsed.skipped += 1;
+            }
+            continue;
+        }
+        if !in_downtime {
+            continue;
+        }
+
+        let line = line.trim();
+        let record = match current.as_mut() {
+            Some(record) => record,
+            None => {
+                // The first line of a block has the start and end
+                match parenthesized_numbers(line)[..] {
+                    [start, end, ..] => current = Some(new_record(epoch_millis(start), epoch_millis(end))),
+                    _ => {
+                        parsed.skipped += 1;
+                        in_downtime = false;
+                    }
+                }
+                continue;
+            }
+        };
+
+        if line.starts_with("lasted for:") {
+            // "(60000 ms)" in the current format, otherwise the wall clock difference is used
+            if let Some(duration) = line.rfind('(').and_then(|index| line[index + 1..].trim_end_matches(')').trim_end_matches(" ms").parse().ok()) {
+                record.duration = duration;
+            }
+        } else if line.starts_with("uncertainty:") {
+            for part in line["uncertainty:".len()..].split(',') {
+                let part = part.trim();
+                let millis = part.split_whitespace().nth(3).and_then(|millis| millis.parse().ok());
+                if part.starts_with("start") {
+                    record.start_uncertainty = millis;
+                } else if part.starts_with("end") {
+                    record.end_uncertainty = millis;
+                }
+            }
+        } else if line.starts_with("location:") {
+            record.location = Some(line["location:".len()..].trim().to_owned());
+        } else if line.starts_with("interrupted:") {
+            record.interrupted = true;
+        } else if line.starts_with("group:") {
+            record.group = Some(line["group:".len()..].trim().to_owned());
+        } else if line.starts_with("targets:") {
+            record.targets = line["targets:".len()..].split(", ").map(|target| target.trim().to_owned()).collect();
+        }
+    }
+    parsed.downtimes.extend(current.take());
+
+    Ok(parsed)
+}
+
+// Inserts the downtimes that aren't in the database yet
+pub fn import(db : &Db, parsed : &ParsedLog) -> Result<ImportSummary, Error> {
+    let mut summary = ImportSummary {imported: 0, duplicates: 0, skipped: parsed.skipped};
+    for record in &parsed.downtimes {
+        if db.import_downtime(record)? {
+            summary.imported += 1;
+        } else {
+            summary.duplicates += 1;
+        }
+    }
+    Ok(summary)
+}
+
+// The downtime on a line of a json log, None for other events
+fn json_downtime(line : &str) -> Result<Option<DowntimeRecord>, ()> {
+    let entry : Value = serde_json::from_str(line).map_err(|_| ())?;
+    if entry.get("event").and_then(Value::as_str) != Some("downtime") {
+        return Ok(None);
+    }
+
+    let millis = |key : &str| entry.get(key).and_then(Value::as_i64);
+    let mut record = match (millis("start_epoch_millis"), millis("end_epoch_millis")) {
+        (Some(start), Some(end)) => new_record(start, end),
+        _ => return Err(())
+    };
+    if let Some(duration) = entry.get("duration_s").and_then(Value::as_f64) {
+        record.duration = (duration * 1000.0).round() as i64;
+    }
+    record.start_uncertainty = millis("start_uncertainty_ms");
+    record.end_uncertainty = millis("end_uncertainty_ms");
+    record.location = entry.get("classification").and_then(Value::as_str).map(str::to_owned);
+    record.interrupted = entry.get("interrupted").and_then(Value::as_bool).unwrap_or(false);
+    record.traceroute = entry.get("traceroute").filter(|traceroute| !traceroute.is_null()).map(Value::to_string);
+    record.group = entry.get("group").and_then(Value::as_str).map(str::to_owned);
+    record.targets = entry.get("targets").and_then(Value::as_array)
+        .map_or(Vec::new(), |targets| targets.iter().filter_map(Value::as_str).map(str::to_owned).collect());
+    Ok(Some(record))
+}
+
+// The downtime on a line of a logfmt log, None for other events
+fn logfmt_downtime(line : &str) -> Result<Option<DowntimeRecord>, ()> {
+    let pairs = logfmt_pairs(line);
+    let value = |key : &str| pairs.iter().find(|(k, _)| k == key).map(|(_, value)| value.as_str()).filter(|value| !value.is_empty());
+    if value("event") != Some("downtime") {
+        return Ok(None);
+    }
+
+    let time = |key : &str| value(key).and_then(|time| DateTime::parse_from_rfc3339(time).ok()).map(|time| time.timestamp_millis());
+    let mut record = match (time("start"), time("end")) {
+        (Some(start), Some(end)) => new_record(start, end),
+        _ => return Err(())
+    };
+    if let Some(duration) = value("duration_s").and_then(|duration| duration.parse::<f64>().ok()) {
+        record.duration = (duration * 1000.0).round() as i64;
+    }
+    record.start_uncertainty = value("start_uncertainty_ms").and_then(|millis| millis.parse().ok());
+    record.end_uncertainty = value("end_uncertainty_ms").and_then(|millis| millis.parse().ok());
+    record.location = value("classification").map(str::to_owned);
+    record.interrupted = value("interrupted") == Some("true");
+    record.group = value("group").map(str::to_owned);
+    record.targets = value("targets").map_or(Vec::new(), |targets| targets.split(',').map(str::to_owned).collect());
+    Ok(Some(record))
+}
+
+// key=value pairs, values quoted with "" if they contain spaces
+fn logfmt_pairs(line : &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        let key : String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c)
+                }
+            }
+        } else {
+            while let Some(c) = chars.peek().cloned() {
+                if c.is_whitespace() {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+        }
+        pairs.push((key.trim().to_owned(), value));
+    }
+    pairs
+}
+
+fn new_record(start : i64, end : i64) -> DowntimeRecord {
+    DowntimeRecord {
+        start: start,
+        end: end,
+        duration: end - start,
+        start_uncertainty: None,
+        end_uncertainty: None,
+        location: None,
+        traceroute: None,
+        interrupted: false,
+        group: None,
+        targets: Vec::new(),
+    }
+}
+
+fn epoch_millis(timestamp : i64) -> i64 {
+    if timestamp < MILLIS_SINCE {
+        timestamp * 1000
+    } else {
+        timestamp
+    }
+}
+
+// Numbers in parentheses, e.g. [1551557643, 1551557703] for "(1551557643) ... - (1551557703) ..."
+fn parenthesized_numbers(line : &str) -> Vec<i64> {
+    line.split('(')
+        .skip(1)
+        .filter_map(|part| part.split(')').next())
+        .filter_map(|number| number.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use crate::ping::model::{ConnectivityDown, FaultLocation, Timestamp};
+    use crate::util::clear_text_log::downtime_entry;
+    use crate::util::config::LogFormat;
+
+    fn parse(log : &str) -> ParsedLog {
+        parse_log(log.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn parses_seconds() {
+        let parsed = parse("Downtime:\n (1551557643) Sat, 02 Mar 2019 21:14:03 +0100 - (1551557703) Sat, 02 Mar 2019 21:15:03 +0100\n lasted for: 0 hours, 1 minutes, 0 seconds\n");
+        assert_eq!(parsed.skipped, 0);
+        assert_eq!(parsed.downtimes.len(), 1);
+
+        let downtime = &parsed.downtimes[0];
+        assert_eq!(downtime.start, 1_551_557_643_000);
+        assert_eq!(downtime.end, 1_551_557_703_000);
+        assert_eq!(downtime.duration, 60_000);
+        assert_eq!(downtime.location, None);
+        assert!(!downtime.interrupted);
+    }
+
+    #[test]
+    fn parses_millis() {
+        let parsed = parse("Downtime:
+ (1551557643250) 2019-03-02T21:14:03.250+01:00 - (1551557703750) 2019-03-02T21:15:03.750+01:00
+ lasted for: 0 hours, 1 minutes, 0.500 seconds (60400 ms)
+ uncertainty: start up to 1000 ms earlier, end unknown
+ location: isp
+ interrupted: icc was stopped before the outage ended
+ traceroute: 198.51.100.9 (198.51.100.9): 1 192.0.2.1 1 ms, 2 *
+Degraded (8.8.8.8):
+ (1551557800000) - (1551557900000) 2019-03-02T21:16:40.000+01:00 - 2019-03-02T21:18:20.000+01:00
+Downtime:
+ (1551558000000) 2019-03-02T21:20:00.000+01:00 - (1551558001000) 2019-03-02T21:20:01.000+01:00
+ lasted for: 0 hours, 0 minutes, 1 seconds (1000 ms)
+ uncertainty: start unknown, end up to 250 ms earlier
+");
+        assert_eq!(parsed.skipped, 0);
+        assert_eq!(parsed.downtimes.len(), 2);
+
+        let downtime = &parsed.downtimes[0];
+        assert_eq!(downtime.start, 1_551_557_643_250);
+        assert_eq!(downtime.end, 1_551_557_703_750);
+        // Measured on the monotonic clock, so it can differ from end - start
+        assert_eq!(downtime.duration, 60_400);
+        assert_eq!(downtime.start_uncertainty, Some(1000));
+        assert_eq!(downtime.end_uncertainty, None);
+        assert_eq!(downtime.location, Some("isp".to_owned()));
+        assert!(downtime.interrupted);
+
+        let downtime = &parsed.downtimes[1];
+        assert_eq!(downtime.start, 1_551_558_000_000);
+        assert_eq!(downtime.start_uncertainty, None);
+        assert_eq!(downtime.end_uncertainty, Some(250));
+        assert!(!downtime.interrupted);
+    }
+
+    #[test]
+    fn skips_unreadable_blocks() {
+        let parsed = parse("Downtime:\n garbled\nDowntime:\n (1551557643) - (1551557703)\n");
+        assert_eq!(parsed.skipped, 1);
+        assert_eq!(parsed.downtimes.len(), 1);
+    }
+
+    #[test]
+    fn imports_once() {
+        let db = Db::new(":memory:").unwrap();
+        let parsed = parse("Downtime:\n (1551557643) - (1551557703)\n");
+        let summary = import(&db, &parsed).unwrap();
+        assert_eq!((summary.imported, summary.duplicates), (1, 0));
+        let summary = import(&db, &parsed).unwrap();
+        assert_eq!((summary.imported, summary.duplicates), (0, 1));
+    }
+
+    #[test]
+    fn imports_groups_down_at_the_same_time() {
+        let db = Db::new(":memory:").unwrap();
+        let parsed = parse(r#"time=2019-03-02T21:15:03.250+01:00 event=downtime group=default targets=8.8.8.8,1.1.1.1 classification=isp start=2019-03-02T21:14:03.250+01:00 end=2019-03-02T21:15:03.250+01:00 duration_s=60.000
+time=2019-03-02T21:15:03.250+01:00 event=downtime group=isp targets=isp-dns classification=isp start=2019-03-02T21:14:03.250+01:00 end=2019-03-02T21:15:03.250+01:00 duration_s=60.000
+"#);
+        let summary = import(&db, &parsed).unwrap();
+        assert_eq!((summary.imported, summary.duplicates), (2, 0));
+
+        // Each group only once, and a downtime without a group matches either
+        let summary = import(&db, &parsed).unwrap();
+        assert_eq!((summary.imported, summary.duplicates), (0, 2));
+        let summary = import(&db, &parse("Downtime:\n (1551557643250) - (1551557703250)\n")).unwrap();
+        assert_eq!((summary.imported, summary.duplicates), (0, 1));
+
+        let downtimes = db.latest_downtimes(10).unwrap();
+        let mut groups : Vec<(Option<String>, Vec<String>)> = downtimes.into_iter().map(|downtime| (downtime.group, downtime.targets)).collect();
+        groups.sort();
+        assert_eq!(groups, vec![
+            (Some("default".to_owned()), vec!["8.8.8.8".to_owned(), "1.1.1.1".to_owned()]),
+            (Some("isp".to_owned()), vec!["isp-dns".to_owned()]),
+        ]);
+    }
+
+    #[test]
+    fn parses_every_log_format() {
+        let base = Instant::now();
+        let at = |millis : u64| Timestamp {epoch_millis: 1_551_557_643_250 + millis as i64, instant: base + Duration::from_millis(millis)};
+        let mut cd = ConnectivityDown::new();
+        cd.start_between(Some(at(0)), at(1000));
+        cd.set_targets("default", vec!["8.8.8.8".to_owned(), "my router".to_owned()]);
+        cd.set_location(FaultLocation::Isp);
+        cd.interrupt_at(at(61_500));
+
+        for format in &[LogFormat::Text, LogFormat::Logfmt, LogFormat::Json] {
+            let parsed = parse(&downtime_entry(*format, &cd));
+            assert_eq!(parsed.skipped, 0, "{:?}", format);
+            assert_eq!(parsed.downtimes.len(), 1, "{:?}", format);
+
+            let downtime = &parsed.downtimes[0];
+            assert_eq!(downtime.start, 1_551_557_644_250, "{:?}", format);
+            assert_eq!(downtime.end, 1_551_557_704_750, "{:?}", format);
+            assert_eq!(downtime.duration, 60_500, "{:?}", format);
+            assert_eq!(downtime.start_uncertainty, Some(1000), "{:?}", format);
+            assert_eq!(downtime.end_uncertainty, None, "{:?}", format);
+            assert_eq!(downtime.location, Some("isp".to_owned()), "{:?}", format);
+            assert!(downtime.interrupted, "{:?}", format);
+            assert_eq!(downtime.group, Some("default".to_owned()), "{:?}", format);
+            assert_eq!(downtime.targets, vec!["8.8.8.8".to_owned(), "my router".to_owned()], "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn counts_unreadable_lines() {
+        let parsed = parse(r#"time=2019-03-02T21:14:03.250+01:00 event=degraded target=8.8.8.8 loss_percent=20.0
+time=2019-03-02T21:14:03.250+01:00 event=downtime start=yesterday end=
+{"event":"degraded","target":"8.8.8.8"}
+{"event":"downtime","start_epoch_millis":1551557643250}
+{"event":"downtime","start_epoch_millis":1551557643250,"end_epoch_millis":1551557703250,"duration_s":60.0,"traceroute":[]}
+{not json
+something else entirely
+"#);
+        assert_eq!(parsed.downtimes.len(), 1);
+        assert_eq!(parsed.downtimes[0].duration, 60_000);
+        assert_eq!(parsed.downtimes[0].traceroute, Some("[]".to_owned()));
+        assert_eq!(parsed.skipped, 4);
+    }
+
+    #[test]
+    fn splits_logfmt() {
+        let pairs = logfmt_pairs(r#"a=1 b="two words" c= d="say \"hi\"""#);
+        let pairs : Vec<(&str, &str)> = pairs.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+        assert_eq!(pairs, vec![("a", "1"), ("b", "two words"), ("c", ""), ("d", "say \"hi\"")]);
+    }
+}
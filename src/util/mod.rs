@@ -1,29 +1,59 @@
-use super::ping::model::{ConnectivityDown, File};
+use super::ping::model::{ConnectivityDown, ConnectivityDegraded};
+use self::clear_text_log::LogFile;
 use std::thread;
-use std::io::Write;
+use std::time::{Duration, Instant};
 use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use log::error;
 
 
 pub mod db;
 pub mod config;
+pub mod clear_text_log;
+pub mod heartbeat;
+pub mod import;
+pub mod webhook;
 
 pub static THREADS_ACTIVE_GRACEFUL : AtomicUsize = ATOMIC_USIZE_INIT;
 
+// Waits for the downtimes and degraded periods being written to finish. Returns how many are still being written after the timeout.
+pub fn wait_graceful(timeout : Duration) -> usize {
+    let started = Instant::now();
+    loop {
+        let active = THREADS_ACTIVE_GRACEFUL.load(Ordering::SeqCst);
+        if active == 0 || started.elapsed() >= timeout {
+            return active;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
 
-pub fn log_cd(mut cd : ConnectivityDown, log_file : File, db_filename : String) {
+
+pub fn log_cd(cd : ConnectivityDown, log_file : LogFile, db_filename : String) {
     thread::spawn(move || {
-        let mut file_lock_guard = log_file.lock().unwrap();
-        let mut file_lock = file_lock_guard.as_mut().unwrap();
-        let payload : String = format!("Downtime:\n ({}) {} - ({}) {}\n lasted for: {}\n",
-            cd.start_epoch_timestamp(),
-            cd.start_text(),
-            cd.end_epoch_timestamp(),
-            cd.end_text(),
-            cd.duration_text());
-        file_lock.write_all(payload.as_bytes());
-
-        let dbc = db::Db::new(db_filename.as_str());
-        dbc.insert_current_downtime(cd.start_epoch_timestamp(), cd.end_epoch_timestamp());
+        if let Some(log) = log_file.lock().unwrap().as_mut() {
+            if let Err(e) = log.write_downtime(&cd) {
+                error!("Error writing to clear text log: {}", e);
+            }
+        }
+
+        if let Err(e) = db::Db::new(db_filename.as_str()).and_then(|dbc| dbc.insert_current_downtime(&cd)) {
+            error!("Error storing downtime in {}: {}", db_filename, e);
+        }
+        THREADS_ACTIVE_GRACEFUL.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+pub fn log_degraded(degraded : ConnectivityDegraded, log_file : LogFile, db_filename : String) {
+    thread::spawn(move || {
+        if let Some(log) = log_file.lock().unwrap().as_mut() {
+            if let Err(e) = log.write_degraded(&degraded) {
+                error!("Error writing to clear text log: {}", e);
+            }
+        }
+
+        if let Err(e) = db::Db::new(db_filename.as_str()).and_then(|dbc| dbc.insert_degraded(&degraded)) {
+            error!("Error storing degraded period in {}: {}", db_filename, e);
+        }
         THREADS_ACTIVE_GRACEFUL.fetch_sub(1, Ordering::SeqCst);
     });
-}
\ No newline at end of file
+}
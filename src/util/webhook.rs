@@ -0,0 +1,264 @@
+// Posts downtimes as JSON to the webhook in the config, when they start, when where they are is known and when they end:
+//
+// {"event":"downtime_located","group":"default","targets":["8.8.8.8"],"classification":"isp","start":"2019-03-02T21:14:03.250+01:00",
+//  "start_epoch_millis":1551557643250,"end":null,"end_epoch_millis":null,"duration_s":null,"interrupted":false,
+//  "traceroute":[{"target":"8.8.8.8","destination":"8.8.8.8","hops":[{"ttl":1,"address":"192.168.1.1","rtt":1},{"ttl":2,"address":null,"rtt":null}]}]}
+//
+// Only plain http is supported, as there is no TLS implementation to use.
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use log::{debug, error};
+use crate::ping::model::{ConnectivityDown, Trace};
+use super::THREADS_ACTIVE_GRACEFUL;
+
+// How long connecting, sending and waiting for the answer may take each
+const TIMEOUT : Duration = Duration::from_secs(10);
+
+pub const EVENT_STARTED : &str = "downtime_started";
+// The location and traceroutes were found, a while after the downtime started
+pub const EVENT_LOCATED : &str = "downtime_located";
+pub const EVENT_ENDED : &str = "downtime_ended";
+
+#[derive(Serialize)]
+pub struct WebhookPayload {
+    event : &'static str,
+    group : Option<String>,
+    targets : Vec<String>,
+    // Where the outage is, "gateway", "isp", "internet" or "unknown"
+    classification : &'static str,
+    start : String,
+    start_epoch_millis : i64,
+    // None until the downtime ended
+    end : Option<String>,
+    end_epoch_millis : Option<i64>,
+    duration_s : Option<f64>,
+    interrupted : bool,
+    traceroute : Vec<Trace>,
+}
+
+impl WebhookPayload {
+    pub fn downtime(event : &'static str, cd : &ConnectivityDown) -> Self {
+        let ended = cd.is_ready();
+        Self {
+            event: event,
+            group: cd.group().map(|group| group.to_owned()),
+            targets: cd.targets().to_vec(),
+            classification: cd.location_text(),
+            start: cd.start_text(),
+            start_epoch_millis: cd.start_epoch_millis(),
+            end: if ended { Some(cd.end_text()) } else { None },
+            end_epoch_millis: if ended { Some(cd.end_epoch_millis()) } else { None },
+            duration_s: if ended { Some(cd.duration_millis() as f64 / 1000.0) } else { None },
+            interrupted: cd.is_interrupted(),
+            traceroute: cd.traces().to_vec(),
+        }
+    }
+}
+
+// Host, port and path of an http:// URL
+#[derive(Debug, PartialEq)]
+pub struct WebhookUrl {
+    host : String,
+    port : u16,
+    path : String,
+}
+
+impl WebhookUrl {
+    pub fn parse(url : &str) -> Result<Self, String> {
+        let rest = if url.starts_with("http://") {
+            &url["http://".len()..]
+        } else if url.starts_with("https://") {
+            return Err("https isn't supported, only http".to_owned());
+        } else {
+            return Err(format!("{} isn't an http:// URL", url));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/")
+        };
+
+        // IPv6 addresses are in brackets, e.g. http://[::1]:8080/
+        let port_start = match authority.rfind(']') {
+            Some(bracket) => authority[bracket..].find(':').map(|index| bracket + index),
+            None => authority.rfind(':')
+        };
+        let (host, port) = match port_start {
+            Some(index) => (&authority[..index], authority[index + 1..].parse::<u16>().map_err(|_| format!("{} has an invalid port", url))?),
+            None => (authority, 80)
+        };
+
+        if host.is_empty() || host == "[]" {
+            return Err(format!("{} has no host", url));
+        }
+
+        Ok(Self {host: host.to_owned(), port: port, path: path.to_owned()})
+    }
+
+    // The request sent for a JSON body
+    fn request(&self, body : &str) -> String {
+        let host = if self.port == 80 { self.host.clone() } else { format!("{}:{}", self.host, self.port) };
+        format!("POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: icc\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path, host, body.len(), body)
+    }
+}
+
+// Status code of a response, e.g. 204 from "HTTP/1.1 204 No Content"
+fn status_code(status_line : &str) -> Option<u16> {
+    let mut parts = status_line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+// Posts the payload, and fails unless the webhook answers with a 2xx status
+pub fn post(url : &str, payload : &WebhookPayload) -> io::Result<()> {
+    let url = WebhookUrl::parse(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let body = serde_json::to_string(payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let host = url.host.trim_start_matches('[').trim_end_matches(']');
+    let address = (host, url.port).to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't resolve", url.host)))?;
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.write_all(url.request(&body).as_bytes())?;
+
+    let mut status_line = String::new();
+    BufReader::new(&stream).read_line(&mut status_line)?;
+    match status_code(&status_line) {
+        Some(code) if (200..300).contains(&code) => Ok(()),
+        _ => Err(io::Error::new(io::ErrorKind::Other, format!("webhook answered {}", status_line.trim())))
+    }
+}
+
+// Posts the payload in the background. Counted as being written, so one sent when icc stops is waited for.
+pub fn send(url : &str, payload : WebhookPayload) {
+    let url = url.to_owned();
+    THREADS_ACTIVE_GRACEFUL.fetch_add(1, Ordering::SeqCst);
+    thread::spawn(move || {
+        match post(&url, &payload) {
+            Ok(_) => debug!("Posted {} to webhook", payload.event),
+            Err(e) => error!("Error posting {} to webhook {}: {}", payload.event, url, e)
+        }
+        THREADS_ACTIVE_GRACEFUL.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use crate::ping::model::{FaultLocation, Hop, Timestamp};
+
+    #[test]
+    fn parses_http_urls() {
+        let url = |host : &str, port, path : &str| Ok(WebhookUrl {host: host.to_owned(), port: port, path: path.to_owned()});
+        assert_eq!(WebhookUrl::parse("http://192.168.1.10:8123/api/webhook/icc"), url("192.168.1.10", 8123, "/api/webhook/icc"));
+        assert_eq!(WebhookUrl::parse("http://example.com"), url("example.com", 80, "/"));
+        assert_eq!(WebhookUrl::parse("http://example.com/hook?token=a:b"), url("example.com", 80, "/hook?token=a:b"));
+        assert_eq!(WebhookUrl::parse("http://[::1]:8080/hook"), url("[::1]", 8080, "/hook"));
+        assert_eq!(WebhookUrl::parse("http://[::1]/"), url("[::1]", 80, "/"));
+
+        for invalid in &["https://example.com/hook", "example.com/hook", "http://", "http://:8080/", "http://example.com:http/", "http://example.com:70000/"] {
+            assert!(WebhookUrl::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn reads_status_codes() {
+        assert_eq!(status_code("HTTP/1.1 204 No Content\r\n"), Some(204));
+        assert_eq!(status_code("HTTP/1.0 500 Internal Server Error"), Some(500));
+        assert_eq!(status_code("SSH-2.0-OpenSSH_7.9"), None);
+        assert_eq!(status_code(""), None);
+    }
+
+    fn downtime() -> ConnectivityDown {
+        let start = Timestamp::now();
+        let mut cd = ConnectivityDown::new();
+        cd.start_at(Timestamp {epoch_millis: 1_551_557_643_250, instant: start.instant});
+        cd.set_targets("dns", vec!["8.8.8.8".to_owned()]);
+        cd
+    }
+
+    #[test]
+    fn describes_downtimes() {
+        let mut cd = downtime();
+        let started = serde_json::to_value(WebhookPayload::downtime(EVENT_STARTED, &cd)).unwrap();
+        assert_eq!(started["event"], "downtime_started");
+        assert_eq!(started["group"], "dns");
+        assert_eq!(started["targets"][0], "8.8.8.8");
+        assert_eq!(started["classification"], "unknown");
+        assert_eq!(started["start_epoch_millis"], 1_551_557_643_250i64);
+        assert!(started["end"].is_null() && started["duration_s"].is_null());
+        assert_eq!(started["traceroute"].as_array().unwrap().len(), 0);
+
+        cd.set_location(FaultLocation::Isp);
+        cd.set_traces(vec![Trace {target: "8.8.8.8".to_owned(), destination: "8.8.8.8".parse().unwrap(), hops: vec![
+            Hop {ttl: 1, address: Some("192.168.1.1".parse().unwrap()), rtt: Some(1)},
+            Hop {ttl: 2, address: None, rtt: None},
+        ]}]);
+        let located = serde_json::to_value(WebhookPayload::downtime(EVENT_LOCATED, &cd)).unwrap();
+        assert_eq!(located["classification"], "isp");
+        assert_eq!(located["traceroute"][0]["hops"][0]["address"], "192.168.1.1");
+        assert!(located["traceroute"][0]["hops"][1]["address"].is_null());
+
+        let end = cd.start_epoch_millis() + 60_000;
+        cd.end_at(Timestamp {epoch_millis: end, instant: Timestamp::now().instant + Duration::from_secs(60)});
+        let ended = serde_json::to_value(WebhookPayload::downtime(EVENT_ENDED, &cd)).unwrap();
+        assert_eq!(ended["end_epoch_millis"], end);
+        assert!(ended["duration_s"].as_f64().unwrap() >= 60.0);
+        assert_eq!(ended["interrupted"], false);
+    }
+
+    // Answers a single request with the status line, and returns the request
+    fn webhook(status_line : &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            // The client keeps the connection open for the answer
+            while let Ok(read) = stream.read(&mut buf) {
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream.write_all(format!("{}\r\nContent-Length: 0\r\n\r\n", status_line).as_bytes()).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn posts_json() {
+        let (url, server) = webhook("HTTP/1.1 204 No Content");
+        post(&url, &WebhookPayload::downtime(EVENT_STARTED, &downtime())).unwrap();
+
+        let request = server.join().unwrap();
+        let (head, body) = request.split_at(request.find("\r\n\r\n").unwrap() + 4);
+        assert!(head.starts_with("POST /hook HTTP/1.1\r\n"), "{}", head);
+        assert!(head.contains("Content-Type: application/json\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        let body : serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["event"], "downtime_started");
+    }
+
+    #[test]
+    fn fails_unless_accepted() {
+        let (url, server) = webhook("HTTP/1.1 500 Internal Server Error");
+        let error = post(&url, &WebhookPayload::downtime(EVENT_STARTED, &downtime())).unwrap_err();
+        assert_eq!(error.to_string(), "webhook answered HTTP/1.1 500 Internal Server Error");
+        server.join().unwrap();
+
+        assert_eq!(post("https://example.com/", &WebhookPayload::downtime(EVENT_STARTED, &downtime())).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}